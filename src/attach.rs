@@ -0,0 +1,266 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use nix::libc;
+use nix::sys::signalfd::SfdFlags;
+
+use log::{debug, info, error};
+
+use crate::terminal;
+
+
+/// Kinds of the frames exchanged with attached clients: input, output, and window sizes for containers with a terminal
+pub const STDIN: u8 = 0;
+pub const STDOUT: u8 = 1;
+pub const STDERR: u8 = 2;
+pub const RESIZE: u8 = 3;
+
+/// Clients attached to a container, receiving its output
+#[derive(Clone, Default)]
+pub struct Clients(Arc<Mutex<Vec<UnixStream>>>);
+impl Clients {
+    /// Send output to every client
+    ///
+    /// Clients that can't be written to anymore are dropped
+    pub fn broadcast(&self, stream: u8, data: &[u8]) {
+        let frame = frame(stream, data);
+        let mut clients = self.0.lock().unwrap();
+        clients.retain(|client| (&*client).write_all(&frame).is_ok());
+    }
+    /// Disconnect every client
+    pub fn close(&self) {
+        for client in self.0.lock().unwrap().drain(..) {
+            let _ = client.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Accept clients on the attach socket of a container, in a new thread
+///
+/// Clients get the output broadcast to them, and their input is written to the container standard input.
+/// If the container has a terminal, 'stdin' is its master end and clients can resize it
+pub fn serve(socket_path: &str, clients: Clients, stdin: RawFd, terminal: bool) -> Result<(), Box<dyn std::error::Error>> {
+    info!("listening for attach clients on {}...", socket_path);
+
+    if Path::new(socket_path).exists() {
+        fs::remove_file(socket_path)?;
+    }
+    let listener = UnixListener::bind(socket_path)?;
+    fs::set_permissions(socket_path, fs::Permissions::from_mode(0o600))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e)     => {
+                    error!("error accepting attach client: {}", e);
+                    continue
+                }
+            };
+            let input = match stream.try_clone() {
+                Ok(input) => input,
+                Err(e)    => {
+                    error!("error accepting attach client: {}", e);
+                    continue
+                }
+            };
+
+            clients.0.lock().unwrap().push(stream);
+            thread::spawn(move || forward_client_input(input, stdin, terminal));
+        }
+    });
+
+    Ok(())
+}
+/// Connect the standard streams to an attach socket, until the container exits or the detach keys are typed
+///
+/// If the container has a terminal, the local one is put in raw mode and its window size is kept on the container one.
+/// Returns true if it detached
+pub fn attach(socket_path: &str, detach_keys: &[u8], terminal: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    info!("attaching to {}...", socket_path);
+
+    let stream = UnixStream::connect(socket_path)?;
+    let input = Arc::new(Mutex::new(stream.try_clone()?));
+
+    let original = match terminal {
+        true  => terminal::set_raw_mode(libc::STDIN_FILENO)?,
+        // Flow control would take ctrl-q, which is in the default detach keys
+        false => terminal::disable_flow_control(libc::STDIN_FILENO)?
+    };
+
+    if terminal {
+        // Blocked before the input thread is spawned, so the signal only reaches the file descriptor
+        let window_changes = terminal::watch_window_size(SfdFlags::empty())?;
+        send_window_size(&input)?;
+        let resize_input = input.clone();
+        thread::spawn(move || {
+            for _ in window_changes {
+                if send_window_size(&resize_input).is_err() {
+                    break
+                }
+            }
+        });
+    }
+
+    let detached = Arc::new(AtomicBool::new(false));
+    let input_detached = detached.clone();
+    let detach_keys = detach_keys.to_vec();
+    thread::spawn(move || {
+        if forward_input(&input, &detach_keys) {
+            input_detached.store(true, Ordering::SeqCst);
+            let _ = input.lock().unwrap().shutdown(Shutdown::Both);
+        }
+    });
+
+    let result = print_output(stream);
+    if let Some(original) = original {
+        terminal::restore_mode(libc::STDIN_FILENO, &original)?;
+    }
+    result?;
+
+    Ok(detached.load(Ordering::SeqCst))
+}
+/// Parse a detach sequence, like 'ctrl-p,ctrl-q'
+///
+/// Keys are ctrl- followed by a letter or one of '@[\]^_', or single characters
+pub fn parse_keys(keys: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let invalid = |key: &str| format!("invalid detach key '{}', expected e.g. 'ctrl-p' or 'q'", key);
+
+    let mut sequence = Vec::new();
+    for key in keys.split(',') {
+        let byte = match key.as_bytes() {
+            [byte] if byte.is_ascii()            => *byte,
+            [b'c', b't', b'r', b'l', b'-', byte] => match byte.to_ascii_uppercase() {
+                symbol @ b'@'..=b'_' => symbol - b'@',
+                _                    => return Err(invalid(key).into())
+            },
+            _ => return Err(invalid(key).into())
+        };
+        sequence.push(byte);
+    }
+    Ok(sequence)
+}
+
+/// Frame of data sent through the attach socket: kind, big endian length, data
+fn frame(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 5);
+    frame.push(kind);
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+/// Read a frame from the attach socket, or None once it's closed
+fn read_frame(mut stream: &UnixStream) -> Result<Option<(u8, Vec<u8>)>, io::Error> {
+    let mut header = [0u8; 5];
+    match stream.read_exact(&mut header) {
+        Ok(())                                                 => {},
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e)                                                 => return Err(e)
+    }
+    let mut size = [0u8; 4];
+    size.copy_from_slice(&header[1..]);
+    let mut data = vec![0u8; u32::from_be_bytes(size) as usize];
+    stream.read_exact(&mut data)?;
+
+    Ok(Some((header[0], data)))
+}
+/// Write what a client sends to the container standard input, and resize the container terminal
+fn forward_client_input(client: UnixStream, stdin: RawFd, terminal: bool) {
+    while let Ok(Some((kind, data))) = read_frame(&client) {
+        match kind {
+            STDIN                                => {
+                if let Err(e) = terminal::write_all(stdin, &data) {
+                    debug!("container standard input closed: {}", e);
+                    break
+                }
+            },
+            RESIZE if terminal && data.len() == 4 => {
+                let rows = u16::from_be_bytes([data[0], data[1]]);
+                let columns = u16::from_be_bytes([data[2], data[3]]);
+                if let Err(e) = terminal::set_window_size(stdin, rows, columns) {
+                    error!("error resizing container terminal: {}", e);
+                }
+            },
+            _                                    => {}
+        }
+    }
+}
+/// Send the window size of the local terminal, to be set on the container one
+fn send_window_size(stream: &Mutex<UnixStream>) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some((rows, columns)) = terminal::window_size(libc::STDIN_FILENO) {
+        let mut size = rows.to_be_bytes().to_vec();
+        size.extend_from_slice(&columns.to_be_bytes());
+        stream.lock().unwrap().write_all(&frame(RESIZE, &size))?;
+    }
+    Ok(())
+}
+/// Send the standard input to the container, holding back what could be the detach sequence
+///
+/// Returns true once the whole sequence is typed, false when the input is closed
+fn forward_input(stream: &Mutex<UnixStream>, detach_keys: &[u8]) -> bool {
+    let mut buffer = [0u8; 4096];
+    let mut matched = 0;
+    loop {
+        let size = match io::stdin().read(&mut buffer) {
+            Ok(0) | Err(_) => return false,
+            Ok(size)       => size
+        };
+
+        let mut data = Vec::with_capacity(size);
+        for byte in &buffer[..size] {
+            if detach_keys.get(matched) == Some(byte) {
+                matched += 1;
+                if matched == detach_keys.len() {
+                    return true
+                }
+                continue
+            }
+            data.extend_from_slice(&detach_keys[..matched]);
+            matched = 0;
+            if detach_keys.first() == Some(byte) {
+                matched = 1;
+            } else {
+                data.push(*byte);
+            }
+        }
+
+        if stream.lock().unwrap().write_all(&frame(STDIN, &data)).is_err() {
+            return false
+        }
+    }
+}
+/// Print the output frames received from the attach socket, until it's closed
+fn print_output(stream: UnixStream) -> Result<(), Box<dyn std::error::Error>> {
+    while let Some((kind, data)) = read_frame(&stream)? {
+        if kind == STDERR {
+            io::stderr().write_all(&data)?;
+        } else {
+            let mut stdout = io::stdout();
+            stdout.write_all(&data)?;
+            stdout.flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_keys_sequences() {
+        assert_eq!(parse_keys("ctrl-p,ctrl-q").unwrap(), vec![0x10, 0x11]);
+        assert_eq!(parse_keys("ctrl-P,q").unwrap(), vec![0x10, b'q']);
+        assert_eq!(parse_keys("ctrl-@,ctrl-[,ctrl-_").unwrap(), vec![0x00, 0x1b, 0x1f]);
+        for keys in ["", "ctrl-", "ctrl-1", "ctrl-pq", "pq", "ctrl-p,", "é"].iter() {
+            assert!(parse_keys(keys).is_err(), "{}", keys);
+        }
+    }
+}
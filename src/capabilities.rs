@@ -0,0 +1,96 @@
+use std::fs::read_to_string;
+use std::io;
+use nix::libc;
+
+use log::info;
+
+use crate::spec::{Capability, CapabilityType};
+
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+#[repr(C)]
+struct CapabilityHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapabilityData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Drop the capabilities missing from the bounding set of the spec
+///
+/// Requires CAP_SETPCAP, so it's done before the other sets are changed
+pub fn drop_bounding_set(capabilities: &Capability) -> Result<(), Box<dyn std::error::Error>> {
+    info!("dropping bounding set...");
+
+    let bounding = mask(&capabilities.bounding);
+    for capability in 0..=last_capability()? {
+        if bounding & (1 << capability) != 0 {
+            continue
+        }
+        prctl(libc::PR_CAPBSET_DROP, capability as libc::c_ulong, 0)
+            .map_err(|e| format!("cannot drop capability {} from the bounding set: {}", capability, e))?;
+    }
+
+    Ok(())
+}
+/// Set the effective, permitted, inheritable and ambient sets as in the spec
+///
+/// Done after changing user, which clears the effective set
+pub fn set_capabilities(capabilities: &Capability) -> Result<(), Box<dyn std::error::Error>> {
+    info!("setting capabilities...");
+
+    let effective = mask(&capabilities.effective);
+    let permitted = mask(&capabilities.permitted);
+    let inheritable = mask(&capabilities.inheritable);
+
+    let mut header = CapabilityHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    // Version 3 splits the 64 bit sets in two 32 bit halves
+    let data = [
+        CapabilityData {
+            effective: effective as u32,
+            permitted: permitted as u32,
+            inheritable: inheritable as u32,
+        },
+        CapabilityData {
+            effective: (effective >> 32) as u32,
+            permitted: (permitted >> 32) as u32,
+            inheritable: (inheritable >> 32) as u32,
+        },
+    ];
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header as *mut CapabilityHeader, data.as_ptr()) } < 0 {
+        return Err(format!("cannot set capabilities: {}", io::Error::last_os_error()).into())
+    }
+
+    prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong, 0)?;
+    for capability in &capabilities.ambient {
+        prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong, *capability as libc::c_ulong)
+            .map_err(|e| format!("cannot raise ambient capability {:?}: {}", capability, e))?;
+    }
+
+    Ok(())
+}
+
+/// Bit mask of a set of capabilities
+fn mask(capabilities: &[CapabilityType]) -> u64 {
+    capabilities.iter().fold(0, |mask, capability| mask | 1 << *capability as u64)
+}
+/// Highest capability supported by the running kernel
+fn last_capability() -> Result<u8, Box<dyn std::error::Error>> {
+    Ok(read_to_string("/proc/sys/kernel/cap_last_cap")?.trim().parse()?)
+}
+fn prctl(option: libc::c_int, arg2: libc::c_ulong, arg3: libc::c_ulong) -> Result<(), io::Error> {
+    if unsafe { libc::prctl(option, arg2, arg3, 0, 0) } < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(())
+}
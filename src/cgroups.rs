@@ -0,0 +1,666 @@
+use std::fs::{self, create_dir_all, read_to_string};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
+use nix::unistd::getpid;
+
+use log::{info, debug};
+
+use crate::config::Config;
+use crate::container::Container;
+use crate::devices;
+use crate::device_filter;
+use crate::spec::{BlockIo, CpuCgroup, Device, DeviceCgroup, DeviceType, HugepageLimit, MemoryCgroup, Network, Pids, Resources};
+
+
+/// Where the cgroup hierarchies are mounted
+const CGROUP_MOUNTPOINT: &str = "/sys/fs/cgroup";
+/// Period of 'cpu.max' when the spec has a quota without one, as the kernel default
+const DEFAULT_CPU_PERIOD: u64 = 100_000;
+
+/// Get the mountpoint of the cgroup v2 hierarchy, if the host has one
+///
+/// It's '/sys/fs/cgroup' on cgroup v2 only hosts, and '/sys/fs/cgroup/unified' on hybrid ones,
+/// where the controllers are usually kept by the cgroup v1 hierarchies
+pub fn unified_mountpoint() -> Option<String> {
+    [CGROUP_MOUNTPOINT.to_string(), format!("{}/unified", CGROUP_MOUNTPOINT)].iter()
+        .find(|path| Path::new(&format!("{}/cgroup.controllers", path)).exists())
+        .cloned()
+}
+/// Get the cgroup v1 hierarchies of the current process, as their controllers and mountpoint
+///
+/// Each is mounted in '/sys/fs/cgroup', in a directory named after its controllers
+pub fn v1_hierarchies() -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    // Lines are 'hierarchy-id:controllers:path', the controllers are empty for cgroup v2
+    let cgroups = read_to_string("/proc/self/cgroup")?;
+    let hierarchies = cgroups.lines()
+        .filter_map(|line| line.split(':').nth(1))
+        .filter(|controllers| !controllers.is_empty())
+        .map(|controllers| (controllers.to_string(), format!("{}/{}", CGROUP_MOUNTPOINT, controllers.trim_start_matches("name="))))
+        .filter(|(_, mountpoint)| Path::new(mountpoint).join("cgroup.procs").exists())
+        .collect();
+    Ok(hierarchies)
+}
+/// Get the cgroup of a container, relative to the hierarchy root
+///
+/// An absolute 'linux.cgroupsPath' is used as is, a relative one is put under the parent of the host configuration,
+/// and containers without one get a cgroup named after them there
+pub fn container_cgroup(container: &Container) -> Result<String, Box<dyn std::error::Error>> {
+    let cgroups_path = container.spec.linux.as_ref()
+        .map(|linux| linux.cgroup_path.as_str())
+        .unwrap_or_default();
+    if cgroups_path.starts_with('/') {
+        return Ok(cgroups_path.trim_end_matches('/').to_string())
+    }
+
+    let parent = Config::load()?.cgroups.parent;
+    let name = if cgroups_path.is_empty() { container.id.as_str() } else { cgroups_path };
+    Ok(format!("/{}/{}", parent.trim_matches('/'), name.trim_end_matches('/')))
+}
+
+/// Create the container cgroups, with the spec resources as limits, and move the current process into them
+///
+/// The container gets a cgroup in every cgroup v1 hierarchy, and in the cgroup v2 one.
+/// Done before unsharing the cgroup namespace, so the container cgroups are its root
+pub fn create(container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+    info!("creating container cgroups...");
+
+    let linux = container.spec.linux.as_ref().ok_or("missing linux section")?;
+    let cgroup = container_cgroup(container)?;
+    let mut cgroup_paths = Vec::new();
+    let result = create_cgroups(&cgroup, &mut cgroup_paths)
+        .and_then(|_| match &linux.resources {
+            Some(resources) if !cgroup_paths.is_empty() => set_resources(&cgroup, resources, &linux.devices),
+            _                                           => Ok(())
+        })
+        .and_then(|_| cgroup_paths.iter().try_for_each(|path| write(path, "cgroup.procs", &getpid().to_string())));
+    if result.is_err() {
+        for path in cgroup_paths.iter().rev() {
+            let _ = fs::remove_dir(path);
+        }
+    }
+    result?;
+
+    if cgroup_paths.is_empty() {
+        info!("no cgroup hierarchy mounted. skipping...");
+    }
+    info!("created container cgroups.");
+    Ok(())
+}
+/// Remove the container cgroups, once their processes are gone
+///
+/// Exited processes can take a moment to leave them, so removing a busy cgroup is retried for a while
+pub fn remove(container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+    let cgroup = container_cgroup(container)?;
+    let mut mountpoints: Vec<String> = v1_hierarchies()?.into_iter()
+        .map(|(_, mountpoint)| mountpoint)
+        .collect();
+    mountpoints.extend(unified_mountpoint());
+
+    for mountpoint in mountpoints {
+        let cgroup_path = format!("{}{}", mountpoint, cgroup);
+        if !Path::new(&cgroup_path).exists() {
+            continue
+        }
+        info!("removing cgroup {}...", cgroup_path);
+
+        let mut removed = false;
+        for _ in 0..50 {
+            match fs::remove_dir(&cgroup_path) {
+                Err(e) if e.raw_os_error() == Some(nix::libc::EBUSY) => sleep(Duration::from_millis(100)),
+                Err(e) if e.kind() == ErrorKind::NotFound             => { removed = true; break },
+                result => {
+                    result.map_err(|e| format!("cannot remove cgroup {}: {}", cgroup_path, e))?;
+                    removed = true;
+                    break
+                }
+            }
+        }
+        if !removed {
+            return Err(format!("cannot remove cgroup {}: still busy", cgroup_path).into())
+        }
+    }
+
+    Ok(())
+}
+/// Move the current process to the cgroups of another process, in every mounted hierarchy
+pub fn join(pid: i32) -> Result<(), Box<dyn std::error::Error>> {
+    info!("joining container cgroups...");
+
+    let cgroups = read_to_string(format!("/proc/{}/cgroup", pid))?;
+    let own_pid = getpid().to_string();
+    for line in cgroups.lines() {
+        // 'hierarchy-id:controllers:path', the controllers are empty for cgroup v2
+        let fields: Vec<&str> = line.splitn(3, ':').collect();
+        if fields.len() != 3 {
+            continue
+        }
+        let hierarchy_path = match fields[1] {
+            ""          => match unified_mountpoint() {
+                Some(mountpoint) => mountpoint,
+                None             => continue
+            },
+            controllers => format!("{}/{}", CGROUP_MOUNTPOINT, controllers.trim_start_matches("name="))
+        };
+        let cgroup_path = format!("{}{}", hierarchy_path, fields[2]);
+        if !Path::new(&cgroup_path).join("cgroup.procs").exists() {
+            debug!("cgroup '{}' not mounted. skipping...", line);
+            continue
+        }
+
+        write(&cgroup_path, "cgroup.procs", &own_pid)?;
+    }
+
+    info!("joined container cgroups.");
+    Ok(())
+}
+/// Freeze or thaw the processes of the container cgroups, with the freezer controller or 'cgroup.freeze' on cgroup v2
+///
+/// Freezing takes a moment for busy processes, so it's waited for a while, and undone if they're still not frozen
+pub fn freeze(container: &Container, frozen: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let cgroup = container_cgroup(container)?;
+    // 'freezer.state' reads 'FREEZING' until every process is frozen, 'cgroup.events' has a 'frozen 0|1' line
+    let (cgroup_path, file, (frozen_value, thawed_value), events_file, frozen_event) = match controller_cgroup("freezer", &cgroup)? {
+        Cgroup::V1(path) => (path, "freezer.state", ("FROZEN", "THAWED"), "freezer.state", "FROZEN"),
+        Cgroup::V2(path) => (path, "cgroup.freeze", ("1", "0"), "cgroup.events", "frozen 1"),
+    };
+    if !frozen {
+        info!("thawing cgroup {}...", cgroup_path);
+        return write(&cgroup_path, file, thawed_value)
+    }
+
+    info!("freezing cgroup {}...", cgroup_path);
+    write(&cgroup_path, file, frozen_value)?;
+    for _ in 0..50 {
+        let events = read(&cgroup_path, events_file).unwrap_or_default();
+        if events.lines().any(|line| line.trim() == frozen_event) {
+            return Ok(())
+        }
+        sleep(Duration::from_millis(100));
+    }
+
+    write(&cgroup_path, file, thawed_value)?;
+    Err(format!("cannot freeze cgroup {}: its processes didn't stop in time", cgroup_path).into())
+}
+
+/// Resource usage of a container, from the accounting files of its cgroups
+///
+/// Values are missing when their controller isn't mounted, or the container isn't running
+#[derive(Debug, Default)]
+pub struct Usage {
+    /// Cpu time used, in nanoseconds
+    pub cpu: Option<u64>,
+    /// Memory used, in bytes, without the inactive file cache
+    pub memory: Option<u64>,
+    pub memory_limit: Option<u64>,
+    pub pids: Option<u64>,
+    /// Bytes read from and written to block devices
+    pub block_read: Option<u64>,
+    pub block_write: Option<u64>,
+}
+
+/// Read the resource usage of a container from its cgroups
+pub fn usage(container: &Container) -> Result<Usage, Box<dyn std::error::Error>> {
+    let cgroup = container_cgroup(container)?;
+    let mut usage = Usage::default();
+
+    if let Ok(cgroup) = controller_cgroup("cpuacct", &cgroup) {
+        usage.cpu = match cgroup {
+            Cgroup::V1(path) => read_number(&path, "cpuacct.usage"),
+            Cgroup::V2(path) => read_key(&path, "cpu.stat", "usage_usec").map(|usec| usec * 1000),
+        };
+    }
+    if let Ok(cgroup) = controller_cgroup("memory", &cgroup) {
+        let (memory, inactive_file) = match &cgroup {
+            Cgroup::V1(path) => (read_number(path, "memory.usage_in_bytes"), read_key(path, "memory.stat", "total_inactive_file")),
+            Cgroup::V2(path) => (read_number(path, "memory.current"), read_key(path, "memory.stat", "inactive_file")),
+        };
+        usage.memory = memory.map(|memory| memory.saturating_sub(inactive_file.unwrap_or(0)));
+        usage.memory_limit = match &cgroup {
+            Cgroup::V1(path) => read_number(path, "memory.limit_in_bytes"),
+            Cgroup::V2(path) => read_number(path, "memory.max"),
+        };
+    }
+    if let Ok(Cgroup::V1(path)) | Ok(Cgroup::V2(path)) = controller_cgroup("pids", &cgroup) {
+        usage.pids = read_number(&path, "pids.current");
+    }
+    if let Ok(cgroup) = controller_cgroup("blkio", &cgroup) {
+        let block_io = match cgroup {
+            Cgroup::V1(path) => read(&path, "blkio.throttle.io_service_bytes_recursive").map(|stats| block_io_v1(&stats)),
+            Cgroup::V2(path) => read(&path, "io.stat").map(|stats| block_io_v2(&stats)),
+        };
+        if let Some((read, write)) = block_io {
+            usage.block_read = Some(read);
+            usage.block_write = Some(write);
+        }
+    }
+
+    Ok(usage)
+}
+
+/// Container cgroup in the hierarchy of a controller
+enum Cgroup {
+    V1(String),
+    V2(String),
+}
+
+/// Get the container cgroup in the hierarchy of a controller, named as in cgroup v1
+///
+/// Controllers missing from the cgroup v1 hierarchies are looked for in the cgroup v2 one
+fn controller_cgroup(controller: &str, cgroup: &str) -> Result<Cgroup, Box<dyn std::error::Error>> {
+    for (controllers, mountpoint) in v1_hierarchies()? {
+        if controllers.split(',').any(|name| name == controller) {
+            return Ok(Cgroup::V1(format!("{}{}", mountpoint, cgroup)))
+        }
+    }
+    match unified_mountpoint() {
+        Some(mountpoint) => Ok(Cgroup::V2(format!("{}{}", mountpoint, cgroup))),
+        None             => Err(format!("the {} cgroup controller isn't mounted", controller).into())
+    }
+}
+/// Create a cgroup in every hierarchy, adding their paths to the created ones
+fn create_cgroups(cgroup: &str, cgroup_paths: &mut Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    for (_, mountpoint) in v1_hierarchies()? {
+        cgroup_paths.push(create_v1_cgroup(&mountpoint, cgroup)?);
+    }
+    if let Some(mountpoint) = unified_mountpoint() {
+        cgroup_paths.push(create_v2_cgroup(&mountpoint, cgroup)?);
+    }
+    Ok(())
+}
+/// Create a cgroup v1 cgroup and its missing ancestors
+///
+/// New cpuset cgroups have no cpus nor memory nodes, so they get the ones of their parent
+fn create_v1_cgroup(mountpoint: &str, cgroup: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut path = mountpoint.to_string();
+    for name in cgroup.trim_matches('/').split('/') {
+        let parent = path.clone();
+        path = format!("{}/{}", path, name);
+        create_dir_all(&path)
+            .map_err(|e| format!("cannot create cgroup {}: {}", path, e))?;
+
+        for file in &["cpuset.cpus", "cpuset.mems"] {
+            let file_path = format!("{}/{}", path, file);
+            if Path::new(&file_path).exists() && read_to_string(&file_path)?.trim().is_empty() {
+                write(&path, file, read_to_string(format!("{}/{}", parent, file))?.trim())?;
+            }
+        }
+    }
+
+    Ok(path)
+}
+/// Create a cgroup v2 cgroup and its missing ancestors, enabling the available controllers of each ancestor for its children
+///
+/// Controllers that can't be enabled are left out, setting their limits fails later
+fn create_v2_cgroup(mountpoint: &str, cgroup: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut path = mountpoint.to_string();
+    for name in cgroup.trim_matches('/').split('/') {
+        let controllers = read_to_string(format!("{}/cgroup.controllers", path))?;
+        for controller in controllers.split_whitespace() {
+            if let Err(e) = fs::write(format!("{}/cgroup.subtree_control", path), format!("+{}", controller)) {
+                debug!("cannot enable {} in {}: {}", controller, path, e);
+            }
+        }
+
+        path = format!("{}/{}", path, name);
+        create_dir_all(&path)
+            .map_err(|e| format!("cannot create cgroup {}: {}", path, e))?;
+    }
+
+    Ok(path)
+}
+/// Write the spec resources to the interface files of the hierarchy of each controller
+///
+/// Resources without a cgroup v2 equivalent, like the kernel memory, are ignored in cgroup v2
+fn set_resources(cgroup: &str, resources: &Resources, devices: &[Device]) -> Result<(), Box<dyn std::error::Error>> {
+    info!("setting cgroup resources...");
+
+    if let Some(memory) = &resources.memory {
+        match controller_cgroup("memory", cgroup)? {
+            Cgroup::V1(path) => set_memory_v1(&path, memory)?,
+            Cgroup::V2(path) => set_memory_v2(&path, memory)?,
+        }
+    }
+    if let Some(cpu) = &resources.cpu {
+        match controller_cgroup("cpu", cgroup)? {
+            Cgroup::V1(path) => set_cpu_v1(&path, cpu)?,
+            Cgroup::V2(path) => set_cpu_v2(&path, cpu)?,
+        }
+        match controller_cgroup("cpuset", cgroup)? {
+            Cgroup::V1(path) | Cgroup::V2(path) => set_cpuset(&path, cpu)?,
+        }
+    }
+    if let Some(pids) = &resources.pids {
+        match controller_cgroup("pids", cgroup)? {
+            Cgroup::V1(path) | Cgroup::V2(path) => set_pids(&path, pids)?,
+        }
+    }
+    if let Some(block_io) = &resources.block_io {
+        match controller_cgroup("blkio", cgroup)? {
+            Cgroup::V1(path) => set_block_io_v1(&path, block_io)?,
+            Cgroup::V2(path) => set_block_io_v2(&path, block_io)?,
+        }
+    }
+    if !resources.hugepage_limits.is_empty() {
+        match controller_cgroup("hugetlb", cgroup)? {
+            Cgroup::V1(path) => set_hugepages(&path, &resources.hugepage_limits, "limit_in_bytes")?,
+            Cgroup::V2(path) => set_hugepages(&path, &resources.hugepage_limits, "max")?,
+        }
+    }
+    if let Some(network) = &resources.network {
+        set_network(cgroup, network)?;
+    }
+    if !resources.devices.is_empty() {
+        let rules = device_rules(&resources.devices, devices);
+        match controller_cgroup("devices", cgroup)? {
+            Cgroup::V1(path) => set_devices_v1(&path, &rules)?,
+            Cgroup::V2(path) => device_filter::attach(&path, &rules)?,
+        }
+    }
+
+    info!("set cgroup resources.");
+    Ok(())
+}
+/// Memory limits of cgroup v1, where the swap limit counts the memory too, like in the spec
+fn set_memory_v1(cgroup_path: &str, memory: &MemoryCgroup) -> Result<(), Box<dyn std::error::Error>> {
+    if memory.limit != 0 {
+        write(cgroup_path, "memory.limit_in_bytes", &memory.limit.to_string())?;
+    }
+    if memory.reservation != 0 {
+        write(cgroup_path, "memory.soft_limit_in_bytes", &memory.reservation.to_string())?;
+    }
+    if memory.swap != 0 {
+        write(cgroup_path, "memory.memsw.limit_in_bytes", &memory.swap.to_string())?;
+    }
+    if memory.kernel != 0 {
+        write(cgroup_path, "memory.kmem.limit_in_bytes", &memory.kernel.to_string())?;
+    }
+    if memory.kernel_tcp != 0 {
+        write(cgroup_path, "memory.kmem.tcp.limit_in_bytes", &memory.kernel_tcp.to_string())?;
+    }
+    if let Some(swappiness) = memory.swappiness {
+        write(cgroup_path, "memory.swappiness", &swappiness.to_string())?;
+    }
+    if memory.disable_oom_killer {
+        write(cgroup_path, "memory.oom_control", "1")?;
+    }
+
+    Ok(())
+}
+/// Memory limits of cgroup v2, where the swap is limited alone
+fn set_memory_v2(cgroup_path: &str, memory: &MemoryCgroup) -> Result<(), Box<dyn std::error::Error>> {
+    if memory.limit != 0 {
+        write(cgroup_path, "memory.max", &limit(memory.limit))?;
+    }
+    if memory.reservation != 0 {
+        write(cgroup_path, "memory.low", &limit(memory.reservation))?;
+    }
+    match memory.swap {
+        0  => {},
+        -1 => write(cgroup_path, "memory.swap.max", "max")?,
+        swap if memory.limit > 0 && swap >= memory.limit => {
+            write(cgroup_path, "memory.swap.max", &(swap - memory.limit).to_string())?
+        },
+        swap => return Err(format!("memory swap {} must be at least the memory limit", swap).into())
+    }
+
+    Ok(())
+}
+/// Cpu limits of cgroup v1, the period is set before the quota
+fn set_cpu_v1(cgroup_path: &str, cpu: &CpuCgroup) -> Result<(), Box<dyn std::error::Error>> {
+    if cpu.shares != 0 {
+        write(cgroup_path, "cpu.shares", &cpu.shares.to_string())?;
+    }
+    if cpu.period != 0 {
+        write(cgroup_path, "cpu.cfs_period_us", &cpu.period.to_string())?;
+    }
+    if cpu.quota != 0 {
+        write(cgroup_path, "cpu.cfs_quota_us", &cpu.quota.to_string())?;
+    }
+    if cpu.realtime_period != 0 {
+        write(cgroup_path, "cpu.rt_period_us", &cpu.realtime_period.to_string())?;
+    }
+    if cpu.realtime_runtime != 0 {
+        write(cgroup_path, "cpu.rt_runtime_us", &cpu.realtime_runtime.to_string())?;
+    }
+
+    Ok(())
+}
+/// Cpu limits of cgroup v2: the shares become a weight, the quota and period 'cpu.max'
+fn set_cpu_v2(cgroup_path: &str, cpu: &CpuCgroup) -> Result<(), Box<dyn std::error::Error>> {
+    if cpu.shares != 0 {
+        // From the [2, 262144] range of the shares to the [1, 10000] one of the weight
+        let shares = cpu.shares.clamp(2, 262_144);
+        write(cgroup_path, "cpu.weight", &(1 + (shares - 2) * 9999 / 262_142).to_string())?;
+    }
+    if cpu.quota != 0 || cpu.period != 0 {
+        let quota = if cpu.quota > 0 { cpu.quota.to_string() } else { "max".to_string() };
+        let period = if cpu.period != 0 { cpu.period } else { DEFAULT_CPU_PERIOD };
+        write(cgroup_path, "cpu.max", &format!("{} {}", quota, period))?;
+    }
+    if cpu.realtime_runtime != 0 || cpu.realtime_period != 0 {
+        return Err("realtime cpu scheduling isn't supported by cgroup v2".into())
+    }
+
+    Ok(())
+}
+/// Cpus and memory nodes the container can use, the same in both versions
+fn set_cpuset(cgroup_path: &str, cpu: &CpuCgroup) -> Result<(), Box<dyn std::error::Error>> {
+    if !cpu.cpus.is_empty() {
+        write(cgroup_path, "cpuset.cpus", &cpu.cpus)?;
+    }
+    if !cpu.mems.is_empty() {
+        write(cgroup_path, "cpuset.mems", &cpu.mems)?;
+    }
+    Ok(())
+}
+/// Number of processes limit, unlimited if negative, the same in both versions
+fn set_pids(cgroup_path: &str, pids: &Pids) -> Result<(), Box<dyn std::error::Error>> {
+    if pids.limit != 0 {
+        write(cgroup_path, "pids.max", &limit(pids.limit))?;
+    }
+    Ok(())
+}
+/// Block io limits of cgroup v1
+///
+/// With the BFQ scheduler, the weights go to its own file, which takes the device ones too
+fn set_block_io_v1(cgroup_path: &str, block_io: &BlockIo) -> Result<(), Box<dyn std::error::Error>> {
+    let (weight_file, weight_device_file) = match Path::new(cgroup_path).join("blkio.weight").exists() {
+        true  => ("blkio.weight", "blkio.weight_device"),
+        false => ("blkio.bfq.weight", "blkio.bfq.weight")
+    };
+
+    if block_io.weight != 0 {
+        write(cgroup_path, weight_file, &block_io.weight.to_string())?;
+    }
+    if block_io.leaf_weight != 0 {
+        write(cgroup_path, "blkio.leaf_weight", &block_io.leaf_weight.to_string())?;
+    }
+    for device in &block_io.weight_device {
+        if device.weight != 0 {
+            write(cgroup_path, weight_device_file, &format!("{}:{} {}", device.major, device.minor, device.weight))?;
+        }
+        if device.leaf_weight != 0 {
+            write(cgroup_path, "blkio.leaf_weight_device", &format!("{}:{} {}", device.major, device.minor, device.leaf_weight))?;
+        }
+    }
+    for device in &block_io.throttle_read_bps_device {
+        write(cgroup_path, "blkio.throttle.read_bps_device", &format!("{}:{} {}", device.major, device.minor, device.rate))?;
+    }
+    for device in &block_io.throttle_write_bps_device {
+        write(cgroup_path, "blkio.throttle.write_bps_device", &format!("{}:{} {}", device.major, device.minor, device.rate))?;
+    }
+
+    Ok(())
+}
+/// Block io limits of cgroup v2: the weights go from the [10, 1000] range to the [1, 10000] one, the throttles to 'io.max'
+///
+/// With the BFQ scheduler, the weights go to its own file
+fn set_block_io_v2(cgroup_path: &str, block_io: &BlockIo) -> Result<(), Box<dyn std::error::Error>> {
+    let weight = |weight: u16| 1 + (weight.clamp(10, 1000) as u64 - 10) * 9999 / 990;
+
+    let weight_file = match Path::new(cgroup_path).join("io.weight").exists() {
+        true  => "io.weight",
+        false => "io.bfq.weight"
+    };
+
+    if block_io.weight != 0 {
+        write(cgroup_path, weight_file, &format!("default {}", weight(block_io.weight)))?;
+    }
+    for device in block_io.weight_device.iter().filter(|device| device.weight != 0) {
+        write(cgroup_path, weight_file, &format!("{}:{} {}", device.major, device.minor, weight(device.weight)))?;
+    }
+    for device in &block_io.throttle_read_bps_device {
+        write(cgroup_path, "io.max", &format!("{}:{} rbps={}", device.major, device.minor, device.rate))?;
+    }
+    for device in &block_io.throttle_write_bps_device {
+        write(cgroup_path, "io.max", &format!("{}:{} wbps={}", device.major, device.minor, device.rate))?;
+    }
+
+    Ok(())
+}
+/// Huge pages limits, per page size, to the 'hugetlb.<size>.<file>' files
+fn set_hugepages(cgroup_path: &str, hugepage_limits: &[HugepageLimit], file: &str) -> Result<(), Box<dyn std::error::Error>> {
+    for hugepage_limit in hugepage_limits {
+        write(cgroup_path, &format!("hugetlb.{}.{}", hugepage_limit.page_size, file), &hugepage_limit.limit.to_string())?;
+    }
+    Ok(())
+}
+/// Network class and interface priorities, only in cgroup v1
+fn set_network(cgroup: &str, network: &Network) -> Result<(), Box<dyn std::error::Error>> {
+    if network.class_id != 0 {
+        match controller_cgroup("net_cls", cgroup)? {
+            Cgroup::V1(path) => write(&path, "net_cls.classid", &network.class_id.to_string())?,
+            Cgroup::V2(_)    => debug!("no network class on cgroup v2. skipping..."),
+        }
+    }
+    if !network.priorities.is_empty() {
+        match controller_cgroup("net_prio", cgroup)? {
+            Cgroup::V1(path) => for priority in &network.priorities {
+                write(&path, "net_prio.ifpriomap", &format!("{} {}", priority.name, priority.priority))?;
+            },
+            Cgroup::V2(_)    => debug!("no network priorities on cgroup v2. skipping..."),
+        }
+    }
+
+    Ok(())
+}
+/// Get the device access rules of the spec, followed by the ones allowing the devices every container gets and the spec devices
+///
+/// So these stay usable, whatever the rules
+fn device_rules(rules: &[DeviceCgroup], devices: &[Device]) -> Vec<DeviceCgroup> {
+    let allow = |typ: &str, major: i64, minor: Option<i64>| DeviceCgroup {
+        allow: true,
+        typ: typ.to_string(),
+        major: Some(major),
+        minor,
+        access: String::from("rwm"),
+    };
+
+    let mut all_rules = rules.to_vec();
+    for (_, major, minor) in devices::DEFAULT_DEVICES.iter() {
+        all_rules.push(allow("c", *major as i64, Some(*minor as i64)));
+    }
+    all_rules.push(allow("c", 5, Some(2)));
+    all_rules.push(allow("c", 136, None));
+    for device in devices {
+        match device.typ {
+            DeviceType::b                 => all_rules.push(allow("b", device.major as i64, Some(device.minor as i64))),
+            DeviceType::c | DeviceType::u => all_rules.push(allow("c", device.major as i64, Some(device.minor as i64))),
+            _                             => continue
+        }
+    }
+
+    all_rules
+}
+/// Device access rules of cgroup v1, applied in order
+fn set_devices_v1(cgroup_path: &str, rules: &[DeviceCgroup]) -> Result<(), Box<dyn std::error::Error>> {
+    let number = |number: Option<i64>| number.map_or("*".to_string(), |number| number.to_string());
+    for rule in rules {
+        let file = if rule.allow { "devices.allow" } else { "devices.deny" };
+        let typ = if rule.typ.is_empty() { "a" } else { rule.typ.as_str() };
+        let access = if rule.access.is_empty() { "rwm" } else { rule.access.as_str() };
+        write(cgroup_path, file, &format!("{} {}:{} {}", typ, number(rule.major), number(rule.minor), access))?;
+    }
+
+    Ok(())
+}
+
+/// Read an interface file of a cgroup, if it can be read
+fn read(cgroup_path: &str, file: &str) -> Option<String> {
+    read_to_string(format!("{}/{}", cgroup_path, file)).ok()
+}
+/// Read a number from an interface file of a cgroup, missing if it's not a number, like 'max'
+fn read_number(cgroup_path: &str, file: &str) -> Option<u64> {
+    read(cgroup_path, file)?.trim().parse().ok()
+}
+/// Read a number from the 'key value' lines of an interface file of a cgroup
+fn read_key(cgroup_path: &str, file: &str, key: &str) -> Option<u64> {
+    read(cgroup_path, file)?.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .find(|fields| fields.len() == 2 && fields[0] == key)
+        .and_then(|fields| fields[1].parse().ok())
+}
+/// Sum the bytes read and written on every device, from lines like '8:0 Read 4096'
+fn block_io_v1(stats: &str) -> (u64, u64) {
+    stats.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .filter(|fields| fields.len() == 3)
+        .fold((0, 0), |(read, write), fields| match (fields[1], fields[2].parse::<u64>()) {
+            ("Read", Ok(bytes))  => (read + bytes, write),
+            ("Write", Ok(bytes)) => (read, write + bytes),
+            _                    => (read, write)
+        })
+}
+/// Sum the bytes read and written on every device, from lines like '8:0 rbytes=4096 wbytes=0 ...'
+fn block_io_v2(stats: &str) -> (u64, u64) {
+    stats.split_whitespace()
+        .filter_map(|field| {
+            let mut parts = field.splitn(2, '=');
+            Some((parts.next()?, parts.next()?.parse::<u64>().ok()?))
+        })
+        .fold((0, 0), |(read, write), (key, bytes)| match key {
+            "rbytes" => (read + bytes, write),
+            "wbytes" => (read, write + bytes),
+            _        => (read, write)
+        })
+}
+/// Format a spec limit for cgroup v2 and the pids controller, where no limit is 'max'
+fn limit(value: i64) -> String {
+    match value {
+        value if value < 0 => "max".to_string(),
+        value              => value.to_string()
+    }
+}
+/// Write a value to an interface file of a cgroup
+fn write(cgroup_path: &str, file: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let path = format!("{}/{}", cgroup_path, file);
+    if !Path::new(&path).exists() {
+        return Err(format!("cannot set {}: its controller isn't available", file).into())
+    }
+
+    debug!("writing {} to {}", value, path);
+    fs::write(&path, value)
+        .map_err(|e| format!("cannot set {} to '{}': {}", file, value, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_io_v1_sums_devices() {
+        let stats = "8:0 Read 4096\n8:0 Write 512\n8:0 Sync 10\n8:0 Total 4608\n8:16 Read 4\n8:16 Write x\nTotal 4612\n";
+        assert_eq!(block_io_v1(stats), (4100, 512));
+        assert_eq!(block_io_v1(""), (0, 0));
+    }
+
+    #[test]
+    fn block_io_v2_sums_devices() {
+        let stats = "8:0 rbytes=4096 wbytes=512 rios=1 wios=2 dbytes=7 dios=1\n8:16 rbytes=4 wbytes=0 rios=1 wios=0\n";
+        assert_eq!(block_io_v2(stats), (4100, 512));
+        assert_eq!(block_io_v2(""), (0, 0));
+    }
+}
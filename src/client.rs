@@ -1,9 +1,10 @@
 use std::os::unix::net::UnixStream;
+use std::net::Shutdown;
 use std::io::prelude::*;
 
 use log::info;
 
-use crate::utils;
+use crate::paths;
 
 pub struct Client {
     stream: UnixStream
@@ -13,7 +14,7 @@ impl Client {
         info!("creating client...");
 
         let s_name = String::from("socket");
-        let s_path_str = utils::get_socket_path(s_name.as_str()).unwrap();
+        let s_path_str = paths::get_socket_path(s_name.as_str()).unwrap();
         let socket = Client::connect_to_socket(s_path_str.clone())?;
 
         info!("created client.");
@@ -33,12 +34,14 @@ impl Client {
         Ok(stream)
     }
 
+    /// Send a message, ending it by shutting down the write end, and log the response
     pub fn send(&self, message: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
 
         let mut temp_stream = self.stream.try_clone()?;
 
         info!("sending message...");
         temp_stream.write_all(message)?;
+        temp_stream.shutdown(Shutdown::Write)?;
 
         info!("reading response...");
         let mut buffer = [0; 1024];
@@ -0,0 +1,85 @@
+#![allow(non_camel_case_types)]
+use std::path::Path;
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::paths;
+
+
+/// Host-wide settings, shared by every container and image on the host
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub cgroups: Cgroups,
+}
+impl Config {
+    /// Load the host configuration, falling back to the defaults if there is none
+    pub fn load() -> Result<Config, Box<dyn std::error::Error>> {
+        let path = paths::get_config_path()?;
+        if !Path::new(&path).exists() {
+            return Ok(Config::default())
+        }
+
+        let file = File::open(path)?;
+        let buf_reader = BufReader::new(file);
+        let config: Config = serde_json::from_reader(buf_reader)?;
+        Ok(config)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Storage {
+    #[serde(default, rename = "layerFormat")]
+    pub layer_format: LayerFormat,
+    #[serde(default)]
+    pub driver: StorageDriverType,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Cgroups {
+    /// Cgroup holding the container cgroups, relative to the hierarchy root
+    #[serde(default = "default_cgroup_parent")]
+    pub parent: String,
+}
+
+impl Default for Cgroups {
+    fn default() -> Cgroups {
+        Cgroups {
+            parent: default_cgroup_parent(),
+        }
+    }
+}
+
+fn default_cgroup_parent() -> String {
+    String::from("minato")
+}
+
+/// How downloaded layers are stored
+///
+///   - directory: unpacked in the image directory
+///   - squashfs, erofs: converted once into a read-only image, loop-mounted when used
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum LayerFormat {
+    #[default]
+    directory,
+    squashfs,
+    erofs,
+}
+
+/// How the writable root filesystem of a container is built on top of its image
+///
+///   - overlay: kernel overlayfs
+///   - fuse-overlayfs: overlayfs in userspace, for kernels that can't mount it unprivileged
+///   - vfs: full copy of the image, works on any filesystem
+///   - btrfs: snapshot of an image subvolume, the storage must be on btrfs
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum StorageDriverType {
+    #[default]
+    overlay,
+    #[serde(rename = "fuse-overlayfs")]
+    fuse_overlayfs,
+    vfs,
+    btrfs,
+}
@@ -5,35 +5,69 @@ use std::path::Path;
 use std::os::unix;
 use std::ffi::CString;
 use std::env;
-use nix::mount::{mount, MntFlags, MsFlags, umount, umount2};
-use nix::sched::{CloneFlags, unshare};
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use nix::errno::Errno;
+use nix::mount::{mount, MntFlags, MsFlags, umount2};
+use nix::sched::{CloneFlags, unshare, setns};
 use nix::sys::stat::Mode;
 use std::process::exit;
 // use nix::sys::stat::{Mode, makedev, mknod, SFlag};
-use nix::sys::wait::waitpid;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::sys::signal::{kill, Signal};
 use nix::unistd::*;
 use nix::fcntl::{open, OFlag};
+use nix::libc;
 #[allow(unused_imports)]
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
-// use dirs;
 use log::{info, error, debug};
 
 use crate::image::Image;
+use crate::paths;
 use crate::utils;
 use crate::networking;
-use crate::spec::Spec;
+use crate::storage::{self, Change};
+use crate::state::{State, Status};
+use crate::terminal;
+use crate::capabilities;
+use crate::cgroups;
+use crate::logs;
+use crate::attach;
+use crate::mounts;
+use crate::devices;
+use crate::seccomp;
+use crate::userns;
+use crate::spec::{Mapping, RlimitType, Seccomp, Spec};
 use crate::spec::Namespace;
 use crate::spec::NamespaceType;
 
 
+/// Uid and gid maps of a user namespace
+pub type IdMappings<'a> = (&'a [Mapping], &'a [Mapping]);
+
+/// How the container process reports to its creator and waits to be started
+struct Init {
+    /// Receives the container process pid, as seen from the host, then a byte once it's ready
+    pipe: RawFd,
+    /// Wait for 'start' on the container exec fifo before executing the process
+    exec_fifo: bool,
+    /// Socket to send the master end of the container terminal to
+    console_socket: Option<String>,
+    /// Slave end of a terminal opened by the supervisor, made the container terminal
+    terminal: Option<RawFd>,
+    /// Leave the container process to be reparented instead of waiting for it
+    detach: bool,
+    /// Pipes for the standard input, output and error of the container process
+    stdio: Option<(RawFd, RawFd, RawFd)>,
+}
 
 pub struct Container {
     pub id: String,
     pub image: Option<Image>,
     pub path: String,
     pub spec: Spec,
-    // pub state: State,
+    pub state: State,
 }
 impl Container {
     /// Create a new container object
@@ -48,16 +82,17 @@ impl Container {
                     .collect::<String>()
             }
         };
-        let path = utils::get_container_path_with_str(id.as_str()).unwrap();
+        let path = paths::get_container_path_with_str(id.as_str()).unwrap();
         // let path = String::from();
         let spec = Spec::new().unwrap();
+        let state = State::new(id.as_str(), path.as_str(), HashMap::new());
 
         Container {
             id,
             image,
             path,
             spec,
-            // state: State::Stopped,
+            state,
         }
     }
 
@@ -73,26 +108,36 @@ impl Container {
         info!("created config json.");
         Ok(())
     }
+    /// Store the changes made to the spec of a container created from an image
+    pub fn save_spec(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.is_bundle() {
+            return Err("the configuration of containers created from a bundle is read from the bundle".into())
+        }
+        self.generate_config_json()
+    }
     /// Create a directory to download and store the container
+    ///
+    /// The container root filesystem is prepared by the storage driver selected in the host configuration
     fn create_directory_structure(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("creating container directory structure...");
 
-        let container_path_str = utils::get_container_path(&self)?;
+        let container_path_str = paths::get_container_path(self)?;
         let container_path = Path::new(container_path_str.as_str());
         if !container_path.exists() {
             fs::create_dir_all(container_path.clone())?;
 
-            let subdirectories = vec!["upper", "work", "merged"];
-            for subdirectory in subdirectories {
-                let subdirectory_path = container_path.join(subdirectory);
-                fs::create_dir_all(subdirectory_path.clone())?;
-            }
-
             let container_lower_path = container_path.join("lower");
             unix::fs::symlink(
-                utils::get_image_path(&self.image.as_ref().unwrap()).unwrap(),
+                paths::get_image_path(self.image.as_ref().unwrap()).unwrap(),
                 container_lower_path
             )?;
+
+            let driver = storage::new_driver()?;
+            storage::save_driver(&self.path, driver.as_ref())?;
+            if let Err(e) = driver.prepare(self) {
+                fs::remove_dir_all(container_path)?;
+                return Err(e)
+            }
         }
 
         info!("created container directory structure.");
@@ -111,13 +156,355 @@ impl Container {
         self.create_directory_structure()?;
         self.generate_config_json()?;
 
+        let state = State::new(&self.id, &self.path, self.spec.annotations.clone());
+        state.save(&self.path)?;
+
+        info!("created container.");
+        Ok(())
+    }
+
+    /// Create a container object for an OCI bundle, configured by the bundle's config.json
+    pub fn from_bundle(container_id: &str, bundle: &str) -> Result<Container, Box<dyn std::error::Error>> {
+        paths::validate_container_id(container_id)?;
+        let mut container = Container::new(Some(container_id), None);
+
+        let bundle = format!("{}", fs::canonicalize(bundle)?.display());
+        let spec_path = format!("{}/config.json", bundle);
+        container.spec = Spec::load(spec_path.as_str())?;
+        container.state = State::new(container_id, bundle.as_str(), container.spec.annotations.clone());
+        container.state.status = Status::creating;
+
+        Ok(container)
+    }
+    /// Create a bundle container and its process, stopped before executing the user process until 'start'
+    ///
+    /// The terminal is sent to the console socket if the process requests one
+    pub fn create_bundle(&mut self, console_socket: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+        info!("creating container from bundle '{}'...", &self.state.bundle);
+
+        if Path::new(&self.path).exists() {
+            return Err(format!("container '{}' already exists", self.id).into())
+        }
+        let namespaces = &self.spec.linux.as_ref().ok_or("missing linux section")?.namespaces;
+        if !namespaces.iter().any(|ns| ns.typ == NamespaceType::mount) {
+            return Err("a mount namespace is required".into())
+        }
+        if self.spec.process.terminal && console_socket.is_none() {
+            return Err("--console-socket is required when the process has a terminal".into())
+        }
+        let console_socket = console_socket.filter(|_| self.spec.process.terminal);
+
+        fs::create_dir_all(&self.path)?;
+        self.state.save(&self.path)?;
+        let fifo_path = format!("{}/exec.fifo", &self.path);
+        mkfifo(fifo_path.as_str(), Mode::S_IRUSR | Mode::S_IWUSR)?;
+        // The container process opens it as the root of its user namespace
+        if let Some((uid_mappings, gid_mappings)) = self.id_mappings()? {
+            chown(
+                fifo_path.as_str(),
+                userns::host_id(uid_mappings, 0).map(Uid::from_raw),
+                userns::host_id(gid_mappings, 0).map(Gid::from_raw),
+            )?;
+        }
+
+        match self.create_process(console_socket) {
+            Ok(pid) => {
+                self.state.set_created(pid);
+                self.state.save(&self.path)?;
+            },
+            Err(e) => {
+                fs::remove_dir_all(&self.path)?;
+                return Err(e)
+            }
+        }
+
         info!("created container.");
         Ok(())
     }
+    /// Fork the container process of a bundle container and wait until it's ready
+    ///
+    /// Returns the pid of the container process, as seen from the host
+    fn create_process(&self, console_socket: Option<String>) -> Result<i32, Box<dyn std::error::Error>> {
+        let (init_read, init_write) = pipe()?;
+
+        match fork()? {
+            ForkResult::Child => {
+                close(init_read)?;
+                let init = Init {
+                    pipe: init_write,
+                    exec_fifo: true,
+                    console_socket,
+                    terminal: None,
+                    detach: true,
+                    stdio: None,
+                };
+                if let Err(e) = self.clean_run(false, None, &init) {
+                    error!("error creating container process: {}", e);
+                    exit(1);
+                }
+                exit(0);
+            }
+            ForkResult::Parent { child, .. } => {
+                close(init_write)?;
+
+                let mut init_pid = [0u8; 4];
+                let mut ready = [0u8; 1];
+                let reported = read(init_read, &mut init_pid)? == init_pid.len()
+                    && read(init_read, &mut ready)? == ready.len();
+                close(init_read)?;
+                waitpid(child, None)?;
+
+                if !reported {
+                    return Err("container process failed before being ready".into())
+                }
+                Ok(i32::from_ne_bytes(init_pid))
+            }
+        }
+    }
+    /// Start the process of a created bundle container, by reading from its exec fifo
+    pub fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        info!("starting container...");
+
+        self.state.check(&[Status::created], "start")?;
+        let fifo_path = format!("{}/exec.fifo", &self.path);
+        if self.state.pid == 0 || !Path::new(&fifo_path).exists() {
+            return Err(format!("container '{}' has no process waiting to be started", self.id).into())
+        }
+
+        let fifo = open(fifo_path.as_str(), OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+        let mut byte = [0u8; 1];
+        let started = read(fifo, &mut byte)? == byte.len();
+        close(fifo)?;
+        fs::remove_file(&fifo_path)?;
+        if !started {
+            return Err("container process exited before starting".into())
+        }
+
+        let pid = self.state.pid;
+        self.state.set_running(pid);
+        self.state.save(&self.path)?;
+
+        info!("started container.");
+        Ok(())
+    }
+    /// Send a signal to the container process
+    pub fn kill(&self, signal: Signal) -> Result<(), Box<dyn std::error::Error>> {
+        if self.state.pid == 0 {
+            return Err(format!("cannot kill container '{}': container is {:?}", self.id, self.state.status).into())
+        }
+
+        info!("sending {:?} to {}...", signal, self.state.pid);
+        kill(Pid::from_raw(self.state.pid), signal)?;
+        Ok(())
+    }
+    /// Freeze the processes of a running container, until it's unpaused
+    pub fn pause(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        info!("pausing container...");
+
+        self.state.check(&[Status::running], "pause")?;
+        // Rootless containers aren't given cgroups
+        if utils::is_rootless() {
+            return Err(format!("cannot pause container '{}': rootless containers have no cgroups", self.id).into())
+        }
+        cgroups::freeze(self, true)?;
+        self.state.set_paused(true);
+        self.state.save(&self.path)?;
+
+        info!("paused container.");
+        Ok(())
+    }
+    /// Thaw the processes of a paused container
+    pub fn unpause(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        info!("unpausing container...");
+
+        self.state.check(&[Status::paused], "unpause")?;
+        cgroups::freeze(self, false)?;
+        self.state.set_paused(false);
+        self.state.save(&self.path)?;
+
+        info!("unpaused container.");
+        Ok(())
+    }
+    /// Execute a command in the running container, with the process settings of the spec
+    ///
+    /// The command runs in a new process of the container namespaces and cgroups,
+    /// on a new terminal proxied to the standard streams if 'tty' is set.
+    /// Returns the exit code of the command
+    pub fn exec(&self, tty: bool, interactive: bool) -> Result<i32, Box<dyn std::error::Error>> {
+        info!("executing command in container...");
+
+        let pid = self.state.pid;
+        let namespaces = self.open_namespaces(pid)?;
+        let pty = match tty {
+            true  => {
+                let (master, slave) = terminal::open_pty()?;
+                terminal::copy_window_size(libc::STDIN_FILENO, master)?;
+                Some((master, slave))
+            },
+            false => None
+        };
+
+        match fork()? {
+            ForkResult::Child => {
+                let result = self.enter(pid, &namespaces, pty, interactive);
+                let code = match result {
+                    Ok(code) => code,
+                    Err(e)   => {
+                        error!("error executing command: {}", e);
+                        126
+                    }
+                };
+                exit(code);
+            }
+            ForkResult::Parent { child } => {
+                for (_, fd) in &namespaces {
+                    close(*fd)?;
+                }
+
+                if let Some((master, slave)) = pty {
+                    close(slave)?;
+                    let original = terminal::set_raw_mode(libc::STDIN_FILENO)?;
+                    let result = terminal::proxy(master, interactive);
+                    if let Some(original) = original {
+                        terminal::restore_mode(libc::STDIN_FILENO, &original)?;
+                    }
+                    close(master)?;
+                    result?;
+                }
+
+                Ok(exit_code(waitpid(child, None)?))
+            }
+        }
+    }
+    /// Open the namespaces of a container process that differ from the current ones
+    ///
+    /// The user namespace comes first, joining it grants the capabilities needed to join the others.
+    /// The mount namespace comes last, since it changes the root directory
+    fn open_namespaces(&self, pid: i32) -> Result<Vec<(&'static str, RawFd)>, Box<dyn std::error::Error>> {
+        let mut namespaces = Vec::new();
+        for namespace in &["user", "cgroup", "ipc", "uts", "net", "pid", "mnt"] {
+            let own_path = format!("/proc/self/ns/{}", namespace);
+            let ns_path = format!("/proc/{}/ns/{}", pid, namespace);
+            let own = match fs::read_link(&own_path) {
+                Ok(own) => own,
+                Err(_)  => {
+                    info!("{} namespace not supported. skipping...", namespace);
+                    continue
+                }
+            };
+            let ns = fs::read_link(&ns_path)
+                .map_err(|e| format!("cannot read {} namespace of process {}: {}", namespace, pid, e))?;
+            if ns == own {
+                continue
+            }
+
+            let fd = open(ns_path.as_str(), OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())
+                .map_err(|e| format!("cannot open {} namespace of process {}: {}", namespace, pid, e))?;
+            namespaces.push((*namespace, fd));
+        }
+        Ok(namespaces)
+    }
+    /// Join the container process cgroups and namespaces, then fork the command so it's in the pid namespace too
+    ///
+    /// Returns the exit code of the command
+    fn enter(&self, pid: i32, namespaces: &[(&str, RawFd)], pty: Option<(RawFd, RawFd)>, interactive: bool) -> Result<i32, Box<dyn std::error::Error>> {
+        if let Some((master, _)) = pty {
+            close(master)?;
+        }
+
+        // Rootless containers aren't given cgroups
+        if !utils::is_rootless() {
+            cgroups::join(pid)?;
+        }
+
+        // Opened from the host, the container might not have it
+        if pty.is_none() && !interactive {
+            let null = open("/dev/null", OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+            dup2(null, libc::STDIN_FILENO)?;
+            close(null)?;
+        }
+
+        info!("setting namespaces...");
+        for (namespace, fd) in namespaces {
+            setns(*fd, CloneFlags::empty())
+                .map_err(|e| format!("cannot join {} namespace: {}", namespace, e))?;
+            close(*fd)?;
+        }
+
+        match fork()? {
+            ForkResult::Child => {
+                if let Some((_, slave)) = pty {
+                    terminal::set_controlling_terminal(slave)?;
+                }
+
+                self.exec_process(&self.spec.process.args)?;
+                Ok(0)
+            }
+            ForkResult::Parent { child } => {
+                if let Some((_, slave)) = pty {
+                    close(slave)?;
+                }
+                Ok(exit_code(waitpid(child, None)?))
+            }
+        }
+    }
+    /// Path to the log file holding the output of detached runs
+    pub fn log_path(&self) -> String {
+        format!("{}/container.log", self.path)
+    }
+    /// Set the window size of the spec console on a new terminal, if one is given
+    fn set_console_size(&self, fd: RawFd) -> Result<(), Box<dyn std::error::Error>> {
+        let size = &self.spec.process.console_size;
+        if size.height > 0 && size.width > 0 {
+            terminal::set_window_size(fd, size.height as u16, size.width as u16)?;
+        }
+        Ok(())
+    }
+    /// Path to the socket clients attach to, while the container runs detached
+    pub fn attach_socket_path(&self) -> String {
+        format!("{}/attach.sock", self.path)
+    }
+    /// Check if the container was created from an OCI bundle, rather than from an image
+    pub fn is_bundle(&self) -> bool {
+        self.state.bundle != self.path
+    }
+    /// Get the path of the container root filesystem
+    pub fn rootfs_path(&self) -> String {
+        if self.is_bundle() {
+            format!("{}", Path::new(&self.state.bundle).join(&self.spec.root.path).display())
+        } else {
+            format!("{}/merged", &self.path)
+        }
+    }
+    /// Get the uid and gid maps of the container user namespace, if it has one
+    pub fn id_mappings(&self) -> Result<Option<IdMappings<'_>>, Box<dyn std::error::Error>> {
+        let linux = self.spec.linux.as_ref().unwrap();
+        if !linux.namespaces.iter().any(|ns| ns.typ == NamespaceType::user) {
+            return Ok(None)
+        }
+        if linux.uid_mappings.is_empty() || linux.gid_mappings.is_empty() {
+            return Err("the container user namespace needs 'uidMappings' and 'gidMappings'".into())
+        }
+        Ok(Some((&linux.uid_mappings, &linux.gid_mappings)))
+    }
+    /// Give the container user namespace its own id ranges, if its spec doesn't map any
+    pub fn allocate_id_mappings(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let linux = self.spec.linux.as_mut().unwrap();
+        if !linux.namespaces.iter().any(|ns| ns.typ == NamespaceType::user)
+            || !linux.uid_mappings.is_empty() || !linux.gid_mappings.is_empty() {
+            return Ok(())
+        }
+
+        let (uid_mappings, gid_mappings) = userns::allocate(&self.id)?;
+        linux.uid_mappings = uid_mappings;
+        linux.gid_mappings = gid_mappings;
+        Ok(())
+    }
 
     // TODO: Find a better way to find image
     /// Load a stored container
     pub fn load(container_name: &str) -> Result<Option<Container>, Box<dyn std::error::Error>> {
+        paths::validate_container_id(container_name)?;
         let mut container = Container::new(Some(container_name), None);
 
         let container_path = Path::new(&container.path);
@@ -125,19 +512,19 @@ impl Container {
             return Ok(None)
         }
 
+        if let Some(state) = State::load(&container.path)? {
+            if state.bundle != container.path {
+                let spec_path = format!("{}/config.json", state.bundle);
+                container.spec = Spec::load(spec_path.as_str())?;
+                container.state = state;
+                return Ok(Some(container))
+            }
+        }
+
         let container_lower_path = container_path.join("lower");
         let container_image_path = container_lower_path.read_link().unwrap();
 
-        // let home = match dirs::home_dir() {
-        //     Some(path) => path,
-        //     None       => return Err("error getting home directory".into())
-        // };
-        // let images_path = format!(
-        //     "{}/.minato/images/",
-        //     home.display()
-        // );
-        // debug!("{}", container_image_path.display());
-        let images_path = format!("/var/lib/minato/images/");
+        let images_path = paths::get_images_path()?;
 
         let image_id = container_image_path
             .strip_prefix(images_path)
@@ -153,48 +540,24 @@ impl Container {
         let spec_path = format!("{}/config.json", container.path);
         container.spec = Spec::load(spec_path.as_str())?;
 
+        // Containers created before states were stored haven't run since
+        container.state = match State::load(&container.path)? {
+            Some(state) => state,
+            None        => {
+                let state = State::new(&container.id, &container.path, container.spec.annotations.clone());
+                state.save(&container.path)?;
+                state
+            }
+        };
+
         Ok(Some(container))
     }
 
-    /// Mount the container layers in a single directory using overlayfs
+    /// Mount the container root filesystem, using the storage driver it was created with
     fn mount_container_filesystem(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("mounting container filesystem...");
 
-        let container_path = Path::new(&self.path);
-
-        let subdirectories = container_path.join("lower")
-            .read_dir().unwrap()
-            .map(|dir|
-                format!("{}", dir.unwrap().path().display()))
-            .collect::<Vec<String>>()
-            .join(":");
-        let lowerdir_arg  = format!("lowerdir={}",       subdirectories);
-        let upperdir_arg  = format!("upperdir={}/upper", &self.path);
-        let workdir_arg   = format!("workdir={}/work",   &self.path);
-        let mergeddir_arg = format!("{}/merged",         &self.path);
-        // let full_arg = format!("{},{},{},index=on",
-        let full_arg = format!("{},{},{}",
-            lowerdir_arg, upperdir_arg, workdir_arg
-        );
-        debug!("mount arguments: \n{}\n{}\n{}\n{}",
-            lowerdir_arg, upperdir_arg, workdir_arg, mergeddir_arg);
-
-        // let output = Command::new("./fuse-overlayfs/fuse-overlayfs")
-        //     .arg(full_arg)
-        //     .arg(mergeddir_arg)
-        //     .output()
-        //     .unwrap();
-
-        // info!("mount output: {}", output.status);
-        // io::stdout().write_all(&output.stdout).unwrap();
-        // io::stderr().write_all(&output.stderr).unwrap();
-        mount(
-            Some("overlay"),
-            mergeddir_arg.as_str(),
-            Some("overlay"),
-            MsFlags::empty(),
-            Some(full_arg.as_str())
-        )?;
+        storage::load_driver(&self.path)?.mount(self)?;
 
         info!("mounted container filesystem.");
         Ok(())
@@ -202,11 +565,12 @@ impl Container {
     /// Prepare the container root
     ///
     /// Executed steps:
-    ///   - unsharing the namespaces
+    ///   - joining the user namespace, if any
+    ///   - unsharing the other namespaces
     ///   - making the parent root private
     ///   - mounting the container root
     ///   - changing directory to container root
-    ///   - creating a 'tini' file and mount-binding it to the one in the .minato directoryg
+    ///   - creating a 'tini' file and mount-binding it to the one in the .minato directoryg, for image containers
     fn prepare_container_mountpoint(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("preparing container mountpoint...");
 
@@ -226,6 +590,14 @@ impl Container {
             }
         }
 
+        // Rootless, it was joined first
+        if clone_flags.contains(CloneFlags::CLONE_NEWUSER) {
+            if !utils::is_rootless() {
+                self.join_user_namespace()?;
+            }
+            clone_flags.remove(CloneFlags::CLONE_NEWUSER);
+        }
+
         info!("unsharing parent namespaces");
         unshare(clone_flags)?;
 
@@ -238,10 +610,7 @@ impl Container {
             None::<&str>,
         )?;
 
-        let rootfs_path_str = format!(
-            "{}/merged",
-            utils::get_container_path(&self)?
-        );
+        let rootfs_path_str = self.rootfs_path();
         let rootfs = rootfs_path_str.as_str();
         info!("mounting container root");
         mount(
@@ -255,16 +624,14 @@ impl Container {
         info!("changing directory to container root [{}]...", rootfs);
         chdir(rootfs)?;
 
+        // Bundle processes are executed directly, like other OCI runtimes do
+        if self.is_bundle() {
+            info!("prepared container mountpoint.");
+            return Ok(())
+        }
+
         // TODO: Move?
-        // let home = match dirs::home_dir() {
-        //     Some(path) => path,
-        //     None       => return Err("error getting home directory".into())
-        // };
-        // let tini_path = format!(
-        //     "{}/.minato/tini",
-        //     home.display()
-        // );
-        let tini_path = format!("/var/lib/minato/tini");
+        let tini_path = paths::get_tini_path()?;
         info!("binding init executable to container...");
         let tini_bin = "sbin/tini";
         if !Path::new(&tini_bin).exists() {
@@ -300,14 +667,11 @@ impl Container {
     }
     /// Create container root directories for future actions
     ///
-    /// Directories: put_old, dev, sys, proc, old_proc
+    /// Directories: put_old, old_proc
     fn prepare_container_directories(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("preparing container directories...");
 
-        let rootfs_path_str = format!(
-            "{}/merged",
-            utils::get_container_path(&self)?
-        );
+        let rootfs_path_str = self.rootfs_path();
         let rootfs = rootfs_path_str.as_str();
 
         utils::prepare_directory(
@@ -318,29 +682,6 @@ impl Container {
             Mode::S_IROTH |                 Mode::S_IXOTH
         )?;
 
-        utils::prepare_directory(
-            rootfs,
-            "dev",
-            Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IXUSR |
-            Mode::S_IRGRP |                 Mode::S_IXGRP |
-            Mode::S_IROTH |                 Mode::S_IXOTH
-        )?;
-
-        utils::prepare_directory(
-            rootfs,
-            "sys",
-            Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IXUSR |
-            Mode::S_IRGRP |                 Mode::S_IXGRP |
-            Mode::S_IROTH |                 Mode::S_IXOTH
-        )?;
-
-        utils::prepare_directory(
-            rootfs,
-            "proc",
-            Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IXUSR |
-            Mode::S_IRGRP |                 Mode::S_IXGRP |
-            Mode::S_IROTH |                 Mode::S_IXOTH
-        )?;
         utils::prepare_directory(
             rootfs,
             "old_proc",
@@ -387,117 +728,14 @@ impl Container {
         info!("prepared container networking.");
         Ok(())
     }
-    /// Mount all the neccessary cgroup directories in /sys/fs/cgroup
-    ///
-    /// The directories are populated automatically by the kernel
-    fn mount_container_cgroup_hierarchy(&self) -> Result<(), Box<dyn std::error::Error>> {
-        info!("mounting container cgroup hierarchy...");
-
-        let directories = vec![
-            // "unified", // cgroup2
-            // "systemd",
-            "freezer",
-            "hugetlb",
-            "memory",
-            "blkio",
-            "cpuset",
-            "cpu,cpuacct",
-            "devices",
-            "pids",
-            "net_cls,net_prio",
-            "perf_event",
-            "rdma"
-        ];
-
-        for dir in directories {
-            let dir_path = format!("sys/fs/cgroup/{}", dir);
-            if Path::new(&dir_path).exists() {
-                fs::remove_dir_all(&dir_path)?;
-            }
-            fs::create_dir_all(&dir_path)?;
-
-            info!("mounting cgroup {}...", dir);
-            let cgroup_version = "cgroup";
-            mount(
-                Some(cgroup_version),
-                dir_path.as_str(),
-                Some(cgroup_version),
-                MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
-                Some(dir),
-            )?;
-        }
-
-        info!("mounted container cgroup hierarchy.");
-        Ok(())
-    }
-    #[allow(dead_code)]
-    /// NOT WORKING: Probably because of loss of privilages
-    ///
-    /// Change the cgroup values according to the container's config.json file
-    fn configure_cgroups(&self) -> Result<(), Box<dyn std::error::Error>> {
-        info!("configuring cgroups...");
-
-        let cgroup_path = format!("sys/fs/cgroup");
-        let resources = match &self.spec.linux.as_ref().unwrap().resources {
-            None => {
-                info!("no resources found. skipping...");
-                return Ok(())
-            },
-            Some(res) => res
-        };
-
-        info!("configuring devices cgroup...");
-        let devices = &resources.devices;
-        // let devices = match resources.devices {
-        //     None => {
-        //         info!("no devices found. skipping...");
-        //         return Ok(())
-        //     },
-        //     Some(dev) => dev
-        // };
-        for device in devices {
-            debug!("{:?}", device);
-            let file = if device.allow {
-                "devices.allow"
-            } else {
-                "devices.deny"
-            };
-            // TODO: Necessary?
-            let typ = device.typ.as_str();
-            let major = match device.major {
-                Some(x) => x.to_string(),
-                None    => "*".to_string()
-            };
-            let minor = match device.minor {
-                Some(x) => x.to_string(),
-                None    => "*".to_string()
-            };
-            let acc = device.access.as_str();
-            let data = format!{"{} {}:{} {}", typ, &major, &minor, acc};
-
-            let devices_path = format!("{}/devices/{}", cgroup_path, file);
-            info!{"writing {} to {}", data, devices_path};
-            if let Err(e) = fs::write(devices_path, data.as_bytes()) {
-                error!("{}", e);
-            }
-        }
-
-        info!("configured cgroups.");
-        Ok(())
-    }
-    /// Execute various mount operations
+    /// Mount the container filesystems, as listed in the spec mounts
     ///
     /// Mounts:
-    ///   - proc fs to old_proc
-    ///   - parent /dev to /dev
-    ///
-    /// Other options:
-    ///   - /sys to /sys
-    ///   - not mounting dev to the parent and populating /dev manually
+    ///   - proc fs to old_proc, so a new proc can be mounted in a user namespace
+    ///   - the spec mounts in order, but proc ones, which need the container pid namespace
     fn mount_container_directories(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("mounting container directories...");
 
-
         info!("mounting proc to old_proc...");
         mount(
             Some("/proc"),
@@ -507,134 +745,25 @@ impl Container {
             None::<&str>,
         )?;
 
-        self.mount_container_cgroup_hierarchy()?;
-        debug!("TODO: cgroups configuring");
-        // self.configure_cgroups()?;
-
-        // info!("mounting sys...");
-        // mount(
-        //     Some("/sys"),
-        //     "sys",
-        //     None::<&str>,
-        //     MsFlags::MS_BIND | MsFlags::MS_REC,
-        //     None::<&str>,
-        // )?;
-
-        // Slashes?
-        info!("mounting dev to dev...");
-        mount(
-            Some("/dev"),
-            "dev",
-            None::<&str>,
-            MsFlags::MS_BIND | MsFlags::MS_REC,
-            None::<&str>,
-        )?;
-
-        // info!("populating /dev...");
-        // if !Path::new("dev").exists() {
-        //     fs::create_dir("dev")?;
-        // }
-        // let tty_path_str = format!("/dev/tty");
-        // let perms =
-        //     Mode::S_IRUSR | Mode::S_IWUSR |
-        //     Mode::S_IRGRP | Mode::S_IWGRP |
-        //     Mode::S_IROTH | Mode::S_IWOTH;
-        // if Path::new(tty_path_str.clone().as_str()).exists() {
-        //     info!("removing /dev/tty...");
-        //     fs::remove_file(tty_path_str.clone())?;
-        // }
-        // let dev = makedev(5, 0);
-        // info!("dev: {}", dev);
-        // info!("mknod");
-        // mknod(
-        //     tty_path_str.clone().as_str(),
-        //     SFlag::S_IFCHR,
-        //     perms,
-        //     dev
-        // )?;
-        // info!("chown");
-        // chown(
-        //     tty_path_str.as_str(),
-        //     Some(Uid::from_raw(0)),
-        //     Some(Gid::from_raw(0))
-        // )?;
-
-        // BUG: Init doesn't work
-        // if self.spec.process.args[0] == "init" {
-            // info!("creating ttys");
-            // for i in 0..7 {
-            //     info!("creating tty{}...", i);
-
-            //     let tty_path_str = format!("/dev/tty{}", i);
-            //     let perms =
-            //         Mode::S_IRUSR | Mode::S_IWUSR |
-            //         Mode::S_IRGRP | Mode::S_IWGRP |
-            //         Mode::S_IROTH | Mode::S_IWOTH;
-            //     if Path::new(tty_path_str.clone().as_str()).exists() {
-            //         info!("removing /dev/tty{}...", i);
-            //         fs::remove_file(tty_path_str.clone())?;
-            //     }
-            //     let dev = makedev(4, i);
-            //     info!("dev: {}", dev);
-            //     info!("mknod");
-            //     mknod(
-            //         tty_path_str.clone().as_str(),
-            //         SFlag::S_IFCHR,
-            //         perms,
-            //         dev
-            //     )?;
-            //     info!("chown");
-            //     chown(
-            //         tty_path_str.as_str(),
-            //         Some(Uid::from_raw(0)),
-            //         Some(Gid::from_raw(0))
-            //     )?;
-        // }
+        let rootfs = self.rootfs_path();
+        for entry in self.spec.mounts.iter().filter(|entry| entry.typ != "proc") {
+            mounts::mount_entry(entry, &rootfs)?;
+        }
 
         info!("mounted container directories.");
         Ok(())
     }
-    /// Write the container maps
+    /// Join a new user namespace with the container id maps, as its root
     ///
-    /// 'uid_map' and 'gid_map' are a 1-1 mapping, root(0) to the configured id
-    ///
-    /// Setting 'setgroups' to 'deny' is required to get the user namespace to work
-    fn prepare_container_id_maps(&self) -> Result<(), Box<dyn std::error::Error>> {
-        info!("preparing container id maps...");
+    /// The namespace is created by a helper process, so its maps can be written from outside of it,
+    /// and the namespaces unshared later are owned by it
+    fn join_user_namespace(&self) -> Result<(), Box<dyn std::error::Error>> {
+        info!("joining container user namespace...");
 
-        debug!("uid: {} - euid: {}", Uid::current(), Uid::effective());
-        debug!("gid: {} - egid: {}", Gid::current(), Gid::effective());
+        let (uid_mappings, gid_mappings) = self.id_mappings()?.ok_or("container has no user namespace")?;
+        userns::join(uid_mappings, gid_mappings)?;
 
-        // let uid = unistd::getuid();
-        let uid = 0;
-        let newuid = self.spec.process.user.uid;
-        let buf = format!("{} {} 1\n", newuid, uid);
-        let fd = open("/proc/self/uid_map", OFlag::O_WRONLY, Mode::empty())?;
-        info!("writing 'uid_map'");
-        write(fd, buf.as_bytes())?;
-        close(fd)?;
-
-        let fd = open("/proc/self/setgroups", OFlag::O_WRONLY, Mode::empty())?;
-        info!("writing 'deny' to setgroups");
-        write(fd, "deny".as_bytes())?;
-        close(fd)?;
-
-        // let gid = unistd::getgid();
-        let gid = 0;
-        let newgid = self.spec.process.user.gid;
-        let buf = format!("{} {} 1\n", newgid, gid);
-        let fd = open("/proc/self/gid_map", OFlag::O_WRONLY, Mode::empty())?;
-        info!("writing 'gid_map'");
-        write(fd, buf.as_bytes())?;
-        close(fd)?;
-
-        // info!("setting groups...");
-        // let gids: Vec<Gid> = self.spec.process.user.additional_gids.iter()
-        //     .map(|gid| Gid::from_raw(*gid as u32))
-        //     .collect();
-        // setgroups(gids.as_slice())?;
-
-        info!("prepared container id maps.");
+        info!("joined container user namespace.");
         Ok(())
     }
     #[allow(dead_code)]
@@ -685,21 +814,52 @@ impl Container {
     /// Execute the inner fork, before executing the initial command
     ///
     /// Probably to enter all the namespaces after unsharing them
-    fn execute_inner_fork(&self, daemon: bool) -> Result<(), Box<dyn std::error::Error>> {
+    ///
+    /// The parent reports the child pid, as seen from the host, through the init pipe,
+    /// then relays the child's ready byte, and exits with the child exit code unless it's detached.
+    /// Rootless, it unmounts the container filesystem from the mount namespace it was mounted in, before exiting
+    fn execute_inner_fork(&self, daemon: bool, init: &Init, exec_fifo: Option<RawFd>, console: Option<RawFd>, storage_ns: Option<RawFd>) -> Result<(), Box<dyn std::error::Error>> {
         info!("executing inner fork...");
 
+        let (ready_read, ready_write) = pipe()?;
         match fork() {
             Ok(ForkResult::Child) => {
                 info!("running child process...");
+                close(init.pipe)?;
+                close(ready_read)?;
+                if let Some(storage_ns) = storage_ns {
+                    close(storage_ns)?;
+                }
 
                 self.remount_container_directories()?;
 
                 sethostname(self.spec.hostname.as_str())?;
 
+                if let Some(console) = console {
+                    info!("setting container terminal...");
+                    terminal::set_controlling_terminal(console)?;
+                }
+
                 // if let Err(e) = self.prepare_container_ids() {
                 //     info!("failed: {}", e);
                 // }
 
+                write(ready_write, &[1])?;
+                close(ready_write)?;
+
+                if let Some(exec_fifo) = exec_fifo {
+                    self.wait_for_start(exec_fifo)?;
+                }
+
+                if let Some((stdin, stdout, stderr)) = init.stdio {
+                    dup2(stdin, libc::STDIN_FILENO)?;
+                    dup2(stdout, libc::STDOUT_FILENO)?;
+                    dup2(stderr, libc::STDERR_FILENO)?;
+                    for fd in &[stdin, stdout, stderr] {
+                        close(*fd)?;
+                    }
+                }
+
                 self.do_exec()?;
 
                 // Should not reach
@@ -710,11 +870,39 @@ impl Container {
                 info!("running parent process...");
 
                 info!("inner fork child pid: {}", child);
+                close(ready_write)?;
+                for fd in exec_fifo.iter().chain(console.iter()) {
+                    close(*fd)?;
+                }
+                if let Some((stdin, stdout, stderr)) = init.stdio {
+                    close(stdin)?;
+                    close(stdout)?;
+                    close(stderr)?;
+                }
+
+                write(init.pipe, &child.as_raw().to_ne_bytes())?;
+                let mut ready = [0u8; 1];
+                if read(ready_read, &mut ready)? == ready.len() {
+                    write(init.pipe, &ready)?;
+                }
+                close(ready_read)?;
+                close(init.pipe)?;
+
+                if init.detach {
+                    exit(0);
+                }
 
                 // if !daemon {
                     info!("waiting for child...");
-                    waitpid(child, None)?;
-                    exit(0);
+                    let code = exit_code(waitpid(child, None)?);
+                    if let Some(storage_ns) = storage_ns {
+                        if let Err(e) = setns(storage_ns, CloneFlags::CLONE_NEWNS) {
+                            error!("cannot join the container storage mount namespace: {}", e);
+                        } else if let Err(e) = storage::load_driver(&self.path).and_then(|driver| driver.unmount(self)) {
+                            error!("cannot unmount the container filesystem: {}", e);
+                        }
+                    }
+                    exit(code);
                 // }
             }
             Err(e) => error!("inner fork error: {}", e)
@@ -723,22 +911,32 @@ impl Container {
         info!("executed inner fork.");
         Ok(())
     }
+    /// Block until the container is started, by opening the exec fifo for writing
+    ///
+    /// The fifo was opened as a path before pivoting, it's reopened through /proc
+    fn wait_for_start(&self, exec_fifo: RawFd) -> Result<(), Box<dyn std::error::Error>> {
+        info!("waiting for container start...");
+
+        let fifo_path = format!("/proc/self/fd/{}", exec_fifo);
+        let fifo = open(fifo_path.as_str(), OFlag::O_WRONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+        close(exec_fifo)?;
+        write(fifo, &[0])?;
+        close(fifo)?;
+
+        Ok(())
+    }
     /// Remount directories, as a child process
     ///
     /// Remounts:
-    ///   - proc fs to 'proc', from 'old_proc'; then removes 'old_proc'
-    ///   - the root directory
+    ///   - the proc mounts of the spec, now in the container pid namespace; then removes 'old_proc'
+    ///   - the masked paths, hidden, and the read-only paths of the spec
+    ///   - the root directory, read-only if the spec root is
     fn remount_container_directories(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("remounting container directories...");
 
-        info!("remounting proc...");
-        mount(
-            Some("/proc"),
-            "/proc",
-            Some("proc"),
-            MsFlags::MS_NOSUID,
-            None::<&str>,
-        )?;
+        for entry in self.spec.mounts.iter().filter(|entry| entry.typ == "proc") {
+            mounts::mount_entry(entry, "/")?;
+        }
 
         info!("unmounting old proc folder...");
         umount2("/old_proc", MntFlags::MNT_DETACH)?;
@@ -748,13 +946,27 @@ impl Container {
             std::fs::remove_dir_all("/old_proc")?;
         }
 
+        let linux = self.spec.linux.as_ref().unwrap();
+        for path in &linux.masked_paths {
+            info!("masking {}...", path);
+            mounts::mask_path(path)?;
+        }
+        for path in &linux.readonly_paths {
+            info!("making {} read-only...", path);
+            mounts::make_readonly(path)?;
+        }
+
         // BUG: Unsure if it's needed. Probably for userns
         info!("remounting container root...");
+        let mut root_flags = MsFlags::MS_BIND | MsFlags::MS_NOSUID | MsFlags::MS_REMOUNT;
+        if self.spec.root.readonly {
+            root_flags |= MsFlags::MS_RDONLY;
+        }
         mount(
             Some("/"),
             "/",
             None::<&str>,
-            MsFlags::MS_BIND | MsFlags::MS_NOSUID | MsFlags::MS_REMOUNT,
+            root_flags,
             None::<&str>,
         )?;
 
@@ -763,7 +975,8 @@ impl Container {
     }
     /// Execute the initial command, usually '/bin/sh'.
     ///
-    /// Actually also runs an init process (tini) before, for more process management and initial command in daemon
+    /// Actually also runs an init process (tini) before, for more process management and initial command in daemon.
+    /// Bundle containers execute their process directly
     ///
     /// Daemon option not working yet
     fn do_exec(&self) -> Result<(), Box<dyn std::error::Error>> {
@@ -790,12 +1003,27 @@ impl Container {
         // let envs = &process.env;
 
         let process = &self.spec.process;
-        tini.append(&mut process.args.clone());
-        let path = "tini";
-        let args = tini;
+        let args = if self.is_bundle() {
+            process.args.clone()
+        } else {
+            tini.append(&mut process.args.clone());
+            tini
+        };
+
+        self.exec_process(&args)
+    }
+    /// Replace the current process with the given command, in the environment of the container process
+    ///
+    /// The resource limits, OOM score adjustment, capabilities, no_new_privileges and seccomp profile of the spec are applied
+    fn exec_process(&self, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        let process = &self.spec.process;
+        let path = match args.first() {
+            Some(path) => path.clone(),
+            None       => return Err("no process arguments".into())
+        };
         let envs = &process.env;
 
-        let p: CString = CString::new(path).unwrap();
+        let p: CString = CString::new(path.as_str()).unwrap();
         let a: Vec<CString> = args.iter()
             .map(|s| CString::new(s.to_string()).unwrap_or_default())
             .collect();
@@ -805,28 +1033,139 @@ impl Container {
 
         info!("setting environment variables...");
         for env in envs {
-            let e: Vec<&str> = env.split("=").collect();
-            let variable = e[0];
-            let value = e[1];
+            let mut e = env.splitn(2, '=');
+            let variable = e.next().unwrap_or_default();
+            let value = e.next().unwrap_or_default();
 
             env::remove_var(variable);
             env::set_var(variable, value);
         }
 
+        if !process.cwd.is_empty() {
+            info!("changing working directory...");
+            chdir(process.cwd.as_str())
+                .map_err(|e| format!("cannot change to working directory '{}': {}", process.cwd, e))?;
+        }
+
+        // Raising limits needs CAP_SYS_RESOURCE, they're set before the capabilities are dropped
+        self.set_rlimits()?;
+        if let Some(oom_score_adj) = process.oom_score_adj {
+            info!("setting OOM score adjustment...");
+            fs::write("/proc/self/oom_score_adj", oom_score_adj.to_string())
+                .map_err(|e| format!("cannot set OOM score adjustment to {}: {}", oom_score_adj, e))?;
+        }
+
+        // Without no_new_privileges, the filter is installed while the process still has CAP_SYS_ADMIN
+        let seccomp = self.seccomp_profile();
+        if let (Some(seccomp), false) = (&seccomp, process.no_new_privileges) {
+            seccomp::install(seccomp)?;
+        }
+
+        let capabilities = process.capabilities.as_ref();
+        if let Some(capabilities) = capabilities {
+            capabilities::drop_bounding_set(capabilities)?;
+            if let Err(e) = prctl::set_keep_capabilities(true) {
+                return Err(format!("cannot keep capabilities: {}", e).into())
+            }
+        }
+        self.set_process_ids()?;
+        if let Some(capabilities) = capabilities {
+            capabilities::set_capabilities(capabilities)?;
+        }
+        if process.no_new_privileges {
+            if let Err(e) = prctl::set_no_new_privileges(true) {
+                return Err(format!("cannot set no_new_privileges: {}", e).into())
+            }
+            if let Some(seccomp) = &seccomp {
+                seccomp::install(seccomp)?;
+            }
+        }
+
         info!("executing command...");
         info!("arguments: \n{:?}\n{:?}\n{:?}",
             p, a, e);
-        execvpe(&p, &a, &e)?;
+        execvpe(&p, &a, &e)
+            .map_err(|e| format!("cannot execute '{}': {}", path, e))?;
 
         Ok(())
     }
-    /// Unmount the overlay filesystem
+    /// Set the resource limits of the spec on the current process
+    fn set_rlimits(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for rlimit in &self.spec.process.rlimits {
+            info!("setting {:?} to {}:{}...", rlimit.typ, rlimit.soft, rlimit.hard);
+            let resource = match rlimit.typ {
+                RlimitType::RLIMIT_AS         => libc::RLIMIT_AS,
+                RlimitType::RLIMIT_CORE       => libc::RLIMIT_CORE,
+                RlimitType::RLIMIT_CPU        => libc::RLIMIT_CPU,
+                RlimitType::RLIMIT_DATA       => libc::RLIMIT_DATA,
+                RlimitType::RLIMIT_FSIZE      => libc::RLIMIT_FSIZE,
+                RlimitType::RLIMIT_LOCKS      => libc::RLIMIT_LOCKS,
+                RlimitType::RLIMIT_MEMLOCK    => libc::RLIMIT_MEMLOCK,
+                RlimitType::RLIMIT_MSGQUEUE   => libc::RLIMIT_MSGQUEUE,
+                RlimitType::RLIMIT_NICE       => libc::RLIMIT_NICE,
+                RlimitType::RLIMIT_NOFILE     => libc::RLIMIT_NOFILE,
+                RlimitType::RLIMIT_NPROC      => libc::RLIMIT_NPROC,
+                RlimitType::RLIMIT_RSS        => libc::RLIMIT_RSS,
+                RlimitType::RLIMIT_RTPRIO     => libc::RLIMIT_RTPRIO,
+                RlimitType::RLIMIT_RTTIME     => libc::RLIMIT_RTTIME,
+                RlimitType::RLIMIT_SIGPENDING => libc::RLIMIT_SIGPENDING,
+                RlimitType::RLIMIT_STACK      => libc::RLIMIT_STACK,
+            };
+            let limit = libc::rlimit {
+                rlim_cur: rlimit.soft,
+                rlim_max: rlimit.hard,
+            };
+            if unsafe { libc::setrlimit(resource, &limit) } < 0 {
+                return Err(format!("cannot set {:?} to {}:{}: {}",
+                    rlimit.typ, rlimit.soft, rlimit.hard, std::io::Error::last_os_error()).into())
+            }
+        }
+        Ok(())
+    }
+    /// Seccomp profile of the container processes
+    ///
+    /// Containers created from an image get the default profile when their spec has none, bundles aren't filtered
+    fn seccomp_profile(&self) -> Option<Seccomp> {
+        let seccomp = self.spec.linux.as_ref().and_then(|linux| linux.seccomp.clone());
+        if seccomp.is_some() || self.is_bundle() {
+            return seccomp
+        }
+
+        let bounding = self.spec.process.capabilities.as_ref()
+            .map(|capabilities| capabilities.bounding.as_slice())
+            .unwrap_or_default();
+        Some(seccomp::default_profile(bounding))
+    }
+    /// Switch to the configured user and groups
+    fn set_process_ids(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let user = &self.spec.process.user;
+        let uid = Uid::from_raw(user.uid as u32);
+        let gid = Gid::from_raw(user.gid as u32);
+        info!("setting ids to {}:{}...", uid, gid);
+
+        let gids: Vec<Gid> = user.additional_gids.iter()
+            .map(|gid| Gid::from_raw(*gid as u32))
+            .collect();
+        match setgroups(gids.as_slice()) {
+            // Denied in user namespaces that only map the user's own ids
+            Err(nix::Error::Sys(Errno::EPERM)) if gids.is_empty() => {},
+            result => result.map_err(|e| format!("cannot set additional groups: {}", e))?
+        }
+        setgid(gid)?;
+        setuid(uid)?;
+
+        Ok(())
+    }
+    /// Unmount the container root filesystem
+    ///
+    /// Rootless, it's mounted in a mount namespace of the container, where the container process unmounts it
     fn unmount_container_filesystem(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if utils::is_rootless() {
+            return Ok(())
+        }
         info!("unmounting container filesystem...");
-        let merged = format!("{}/merged", &self.path);
 
-        info!("unmounting '{}'...", merged);
-        umount(merged.as_str())?;
+        storage::load_driver(&self.path)?.unmount(self)?;
 
         info!("unmounted container filesystem.");
         Ok(())
@@ -834,8 +1173,9 @@ impl Container {
     /// Cleanup after running the container
     ///
     /// Executed steps:
-    ///   - unmount the overlay filesystem
-    ///   - remove the pid file from the container directory
+    ///   - unmount the container root filesystem
+    ///   - remove the container cgroup
+    ///   - remove the container networking, unless rootless
     fn cleanup(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("cleaning up container...");
 
@@ -843,10 +1183,12 @@ impl Container {
         if let Err(e) = self.unmount_container_filesystem() {
             info!("unmounting error: {}", e);
         };
+        if let Err(e) = cgroups::remove(self) {
+            error!("{}", e);
+        }
 
         // TODO: Move code where it belongs(???)
-        if true {
-        // if false {
+        if !utils::is_rootless() {
             networking::delete_container_from_network(&self.id)?;
             networking::remove_veth_from_bridge(&self.id)?;
             networking::delete_veth(&self.id)?;
@@ -854,12 +1196,6 @@ impl Container {
             networking::delete_network_namespace(&self.id)?;
         }
 
-        let pid_path = format!("{}/pid", self.path);
-        if Path::new(&pid_path).exists() {
-            info!("removing pid file...");
-            fs::remove_file(&pid_path)?;
-        }
-
         info!("cleaned up (after) container.");
         Ok(())
     }
@@ -886,24 +1222,60 @@ impl Container {
     /// Separate run function necessary to execute a fork and cleanup
     ///
     /// Executed steps:
-    ///   - mount the container filesystem
+    ///   - open the terminal and send it to the console socket, if requested
+    ///   - create the container cgroup and move into it, unless rootless
+    ///   - join the user namespace and a mount namespace, if rootless
+    ///   - mount the container filesystem, for image containers
     ///   - prepare the container root mountpoint
     ///   - prepare the container root directories
     ///   - prepare the container root directories
     ///   - prepare the container for networking
     ///   - mount container directories
-    ///   - write the container id maps
+    ///   - open the exec fifo, if requested
+    ///   - create the devices
     ///   - pivot root
     ///   - execute inner fork
-    ///     - remount proc and root
-    ///     - set hostname
+    ///     - mount proc and remount root
+    ///     - set hostname and terminal
+    ///     - wait for start, if requested
     ///     - execute initial command
-    fn clean_run(&self, daemon: bool, volume: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    fn clean_run(&self, daemon: bool, volume: Option<String>, init: &Init) -> Result<(), Box<dyn std::error::Error>> {
 
         debug!("uid: {} - euid: {}", Uid::current(), Uid::effective());
         debug!("gid: {} - egid: {}", Gid::current(), Gid::effective());
 
-        self.mount_container_filesystem()?;
+        // The console socket belongs to the host users
+        let console = match &init.console_socket {
+            Some(console_socket) => {
+                let (master, slave) = terminal::open_pty()?;
+                self.set_console_size(master)?;
+                terminal::send_fd(console_socket, master)?;
+                close(master)?;
+                Some(slave)
+            },
+            None => init.terminal
+        };
+
+        // Rootless containers aren't given cgroups
+        if !utils::is_rootless() {
+            cgroups::create(self)?;
+        }
+
+        // Rootless, nothing can be mounted outside of the user namespace
+        let storage_ns = if utils::is_rootless() {
+            if self.id_mappings()?.is_none() {
+                return Err("rootless containers need a user namespace".into())
+            }
+            self.join_user_namespace()?;
+            unshare(CloneFlags::CLONE_NEWNS)?;
+            Some(open("/proc/self/ns/mnt", OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())?)
+        } else {
+            None
+        };
+
+        if !self.is_bundle() {
+            self.mount_container_filesystem()?;
+        }
 
         self.prepare_container_mountpoint()?;
 
@@ -915,93 +1287,270 @@ impl Container {
 
         self.mount_container_directories()?;
 
-        self.prepare_container_id_maps()?;
+        let exec_fifo = if init.exec_fifo {
+            let fifo_path = format!("{}/exec.fifo", &self.path);
+            Some(open(fifo_path.as_str(), OFlag::O_PATH | OFlag::O_CLOEXEC, Mode::empty())?)
+        } else {
+            None
+        };
+
+        let devices = &self.spec.linux.as_ref().unwrap().devices;
+        devices::create_devices(&self.rootfs_path(), devices, console)?;
 
         self.pivot_container_root()?;
 
-        self.execute_inner_fork(daemon)?;
+        self.execute_inner_fork(daemon, init, exec_fifo, console, storage_ns)?;
 
 
         Ok(())
     }
     /// Run a stored container
     ///
+    /// If detached, the container runs in the background and its output goes to the container log
+    pub fn run(&self, daemon: bool, volume: Option<String>, host_ip: Option<String>, container_ip: Option<String>, detach: bool) -> Result<(), Box<dyn std::error::Error>> {
+        info!("running container...");
+
+        if self.is_bundle() {
+            return Err("containers created from a bundle are started with 'minato start'".into())
+        }
+        if utils::is_rootless() && (host_ip.is_some() || container_ip.is_some()) {
+            return Err("container networking needs root".into())
+        }
+
+        if detach {
+            return self.run_detached(daemon, volume, host_ip, container_ip)
+        }
+        self.supervise(daemon, volume, host_ip, container_ip, None)
+    }
+    /// Run the container under a supervisor process, which writes the container output to the container log
+    ///
+    /// Returns once the container process is running
+    fn run_detached(&self, daemon: bool, volume: Option<String>, host_ip: Option<String>, container_ip: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+        info!("starting supervisor...");
+
+        let (ready_read, ready_write) = pipe()?;
+        match fork()? {
+            ForkResult::Child => {
+                close(ready_read)?;
+                setsid()?;
+                // Forking again reparents the supervisor to init, so the caller doesn't have to reap it
+                if let ForkResult::Parent { .. } = fork()? {
+                    exit(0);
+                }
+
+                let null = open("/dev/null", OFlag::O_RDWR, Mode::empty())?;
+                for fd in 0..3 {
+                    dup2(null, fd)?;
+                }
+                close(null)?;
+
+                if let Err(e) = self.supervise(daemon, volume, host_ip, container_ip, Some(ready_write)) {
+                    let _ = logs::append(&self.log_path(), "stderr", &format!("minato: {}\n", e));
+                    exit(1);
+                }
+                exit(0);
+            }
+            ForkResult::Parent { child } => {
+                close(ready_write)?;
+                waitpid(child, None)?;
+
+                let mut pid = [0u8; 4];
+                let size = read(ready_read, &mut pid)?;
+                close(ready_read)?;
+                if size != pid.len() {
+                    return Err(format!("container failed to start, see 'minato container logs -c {}'", self.id).into())
+                }
+
+                info!("container pid: {}", i32::from_ne_bytes(pid));
+                Ok(())
+            }
+        }
+    }
+    /// Run the container and wait for it to exit
+    ///
     /// Executes a fork before all the steps so it works with a daemon
     ///
-    /// The parent creates a pid file that is used to check the container's state
-    pub fn run(&self, daemon: bool, volume: Option<String>, host_ip: Option<String>, container_ip: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
-        info!("running container...");
+    /// The parent records the container process in the container state, and its exit code once it's done.
+    /// With a 'ready' pipe, the container output is captured to the container log, clients can attach to the container
+    /// through its attach socket, and the container pid is written to the pipe once it's running
+    fn supervise(&self, daemon: bool, volume: Option<String>, host_ip: Option<String>, container_ip: Option<String>, ready: Option<RawFd>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut state = self.state.clone();
+        let (init_read, init_write) = pipe()?;
+        let pty = match self.spec.process.terminal {
+            true  => {
+                let (master, slave) = terminal::open_pty()?;
+                self.set_console_size(master)?;
+                if ready.is_none() {
+                    terminal::copy_window_size(libc::STDIN_FILENO, master)?;
+                }
+                Some((master, slave))
+            },
+            false => None
+        };
+        let stdio = match (ready, pty) {
+            (Some(_), None) => Some((pipe()?, pipe()?, pipe()?)),
+            _               => None
+        };
+        let clients = attach::Clients::default();
 
         info!("executing outer fork...");
         let result = match fork() {
             Ok(ForkResult::Child) => {
-                self.clean_run(daemon, volume)?;
+                close(init_read)?;
+                if let Some(ready) = ready {
+                    close(ready)?;
+                }
+                if let Some(((_, stdin_write), (stdout_read, _), (stderr_read, _))) = stdio {
+                    close(stdin_write)?;
+                    close(stdout_read)?;
+                    close(stderr_read)?;
+                }
+                if let Some((master, _)) = pty {
+                    close(master)?;
+                }
+                let init = Init {
+                    pipe: init_write,
+                    exec_fifo: false,
+                    console_socket: None,
+                    terminal: pty.map(|(_, slave)| slave),
+                    detach: false,
+                    stdio: stdio.map(|((stdin_read, _), (_, stdout_write), (_, stderr_write))| (stdin_read, stdout_write, stderr_write)),
+                };
+                self.clean_run(daemon, volume, &init)?;
 
                 return Ok(());
             }
             Ok(ForkResult::Parent { child, .. }) => {
                 info!("outer fork child pid: {}", child);
 
-                // TODO: Add iproute2 check
-                if host_ip.is_some() && container_ip.is_some() {
-                // if false {
-                    // networking::create_network_namespace(&container.id)?;
-                    networking::create_bridge(&self.id, &host_ip.unwrap())?;
-                    networking::create_veth(&self.id)?;
-                    networking::add_veth_to_bridge(&self.id)?;
-                    networking::add_container_to_network(&self.id, child, &container_ip.unwrap())?;
+                // Errors don't return before the child is waited for, so the container is always cleaned up and stopped
+                let mut loggers = Vec::new();
+                let mut ready_pipe = ready;
+                let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+                    close(init_write)?;
+
+                    if let Some(((stdin_read, stdin_write), (stdout_read, stdout_write), (stderr_read, stderr_write))) = stdio {
+                        close(stdin_read)?;
+                        close(stdout_write)?;
+                        close(stderr_write)?;
+                        loggers.push(logs::capture(stdout_read, "stdout", &self.log_path(), clients.clone()));
+                        loggers.push(logs::capture(stderr_read, "stderr", &self.log_path(), clients.clone()));
+                        attach::serve(&self.attach_socket_path(), clients.clone(), stdin_write, false)?;
+                    }
+                    if let Some((master, slave)) = pty {
+                        close(slave)?;
+                        if ready.is_some() {
+                            // The logger closes the master once the terminal is closed, the attach clients write to a copy
+                            attach::serve(&self.attach_socket_path(), clients.clone(), dup(master)?, true)?;
+                            loggers.push(logs::capture(master, "stdout", &self.log_path(), clients.clone()));
+                        }
+                    }
+
+                    // TODO: Add iproute2 check
+                    if let (Some(host_ip), Some(container_ip)) = (&host_ip, &container_ip) {
+                    // if false {
+                        // networking::create_network_namespace(&container.id)?;
+                        networking::create_bridge(&self.id, host_ip)?;
+                        networking::create_veth(&self.id)?;
+                        networking::add_veth_to_bridge(&self.id)?;
+                        networking::add_container_to_network(&self.id, child, container_ip)?;
+                    }
+
+                    let mut init_pid = [0u8; 4];
+                    if read(init_read, &mut init_pid)? == init_pid.len() {
+                        info!("updating container state...");
+                        state.set_running(i32::from_ne_bytes(init_pid));
+                        state.save(&self.path)?;
+
+                        if let Some(ready) = ready_pipe.take() {
+                            write(ready, &init_pid)?;
+                            close(ready)?;
+                        }
+                    }
+                    // The ready byte is read too, closing the pipe before would fail the container process
+                    let mut ready_byte = [0u8; 1];
+                    read(init_read, &mut ready_byte)?;
+                    // Path::new("/proc/self/ns")
+                    //     .read_dir().unwrap()
+                    //     .for_each(|dir| {
+                    //         let ns_path = format!("{}",
+                    //             dir.unwrap()
+                    //             .path()
+                    //             .display());
+                    //         let link = format!("{}", fs::read_link(&ns_path).unwrap().display());
+                    //         let ns_dir_path = format!("{}/{}",
+                    //             &self.path,
+                    //             Path::new(&ns_path)
+                    //                 .strip_prefix(Path::new("/proc/self/ns/")).unwrap()
+                    //                 .display()
+                    //             );
+
+                    //         info!("{} -> {}", ns_dir_path, link);
+                    //         // if let Err(e) = fs::copy(ns_path, ns_dir_path) {
+                    //         //     info!("{}", e);
+                    //         // };
+                    //         if let Err(e) = fs::soft_link(ns_dir_path, link) {
+                    //             info!("{}", e);
+                    //         };
+                    //     });
+
+                    if let (Some((master, _)), None) = (pty, ready) {
+                        let original = terminal::set_raw_mode(libc::STDIN_FILENO)?;
+                        let result = terminal::proxy(master, true);
+                        if let Some(original) = original {
+                            terminal::restore_mode(libc::STDIN_FILENO, &original)?;
+                        }
+                        close(master)?;
+                        result?;
+                    }
+
+                    Ok(())
+                })();
+
+                if let Err(e) = &result {
+                    error!("cannot supervise container: {}", e);
+                    // The container process may be waiting for the pid to be read, or running already
+                    let mut init_pid = [0u8; 4];
+                    if state.pid == 0 && read(init_read, &mut init_pid).unwrap_or(0) == init_pid.len() {
+                        state.set_running(i32::from_ne_bytes(init_pid));
+                    }
+                    if state.pid != 0 {
+                        let _ = kill(Pid::from_raw(state.pid), Signal::SIGKILL);
+                    }
                 }
-
-                info!("writing pid file...");
-                let pid_path = format!("{}/pid", self.path);
-                if Path::new(&pid_path).exists() {
-                    info!("removing pid file...");
-                    fs::remove_file(&pid_path)?;
+                let _ = close(init_read);
+                if let Some(ready) = ready_pipe {
+                    let _ = close(ready);
                 }
-                let pid_str = format!("{}\n", child.as_raw().to_string());
-                fs::File::create(&pid_path)?;
-                fs::write(pid_path, pid_str)?;
-
-                std::thread::sleep(std::time::Duration::from_millis(10));
-                // Path::new("/proc/self/ns")
-                //     .read_dir().unwrap()
-                //     .for_each(|dir| {
-                //         let ns_path = format!("{}",
-                //             dir.unwrap()
-                //             .path()
-                //             .display());
-                //         let link = format!("{}", fs::read_link(&ns_path).unwrap().display());
-                //         let ns_dir_path = format!("{}/{}",
-                //             &self.path,
-                //             Path::new(&ns_path)
-                //                 .strip_prefix(Path::new("/proc/self/ns/")).unwrap()
-                //                 .display()
-                //             );
-
-                //         info!("{} -> {}", ns_dir_path, link);
-                //         // if let Err(e) = fs::copy(ns_path, ns_dir_path) {
-                //         //     info!("{}", e);
-                //         // };
-                //         if let Err(e) = fs::soft_link(ns_dir_path, link) {
-                //             info!("{}", e);
-                //         };
-                //     });
 
-                // if !daemon {
-                    waitpid(child, None)?;
-                    // exit(0);
-                // }
+                let status = waitpid(child, None);
+                if let Ok(status) = status {
+                    state.set_stopped(Some(exit_code(status)));
+                }
+                for logger in loggers {
+                    let _ = logger.join();
+                }
 
-                Ok(())
+                result.and(status.map(|_| ()).map_err(From::from))
             }
             Err(e) => Err(From::from(e))
         };
         info!("executed outer fork.");
 
-        self.cleanup()?;
+        let cleanup = self.cleanup();
+
+        if state.is_active() {
+            state.set_stopped(None);
+        }
+        state.save(&self.path)?;
+
+        if ready.is_some() {
+            clients.close();
+            let _ = fs::remove_file(self.attach_socket_path());
+        }
 
         info!("ran container.");
-        result
+        result.and(cleanup)
         // match result {
         //     Err(e) => {
         //         info!("error encountered while running container: {}", e);
@@ -1023,9 +1572,49 @@ impl Container {
             return Ok(())
         }
 
-        fs::remove_dir_all(container_path)?;
+        if let Some(state) = State::load(&self.path)? {
+            state.check(&[Status::created, Status::stopped], "delete")?;
+            if state.pid != 0 {
+                return Err(format!("cannot delete container '{}': container process is waiting to be started", self.id).into())
+            }
+        }
+
+        cgroups::remove(self)?;
+        storage::load_driver(&self.path)?.remove(self)?;
+        // The files of a mount left in the container directory would be deleted with it, like the image layers
+        if utils::has_mounts_under(&self.path)? {
+            return Err(format!("cannot delete container '{}': its filesystem is still mounted", self.id).into())
+        }
+        // Rootless, the files written by the other container users are owned by subordinate ids
+        let spec_path = format!("{}/config.json", &self.path);
+        let linux = match utils::is_rootless() && Path::new(&spec_path).exists() {
+            true  => Spec::load(&spec_path)?.linux,
+            false => None
+        };
+        if let Err(e) = fs::remove_dir_all(container_path) {
+            match linux {
+                Some(linux) if !linux.uid_mappings.is_empty() && !linux.gid_mappings.is_empty() => {
+                    userns::run_as_root(&linux.uid_mappings, &linux.gid_mappings, || Ok(fs::remove_dir_all(container_path)?))?;
+                },
+                _ => return Err(e.into())
+            }
+        }
 
         info!("deleted container");
         Ok(())
     }
+
+    /// List the changes made by the container to its image
+    pub fn diff(&self) -> Result<Vec<Change>, Box<dyn std::error::Error>> {
+        storage::load_driver(&self.path)?.diff(self)
+    }
+}
+
+/// Get the exit code of a process, or 128 plus the signal that killed it, as shells do
+pub fn exit_code(status: WaitStatus) -> i32 {
+    match status {
+        WaitStatus::Exited(_, code)        => code,
+        WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+        _                                  => 0,
+    }
 }
@@ -1,11 +1,7 @@
-use std::fs::File;
+use std::fs;
 use std::path::Path;
-use std::ffi::CString;
-use std::os::unix::io::AsRawFd;
-use std::collections::HashMap;
-use nix::sys::{wait::waitpid, signal::kill, signal::Signal};
-use nix::unistd::{fork, ForkResult, execve, Pid};
-use nix::sched::{CloneFlags, setns};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
 use clap::ArgMatches;
 
 // use nix::unistd::*;
@@ -14,9 +10,16 @@ use clap::ArgMatches;
 
 use log::{info, error};
 
+use crate::paths;
 use crate::utils;
 use crate::image::Image;
 use crate::container::Container;
+use crate::state::{State, Status};
+use crate::spec::Overrides;
+use crate::logs;
+use crate::stats;
+use crate::attach;
+use crate::userns;
 
 pub struct ContainerManager<'a> {
     #[allow(dead_code)]
@@ -35,11 +38,12 @@ impl<'a> ContainerManager<'a> {
     pub fn create_with_args(&self, args: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
         let image_name = args.value_of("image-id").unwrap();
         let container_name = args.value_of("container-name").unwrap();
-        self.create(container_name, image_name)
+        self.create(container_name, image_name, &Overrides::default())
     }
     /// Create and store a new container
-    pub fn create(&self, container_name: &str, image_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn create(&self, container_name: &str, image_id: &str, overrides: &Overrides) -> Result<(), Box<dyn std::error::Error>> {
         info!("creating container '{}'...", container_name);
+        paths::validate_container_id(container_name)?;
 
         let image = match Image::load(image_id)? {
             Some(image) => image,
@@ -52,7 +56,10 @@ impl<'a> ContainerManager<'a> {
             }
         };
 
-        let container = Container::new(Some(container_name), Some(image));
+        let mut container = Container::new(Some(container_name), Some(image));
+        container.spec.apply_overrides(overrides)?;
+        let _lock = userns::lock()?;
+        container.allocate_id_mappings()?;
 
         container.create()?;
         info!("created container.");
@@ -63,14 +70,17 @@ impl<'a> ContainerManager<'a> {
     /// Run a stored container using arguments passed to the executable as parameters
     pub fn run_with_args(&self, args: &ArgMatches, daemon: bool, volume: Option<String>, host_ip: Option<String>, container_ip: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
         let container_name = args.value_of("container-name").unwrap();
-        self.run(container_name, daemon, volume, host_ip, container_ip)
+        self.run(container_name, daemon, volume, host_ip, container_ip, false, &Overrides::default())
     }
     /// Run a stored container
-    pub fn run(&self, container_name: &str, daemon: bool, volume: Option<String>, host_ip: Option<String>, container_ip: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    ///
+    /// The overrides are stored in the container configuration, so they're kept for the next runs
+    #[allow(clippy::too_many_arguments)]
+    pub fn run(&self, container_name: &str, daemon: bool, volume: Option<String>, host_ip: Option<String>, container_ip: Option<String>, detach: bool, overrides: &Overrides) -> Result<(), Box<dyn std::error::Error>> {
         info!("running container '{}'...", container_name);
 
         info!("loading container...");
-        let container = match Container::load(container_name).unwrap() {
+        let mut container = match Container::load(container_name).unwrap() {
             Some(container) => container,
             None            => {
                 info!("container not found. exiting...");
@@ -78,164 +88,187 @@ impl<'a> ContainerManager<'a> {
             }
         };
 
-        let pid_path = format!("{}/pid", container.path);
-        if Path::new(&pid_path).exists() {
-            info!("container already running. exiting...");
-            return Ok(())
-        }
+        container.state.check(&[Status::created, Status::stopped], "run")?;
+
+        container.spec.apply_overrides(overrides)?;
+        let lock = userns::lock()?;
+        container.allocate_id_mappings()?;
+        container.save_spec()?;
+        drop(lock);
 
-        container.run(daemon, volume, host_ip, container_ip)?;
+        container.run(daemon, volume, host_ip, container_ip, detach)?;
         info!("ran container.");
         Ok(())
     }
 
-    /// Call the setns syscall to enter a container's namespaces
-    fn set_namespace(&self, fd: &str, flag: CloneFlags) -> Result<(), Box<dyn std::error::Error>> {
-        // while !Path::new(fd).exists() {;}
+    /// Open/enter a running container, with a shell
+    ///
+    /// Returns the exit code of the shell
+    pub fn open(&self, container_name: &str) -> Result<i32, Box<dyn std::error::Error>> {
+        info!("opening container...");
 
-        if !Path::new(fd).exists() {
-            info!("path '{}' does not exit", fd);
-            Ok(())
-        } else {
-            if let Err(e) = setns(File::open(fd).unwrap().as_raw_fd(), flag) {
-                info!("error setting namespace {} - {:?}: {}", fd, flag, e);
-                Ok(())
-            } else {
-                info!("ns {:?} set", flag);
-                Ok(())
-            }
-        }
+        let overrides = Overrides {
+            command: vec![String::from("/bin/sh")],
+            ..Overrides::default()
+        };
+        let code = self.exec(container_name, &overrides, true, true)?;
+
+        info!("opened container.");
+        Ok(code)
     }
-    /// Execute command inside container
-    fn do_exec(&self, cmd: &str) -> Result<(), Box<dyn std::error::Error>> {
-        info!("preparing command execution...");
-
-        let args = &[Path::new(cmd).file_stem().unwrap().to_str().unwrap()];
-        let envs = &[
-            "PATH=/bin:/sbin:/usr/bin:/usr/sbin:/usr/local/bin",
-            "TERM=xterm-256color",
-            "LC_ALL=C"
-        ];
-        let p = CString::new(cmd).unwrap();
-
-        let a: Vec<CString> = args.iter()
-            .map(|s| CString::new(s.to_string()).unwrap_or_default())
-            .collect();
-        let e: Vec<CString> = envs.iter()
-            .map(|s| CString::new(s.to_string()).unwrap_or_default())
-            .collect();
-
-        info!("executing command...");
-        info!("arguments: \n{:?}\n{:?}\n{:?}",
-            args, envs, p);
-        execve(&p, &a, &e)?;
+    /// Execute a command in a running container
+    ///
+    /// The overrides apply to this command only. Returns the exit code of the command
+    pub fn exec(&self, container_name: &str, overrides: &Overrides, tty: bool, interactive: bool) -> Result<i32, Box<dyn std::error::Error>> {
+        info!("executing command in container '{}'...", container_name);
 
-        Ok(())
+        let mut container = match Container::load(container_name)? {
+            Some(container) => container,
+            None            => return Err(format!("container '{}' doesn't exist", container_name).into())
+        };
+        container.state.check(&[Status::running], "exec in")?;
+        info!("container pid: {}", container.state.pid);
+
+        container.spec.apply_overrides(overrides)?;
+        let code = container.exec(tty, interactive)?;
+
+        info!("executed command. exit code: {}", code);
+        Ok(code)
     }
-    /// Open/enter a running container
-    pub fn open(&self, container_name: &str) -> Result<(), Box<dyn std::error::Error>> {
-        info!("opening container...");
-        // let pid = unistd::Pid::from_raw(container_pid.parse::<i32>().unwrap());
 
-        let container_pid = match utils::get_container_pid_with_str(container_name).unwrap() {
-            None => {
-                info!("container isn't running or doesn't exist. exiting...");
-                return Ok(());
-            },
-            Some(pid) => pid
+    /// Attach to a container run in the background, until it stops or the detach keys are typed
+    ///
+    /// Returns the exit code of the container, or 0 when detaching
+    pub fn attach(&self, container_name: &str, detach_keys: &str) -> Result<i32, Box<dyn std::error::Error>> {
+        info!("attaching to container '{}'...", container_name);
+
+        let container = match Container::load(container_name)? {
+            Some(container) => container,
+            None            => return Err(format!("container '{}' doesn't exist", container_name).into())
         };
-        info!("container pid: {}", container_pid);
-
-        // info!("uid");
-        // // let uid = getuid();
-        // let uid = 0;
-        // // let newuid = self.spec.process.user.uid;
-        // let newuid = 0;
-        // let buf = format!("{} {} 1\n", newuid, uid);
-        // let fd = open("/proc/self/uid_map", OFlag::O_WRONLY, Mode::empty())?;
-        // info!("writing 'uid_map'");
-        // write(fd, buf.as_bytes())?;
-        // close(fd)?;
-
-        // // let fd = open("/proc/self/setgroups", OFlag::O_WRONLY, Mode::empty())?;
-        // // info!("writing 'deny' to setgroups");
-        // // write(fd, "deny".as_bytes())?;
-        // // close(fd)?;
-
-        // info!("gid");
-        // // let gid = getgid();
-        // let gid = 0;
-        // // let newgid = self.spec.process.user.gid;
-        // let newgid = 0;
-        // let buf = format!("{} {} 1\n", newgid, gid);
-        // let fd = open("/proc/self/gid_map", OFlag::O_WRONLY, Mode::empty())?;
-        // info!("writing 'gid_map'");
-        // write(fd, buf.as_bytes())?;
-        // close(fd)?;
-
-        let mut namespaces = HashMap::new();
-        namespaces.insert(CloneFlags::CLONE_NEWIPC, "ipc");
-        namespaces.insert(CloneFlags::CLONE_NEWUTS, "uts");
-        namespaces.insert(CloneFlags::CLONE_NEWNET, "net");
-        namespaces.insert(CloneFlags::CLONE_NEWPID, "pid");
-        namespaces.insert(CloneFlags::CLONE_NEWNS, "mnt");
-        namespaces.insert(CloneFlags::CLONE_NEWCGROUP, "cgroup");
-        namespaces.insert(CloneFlags::CLONE_NEWUSER, "user");
-
-        let pid_path = format!("/proc/{}/ns", container_pid);
-        info!("setting namespaces...");
-        for namespace in namespaces {
-            let ns_path = format!("{}/{}", pid_path, namespace.1);
-            self.set_namespace(ns_path.as_str(), namespace.0)?;
+        container.state.check(&[Status::running], "attach to")?;
+        let socket_path = container.attach_socket_path();
+        if !Path::new(&socket_path).exists() {
+            return Err(format!("container '{}' wasn't run in the background", container_name).into())
         }
 
-        let result = match fork() {
-            Ok(ForkResult::Parent { child, .. }) => {
-                waitpid(child, None)?;
-                Ok(())
-            }
-            Ok(ForkResult::Child) => {
-                self.do_exec("/bin/sh")
+        let detach_keys = attach::parse_keys(detach_keys)?;
+        if attach::attach(&socket_path, &detach_keys, container.spec.process.terminal)? {
+            info!("detached from container.");
+            return Ok(0)
+        }
+
+        let code = match State::load(&container.path)? {
+            Some(state) => state.exit_code.unwrap_or(0),
+            None        => 0
+        };
+        info!("container stopped. exit code: {}", code);
+        Ok(code)
+    }
+
+    /// Print the output of a container run in the background
+    pub fn logs(&self, container_name: &str, follow: bool, since: Option<String>, tail: Option<usize>) -> Result<(), Box<dyn std::error::Error>> {
+        info!("printing container logs...");
+
+        let container = match Container::load(container_name)? {
+            Some(container) => container,
+            None            => return Err(format!("container '{}' doesn't exist", container_name).into())
+        };
+        let since = match since {
+            Some(since) => Some(logs::parse_since(&since)?),
+            None        => None
+        };
+
+        let is_running = || match State::load(&container.path) {
+            Ok(Some(state)) => state.is_active(),
+            _               => false
+        };
+        logs::print(&container.log_path(), follow, since, tail, is_running)?;
+
+        info!("printed container logs.");
+        Ok(())
+    }
+
+    /// Print the resource usage of containers, every second unless 'stream' is off
+    ///
+    /// Without names, the running containers are watched
+    pub fn stats(&self, container_names: &[String], stream: bool, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+        info!("printing container stats...");
+
+        let mut containers = Vec::new();
+        if container_names.is_empty() {
+            let containers_path = paths::get_containers_path()?;
+            if Path::new(&containers_path).exists() {
+                for entry in fs::read_dir(&containers_path)? {
+                    let container_id = entry?.file_name().to_string_lossy().into_owned();
+                    if let Some(container) = Container::load(&container_id)? {
+                        if container.state.is_active() {
+                            containers.push(container);
+                        }
+                    }
+                }
             }
-            Err(e) => {
-                info!("fork failed: {}", e);
-                Ok(())
+            containers.sort_by(|a, b| a.id.cmp(&b.id));
+        } else {
+            for container_name in container_names {
+                match Container::load(container_name)? {
+                    Some(container) => containers.push(container),
+                    None            => return Err(format!("container '{}' doesn't exist", container_name).into())
+                }
             }
-        };
+        }
 
-        info!("opened container.");
-        result
+        stats::watch(&containers, stream, json)?;
+
+        info!("printed container stats.");
+        Ok(())
     }
 
     /// Stop a running container
     pub fn stop(&self, container_name: &str) -> Result<(), Box<dyn std::error::Error>> {
         info!("stopping container...");
 
-        let pid = match utils::get_container_pid_with_str(container_name).unwrap() {
+        let pid = match Container::load(container_name)? {
             None => {
-                info!("container isn't running or doesn't exist. exiting...");
+                info!("container doesn't exist. exiting...");
                 return Ok(());
             },
-            Some(pid) => pid
+            Some(mut container) => {
+                container.state.check(&[Status::running, Status::paused], "stop")?;
+                // Frozen processes don't handle signals
+                if container.state.status == Status::paused {
+                    container.unpause()?;
+                }
+                container.state.pid
+            }
         };
         info!("container pid: {}", pid);
 
         info!("killing process...");
-        let pid_int: i32 = pid.parse()?;
-        kill(Pid::from_raw(pid_int), Signal::SIGTERM)?;
+        kill(Pid::from_raw(pid), Signal::SIGTERM)?;
 
         info!("stopped container.");
         Ok(())
     }
 
+    /// Pause a running container, freezing its processes
+    pub fn pause(&self, container_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match Container::load(container_name)? {
+            Some(mut container) => container.pause(),
+            None                => Err(format!("container '{}' doesn't exist", container_name).into())
+        }
+    }
+    /// Unpause a paused container
+    pub fn unpause(&self, container_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match Container::load(container_name)? {
+            Some(mut container) => container.unpause(),
+            None                => Err(format!("container '{}' doesn't exist", container_name).into())
+        }
+    }
+
     /// List all stored containers
     pub fn list(&self) -> Result<(), Box<dyn std::error::Error>> {
-        // let home = match dirs::home_dir() {
-        //     Some(path) => path,
-        //     None       => return Err("error getting home directory".into())
-        // };
-        // let containers_path = format!("{}/.minato/containers", home.display());
-        let containers_path = format!("/var/lib/minato/containers");
+        let containers_path = paths::get_containers_path()?;
         let containers_path = Path::new(&containers_path);
         if !containers_path.exists() {
             error!("containers path not found. exiting...");
@@ -256,8 +289,8 @@ impl<'a> ContainerManager<'a> {
 
         // debug!("{:?}", containers);
         println!(
-            "{:10} {:30} {:30} {}",
-            "pid", "id", "image", "path");
+            "{:10} {:10} {:30} {:30} path",
+            "pid", "status", "id", "image");
         for c in containers {
             let container = match Container::load(c.as_str()) {
                 Ok(c) => c,
@@ -277,9 +310,11 @@ impl<'a> ContainerManager<'a> {
                         None => String::from("-")
                     };
 
+                    let status = format!("{:?}", cont.state.status);
+
                     println!(
-                        "{:10} {:30} {:30} {}",
-                        pid, cont.id, image, cont.path);
+                        "{:10} {:10} {:30} {:30} {}",
+                        pid, status, cont.id, image, cont.path);
                 },
                 None => continue
             }
@@ -292,15 +327,98 @@ impl<'a> ContainerManager<'a> {
     /// Delete a stored container using arguments passed to the executable as parameters
     pub fn delete_with_args(&self, args: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
         let container_name = args.value_of("container-name").unwrap();
-        self.delete(container_name)
+        self.delete(container_name, false)
     }
     /// Delete a stored container
-    pub fn delete(&self, container_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    ///
+    /// With force, the container process is killed first
+    pub fn delete(&self, container_name: &str, force: bool) -> Result<(), Box<dyn std::error::Error>> {
         info!("deleting container...");
+        paths::validate_container_id(container_name)?;
         let container = Container::new(Some(container_name), None);
 
+        if force {
+            if let Some(mut loaded) = Container::load(container_name)? {
+                if loaded.state.pid != 0 {
+                    // Frozen processes don't handle signals, not even SIGKILL on cgroup v1
+                    if loaded.state.status == Status::paused {
+                        loaded.unpause()?;
+                    }
+                    loaded.kill(Signal::SIGKILL)?;
+
+                    // The supervisor unmounts the container filesystem once the process is gone
+                    let mut stopped = false;
+                    for _ in 0..1000 {
+                        if State::load(&loaded.path)?.is_none_or(|state| state.pid == 0) && !utils::has_mounts_under(&loaded.path)? {
+                            stopped = true;
+                            break
+                        }
+                        std::thread::sleep(std::time::Duration::from_millis(10));
+                    }
+                    if !stopped {
+                        return Err(format!("cannot delete container '{}': its process {} wasn't cleaned up in time", container_name, loaded.state.pid).into())
+                    }
+                }
+            }
+        }
+
         container.delete()?;
         info!("deleted container.");
         Ok(())
     }
+
+    /// Print the changes made by a container to its image
+    pub fn diff(&self, container_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let container = match Container::load(container_name)? {
+            Some(container) => container,
+            None            => return Err("container not found".into())
+        };
+
+        for change in container.diff()? {
+            println!("{}", change);
+        }
+
+        Ok(())
+    }
+
+    /// Create a container from an OCI bundle, leaving its process waiting for 'start'
+    pub fn create_from_bundle(&self, container_id: &str, bundle: &str, pid_file: Option<String>, console_socket: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+        info!("creating container '{}'...", container_id);
+
+        let mut container = Container::from_bundle(container_id, bundle)?;
+        container.create_bundle(console_socket)?;
+
+        if let Some(pid_file) = pid_file {
+            info!("writing pid file...");
+            fs::write(pid_file, container.state.pid.to_string())?;
+        }
+
+        info!("created container.");
+        Ok(())
+    }
+    /// Start the process of a created container
+    pub fn start(&self, container_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match Container::load(container_id)? {
+            Some(mut container) => container.start(),
+            None                => Err(format!("container '{}' not found", container_id).into())
+        }
+    }
+    /// Print the state of a container
+    pub fn state(&self, container_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match Container::load(container_id)? {
+            Some(container) => {
+                println!("{}", serde_json::to_string_pretty(&container.state)?);
+                Ok(())
+            },
+            None => Err(format!("container '{}' not found", container_id).into())
+        }
+    }
+    /// Send a signal to the container process
+    pub fn kill(&self, container_id: &str, signal: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let signal = utils::parse_signal(signal)?;
+        match Container::load(container_id)? {
+            Some(container) => container.kill(signal),
+            None            => Err(format!("container '{}' not found", container_id).into())
+        }
+    }
 }
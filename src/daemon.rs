@@ -12,6 +12,7 @@ use std::fmt;
 
 use log::info;
 
+use crate::paths;
 use crate::utils;
 use crate::image_manager::ImageManager;
 use crate::container_manager::ContainerManager;
@@ -53,7 +54,7 @@ impl<'a> Daemon<'a> {
         // let suffix = process::id();
         // let s_name = format!("socket_{}", suffix);
         let s_name = String::from("socket");
-        let s_path_str = utils::get_socket_path(s_name.as_str()).unwrap();
+        let s_path_str = paths::get_socket_path(s_name.as_str()).unwrap();
         let socket = Daemon::create_socket(s_path_str.clone())?;
 
         info!("created daemon.");
@@ -69,6 +70,9 @@ impl<'a> Daemon<'a> {
         info!("creating socket...");
 
         let socket = Path::new(&socket_path);
+        if let Some(runtime_path) = socket.parent() {
+            fs::create_dir_all(runtime_path)?;
+        }
         if socket.exists() {
             info!("socket file already exists. removing...");
             fs::remove_file(&socket)?;
@@ -86,13 +90,7 @@ impl<'a> Daemon<'a> {
     pub fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("starting daemon...");
 
-        // let home = match dirs::home_dir() {
-        //     Some(path) => path,
-        //     None       => return Err("error getting home directory".into())
-        // };
-
-        // let pid_path = format!("{}/.minato/pid", home.display());
-        let pid_path = format!("/var/lib/minato/pid");
+        let pid_path = paths::get_pid_path()?;
         if Path::new(&pid_path).exists() {
             info!("removing pid file...");
             fs::remove_file(&pid_path)?;
@@ -138,15 +136,16 @@ impl<'a> Daemon<'a> {
     // }
 
     /// Convert string command recieved from client and execute it
+    ///
+    /// The message is the client's command line as a JSON array, read until the client shuts down its end
     fn handle_client(&self, stream: UnixStream) -> Result<(), Box<dyn std::error::Error>> {
         info!("handling client...");
 
         let mut temp_stream = stream.try_clone()?;
 
         info!("reading message...");
-        let mut buffer = [0; 1024];
-        let size = temp_stream.read(&mut buffer).unwrap();
-        let message = String::from_utf8(buffer[..size].to_vec()).unwrap();
+        let mut message = String::new();
+        temp_stream.read_to_string(&mut message)?;
         info!("client message: {}", message);
 
         let opt = Opt::from_str(message.as_str())?;
@@ -155,6 +154,12 @@ impl<'a> Daemon<'a> {
         if opt.exit {
             return Err(Box::new(ExitError("close daemon".into())));
         }
+        // Refused without an error, which would stop the daemon
+        if opt.is_interactive() {
+            info!("refusing command using the terminal of the client...");
+            temp_stream.write_all(b"exec and attach use the terminal of the client, they can't run in the daemon")?;
+            return Ok(())
+        }
 
         info!("executing command ...");
         utils::run_command(opt, &self.image_manager, &self.container_manager)?;
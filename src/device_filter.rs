@@ -0,0 +1,285 @@
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use nix::libc;
+use nix::unistd::close;
+
+use log::info;
+
+use crate::spec::DeviceCgroup;
+
+
+// eBPF instructions, from linux/bpf.h
+const BPF_LDX_MEM_W: u8 = 0x61;
+const BPF_ALU_AND_K: u8 = 0x54;
+const BPF_ALU_RSH_K: u8 = 0x74;
+const BPF_ALU_MOV_X: u8 = 0xbc;
+const BPF_ALU64_MOV_K: u8 = 0xb7;
+const BPF_JMP_JEQ_K: u8 = 0x15;
+const BPF_JMP_JNE_K: u8 = 0x55;
+const BPF_JMP_JNE_X: u8 = 0x5d;
+const BPF_JMP_EXIT: u8 = 0x95;
+const BPF_CLASS_JMP: u8 = 0x05;
+
+// Registers
+const R0: u8 = 0;
+const R1: u8 = 1;
+const R2: u8 = 2;
+const R3: u8 = 3;
+const R4: u8 = 4;
+const R5: u8 = 5;
+
+// Offsets in struct bpf_cgroup_dev_ctx
+const ACCESS_TYPE_OFFSET: i16 = 0;
+const MAJOR_OFFSET: i16 = 4;
+const MINOR_OFFSET: i16 = 8;
+
+// Device types and accesses of the context, from linux/bpf.h
+const BPF_DEVCG_DEV_BLOCK: i32 = 1;
+const BPF_DEVCG_DEV_CHAR: i32 = 2;
+const BPF_DEVCG_ACC_MKNOD: i32 = 1;
+const BPF_DEVCG_ACC_READ: i32 = 2;
+const BPF_DEVCG_ACC_WRITE: i32 = 4;
+const ALL_ACCESSES: i32 = BPF_DEVCG_ACC_MKNOD | BPF_DEVCG_ACC_READ | BPF_DEVCG_ACC_WRITE;
+
+// bpf() commands and program attributes
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_PROG_ATTACH: libc::c_int = 8;
+const BPF_PROG_TYPE_CGROUP_DEVICE: u32 = 15;
+const BPF_CGROUP_DEVICE: u32 = 6;
+const BPF_F_ALLOW_MULTI: u32 = 2;
+const LICENSE: &[u8] = b"Apache\0";
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Instruction {
+    code: u8,
+    /// Destination register in the low bits, source register in the high ones
+    registers: u8,
+    offset: i16,
+    immediate: i32,
+}
+
+#[repr(C)]
+struct ProgramLoadAttributes {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+}
+
+#[repr(C)]
+struct ProgramAttachAttributes {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+    replace_bpf_fd: u32,
+}
+
+/// Compile device rules and attach them to a cgroup v2 cgroup, where the devices controller of cgroup v1 doesn't exist
+///
+/// The program stays attached until the cgroup is removed
+pub fn attach(cgroup_path: &str, rules: &[DeviceCgroup]) -> Result<(), Box<dyn std::error::Error>> {
+    info!("attaching device filter...");
+
+    let program = compile(rules)?;
+    let load_attributes = ProgramLoadAttributes {
+        prog_type: BPF_PROG_TYPE_CGROUP_DEVICE,
+        insn_cnt: program.len() as u32,
+        insns: program.as_ptr() as u64,
+        license: LICENSE.as_ptr() as u64,
+        log_level: 0,
+        log_size: 0,
+        log_buf: 0,
+        kern_version: 0,
+        prog_flags: 0,
+    };
+    let program_fd = bpf(BPF_PROG_LOAD, &load_attributes)
+        .map_err(|e| format!("cannot load device filter: {}", e))?;
+
+    let cgroup = File::open(cgroup_path)
+        .map_err(|e| format!("cannot open cgroup {}: {}", cgroup_path, e));
+    let result = cgroup.and_then(|cgroup| {
+        let attach_attributes = ProgramAttachAttributes {
+            target_fd: cgroup.as_raw_fd() as u32,
+            attach_bpf_fd: program_fd as u32,
+            attach_type: BPF_CGROUP_DEVICE,
+            attach_flags: BPF_F_ALLOW_MULTI,
+            replace_bpf_fd: 0,
+        };
+        bpf(BPF_PROG_ATTACH, &attach_attributes)
+            .map_err(|e| format!("cannot attach device filter to {}: {}", cgroup_path, e))
+    });
+    close(program_fd)?;
+    result?;
+
+    info!("attached device filter ({} instructions).", program.len());
+    Ok(())
+}
+
+/// Make a bpf() call
+fn bpf<T>(command: libc::c_int, attributes: &T) -> Result<i32, io::Error> {
+    let result = unsafe {
+        libc::syscall(libc::SYS_bpf, command, attributes as *const T, size_of::<T>() as libc::c_uint)
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(result as i32)
+}
+
+/// Compile device rules to an eBPF program for the cgroup device hook
+///
+/// As in cgroup v1, where each rule is written over the previous ones, the last rule matching an access applies,
+/// and accesses no rule matches are allowed
+fn compile(rules: &[DeviceCgroup]) -> Result<Vec<Instruction>, Box<dyn std::error::Error>> {
+    // R2 holds the device type, R3 the access, R4 the major and R5 the minor number
+    let mut program = vec![
+        instruction(BPF_LDX_MEM_W, R2, R1, ACCESS_TYPE_OFFSET, 0),
+        instruction(BPF_ALU_AND_K, R2, 0, 0, 0xffff),
+        instruction(BPF_LDX_MEM_W, R3, R1, ACCESS_TYPE_OFFSET, 0),
+        instruction(BPF_ALU_RSH_K, R3, 0, 0, 16),
+        instruction(BPF_LDX_MEM_W, R4, R1, MAJOR_OFFSET, 0),
+        instruction(BPF_LDX_MEM_W, R5, R1, MINOR_OFFSET, 0),
+    ];
+    for rule in rules.iter().rev() {
+        let block = rule_block(rule)?;
+        // A rule matching every access hides the previous ones, and the verifier rejects unreachable instructions
+        let matches_all = block.iter().all(|instruction| !is_conditional_jump(instruction));
+        program.extend(block);
+        if matches_all {
+            return Ok(program)
+        }
+    }
+    program.extend_from_slice(&[
+        instruction(BPF_ALU64_MOV_K, R0, 0, 0, 1),
+        instruction(BPF_JMP_EXIT, 0, 0, 0, 0),
+    ]);
+
+    Ok(program)
+}
+
+/// Compile a rule to instructions returning its verdict if it matches, and going on after them otherwise
+///
+/// An allow rule matches if it has every requested access, a deny rule if it has any of them
+fn rule_block(rule: &DeviceCgroup) -> Result<Vec<Instruction>, Box<dyn std::error::Error>> {
+    let mut block = Vec::new();
+
+    match rule.typ.as_str() {
+        "" | "a"  => {},
+        "b"       => block.push(instruction(BPF_JMP_JNE_K, R2, 0, 0, BPF_DEVCG_DEV_BLOCK)),
+        "c" | "u" => block.push(instruction(BPF_JMP_JNE_K, R2, 0, 0, BPF_DEVCG_DEV_CHAR)),
+        typ       => return Err(format!("invalid device type '{}'", typ).into())
+    }
+
+    let access = access(&rule.access)?;
+    if access != ALL_ACCESSES {
+        block.push(instruction(BPF_ALU_MOV_X, R1, R3, 0, 0));
+        block.push(instruction(BPF_ALU_AND_K, R1, 0, 0, access));
+        if rule.allow {
+            block.push(instruction(BPF_JMP_JNE_X, R1, R3, 0, 0));
+        } else {
+            block.push(instruction(BPF_JMP_JEQ_K, R1, 0, 0, 0));
+        }
+    }
+
+    for (register, number) in &[(R4, rule.major), (R5, rule.minor)] {
+        if let Some(number) = number.filter(|number| *number >= 0) {
+            block.push(instruction(BPF_JMP_JNE_K, *register, 0, 0, number as i32));
+        }
+    }
+
+    block.push(instruction(BPF_ALU64_MOV_K, R0, 0, 0, rule.allow as i32));
+    block.push(instruction(BPF_JMP_EXIT, 0, 0, 0, 0));
+
+    // Every conditional jump skips the rest of the block
+    let length = block.len();
+    for (index, instruction) in block.iter_mut().enumerate() {
+        if is_conditional_jump(instruction) {
+            instruction.offset = (length - index - 1) as i16;
+        }
+    }
+
+    Ok(block)
+}
+
+/// Convert the access of a rule, like "rwm", to the access bits of the context, every access if it's empty
+fn access(access: &str) -> Result<i32, Box<dyn std::error::Error>> {
+    if access.is_empty() {
+        return Ok(ALL_ACCESSES)
+    }
+
+    let mut bits = 0;
+    for c in access.chars() {
+        bits |= match c {
+            'r' => BPF_DEVCG_ACC_READ,
+            'w' => BPF_DEVCG_ACC_WRITE,
+            'm' => BPF_DEVCG_ACC_MKNOD,
+            _   => return Err(format!("invalid device access '{}'", access).into())
+        };
+    }
+    Ok(bits)
+}
+
+fn is_conditional_jump(instruction: &Instruction) -> bool {
+    instruction.code & 0x07 == BPF_CLASS_JMP && instruction.code != BPF_JMP_EXIT
+}
+
+fn instruction(code: u8, destination: u8, source: u8, offset: i16, immediate: i32) -> Instruction {
+    Instruction {
+        code,
+        registers: (source << 4) | destination,
+        offset,
+        immediate,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(allow: bool, typ: &str, major: Option<i64>, minor: Option<i64>, access: &str) -> DeviceCgroup {
+        DeviceCgroup { allow, typ: typ.to_string(), major, minor, access: access.to_string() }
+    }
+
+    #[test]
+    fn rule_block_jumps_past_its_verdict() {
+        let block = rule_block(&rule(true, "c", Some(1), Some(5), "r")).unwrap();
+        assert_eq!(block, vec![
+            instruction(BPF_JMP_JNE_K, R2, 0, 7, BPF_DEVCG_DEV_CHAR),
+            instruction(BPF_ALU_MOV_X, R1, R3, 0, 0),
+            instruction(BPF_ALU_AND_K, R1, 0, 0, BPF_DEVCG_ACC_READ),
+            instruction(BPF_JMP_JNE_X, R1, R3, 4, 0),
+            instruction(BPF_JMP_JNE_K, R4, 0, 3, 1),
+            instruction(BPF_JMP_JNE_K, R5, 0, 2, 5),
+            instruction(BPF_ALU64_MOV_K, R0, 0, 0, 1),
+            instruction(BPF_JMP_EXIT, 0, 0, 0, 0),
+        ]);
+    }
+
+    #[test]
+    fn compile_stops_at_a_rule_matching_everything() {
+        let rules = [rule(true, "c", Some(1), Some(3), ""), rule(false, "a", None, None, "rwm")];
+        let program = compile(&rules).unwrap();
+        assert_eq!(program.len(), 6 + 2);
+        assert_eq!(program[6], instruction(BPF_ALU64_MOV_K, R0, 0, 0, 0));
+
+        let rules = [rule(false, "a", None, None, ""), rule(true, "c", Some(1), Some(3), "")];
+        let program = compile(&rules).unwrap();
+        assert_eq!(program.len(), 6 + 5 + 2);
+        assert_eq!(program[10], instruction(BPF_JMP_EXIT, 0, 0, 0, 0));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!(compile(&[rule(true, "x", None, None, "")]).is_err());
+        assert!(compile(&[rule(true, "c", None, None, "rx")]).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use std::fs::{self, create_dir_all, File};
+use std::os::unix;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use nix::errno::Errno;
+use nix::mount::{mount, MsFlags};
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use nix::unistd::{chown, Gid, Uid};
+
+use log::info;
+
+use crate::mounts;
+use crate::spec::{Device, DeviceType};
+
+
+/// Devices every container gets, as path, major and minor numbers
+pub const DEFAULT_DEVICES: [(&str, u64, u64); 6] = [
+    ("/dev/null",    1, 3),
+    ("/dev/zero",    1, 5),
+    ("/dev/full",    1, 7),
+    ("/dev/random",  1, 8),
+    ("/dev/urandom", 1, 9),
+    ("/dev/tty",     5, 0),
+];
+/// Links every container gets, as path and target
+const DEFAULT_LINKS: [(&str, &str); 5] = [
+    ("/dev/ptmx",   "pts/ptmx"),
+    ("/dev/fd",     "/proc/self/fd"),
+    ("/dev/stdin",  "/proc/self/fd/0"),
+    ("/dev/stdout", "/proc/self/fd/1"),
+    ("/dev/stderr", "/proc/self/fd/2"),
+];
+
+/// Populate the /dev of a container, under its root directory
+///
+/// Creates the default devices and links, the devices of the spec, and /dev/console if the container has a terminal
+pub fn create_devices(rootfs: &str, devices: &[Device], console: Option<RawFd>) -> Result<(), Box<dyn std::error::Error>> {
+    info!("creating container devices...");
+
+    for (path, major, minor) in DEFAULT_DEVICES.iter() {
+        let device = Device {
+            typ: DeviceType::c,
+            path: path.to_string(),
+            major: *major,
+            minor: *minor,
+            file_mode: 0o666,
+            uid: 0,
+            gid: 0,
+        };
+        create_device(rootfs, &device)?;
+    }
+    for device in devices {
+        create_device(rootfs, device)?;
+    }
+
+    for (path, target) in DEFAULT_LINKS.iter() {
+        let link = mounts::node_path(rootfs, path)?;
+        if fs::symlink_metadata(&link).is_ok() {
+            fs::remove_file(&link)?;
+        }
+        unix::fs::symlink(target, &link)?;
+    }
+
+    if let Some(console) = console {
+        info!("binding container terminal to /dev/console...");
+        let path = mounts::node_path(rootfs, "/dev/console")?;
+        if fs::symlink_metadata(&path).map(|metadata| metadata.file_type().is_symlink()).unwrap_or(false) {
+            fs::remove_file(&path)?;
+        }
+        if !Path::new(&path).exists() {
+            File::create(&path)?;
+        }
+        // Bound by path, the terminal may have been opened before the mount namespace was unshared
+        let terminal = fs::read_link(format!("/proc/self/fd/{}", console))?;
+        mount(Some(&terminal), path.as_str(), None::<&str>, MsFlags::MS_BIND, None::<&str>)
+            .map_err(|e| format!("cannot bind the container terminal to /dev/console: {}", e))?;
+    }
+
+    info!("created container devices.");
+    Ok(())
+}
+
+/// Create a device node under the root directory of a container
+///
+/// In a user namespace, where device nodes can't be created, the host device is bound instead
+fn create_device(rootfs: &str, device: &Device) -> Result<(), Box<dyn std::error::Error>> {
+    let path = mounts::node_path(rootfs, &device.path)?;
+    let kind = match device.typ {
+        DeviceType::c | DeviceType::u => SFlag::S_IFCHR,
+        DeviceType::b                 => SFlag::S_IFBLK,
+        DeviceType::p                 => SFlag::S_IFIFO,
+        DeviceType::a                 => return Err(format!("invalid type for device '{}'", device.path).into())
+    };
+
+    if let Some(parent) = Path::new(&path).parent() {
+        create_dir_all(parent)?;
+    }
+    if fs::symlink_metadata(&path).is_ok() {
+        fs::remove_file(&path)?;
+    }
+
+    let mode = Mode::from_bits_truncate(device.file_mode);
+    match mknod(path.as_str(), kind, mode, makedev(device.major, device.minor)) {
+        Ok(()) => {
+            // The mode given to mknod is masked by the umask
+            fs::set_permissions(&path, fs::Permissions::from_mode(device.file_mode & 0o7777))?;
+            chown(path.as_str(), Some(Uid::from_raw(device.uid)), Some(Gid::from_raw(device.gid)))?;
+        },
+        Err(nix::Error::Sys(Errno::EPERM)) => {
+            File::create(&path)?;
+            mount(Some(device.path.as_str()), path.as_str(), None::<&str>, MsFlags::MS_BIND, None::<&str>)
+                .map_err(|e| format!("cannot bind device '{}': {}", device.path, e))?;
+        },
+        Err(e) => return Err(format!("cannot create device '{}': {}", device.path, e).into())
+    }
+
+    Ok(())
+}
@@ -1,15 +1,25 @@
 use std::io::copy;
-use std::fs::{create_dir_all, File, remove_file, remove_dir_all};
+use std::fs::{create_dir_all, File, OpenOptions, remove_file, remove_dir_all, rename};
 use std::path::Path;
-
-use log::{debug, info};
+use std::process::Command;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::io::AsRawFd;
+use nix::fcntl::{flock, FlockArg};
+use nix::mount::{umount2, MntFlags};
+use openssl::pkey::{PKey, Public};
+
+use log::{debug, info, error};
 use reqwest;
 use serde_json::{self, Value};
 use tar::Archive;
 use flate2::read::GzDecoder;
 extern crate clap;
 
+use crate::paths;
 use crate::utils;
+use crate::stargz;
+use crate::config::{Config, LayerFormat};
+use crate::trust::{self, Policy};
 
 
 
@@ -20,6 +30,9 @@ pub struct Image {
     pub fs_layers: Vec<String>,
     pub path: String
 }
+/// Name of the image json, kept in the image directory next to the layers
+const IMAGE_JSON: &str = "image.json";
+
 // TODO: Control better how layers are added (load automatically)
 // TODO: Move load, add 'exists' function
 // TODO: Check to see if 'self's are required
@@ -29,7 +42,7 @@ impl Image {
         let id = utils::fix_image_id(image_id).unwrap();
         let (image_name, image_reference) = utils::split_image_id(id.clone()).unwrap();
 
-        let path = utils::get_image_path_with_str(id.as_str()).unwrap();
+        let path = paths::get_image_path_with_str(id.as_str()).unwrap();
 
         Image {
             id,
@@ -58,6 +71,7 @@ impl Image {
                     .path()
                     .file_name().unwrap()
                     .to_str().unwrap()))
+            .filter(|name| name != IMAGE_JSON)
             .collect::<Vec<String>>()
             .clone();
 
@@ -95,38 +109,27 @@ impl Image {
         info!("retrieved manifests.");
         Ok(body)
     }
-    /// Write the image json in the images/json directory
-    fn write_image_json(&self, body: Value) -> Result<(), Box<dyn std::error::Error>> {
+    /// Write the image json in the image directory, while it's still in the staging directory
+    ///
+    /// So an image is never in place without its json
+    fn write_image_json(&self, staging_path: &str, body: Value) -> Result<(), Box<dyn std::error::Error>> {
         info!("writing image json...");
-        let image_id = utils::fix_image_id(&self.id).unwrap();
-
-        // let home = match dirs::home_dir() {
-        //     Some(path) => path,
-        //     None       => return Err("error getting home directory".into())
-        // };
-        // let json_directory_path_str = format!(
-        //     "{}/.minato/images/json",
-        //     home.display()
-        // );
-        let json_directory_path_str = format!("/var/lib/minato/images/json");
-        let json_directory_path = Path::new(json_directory_path_str.as_str());
-
-        if !json_directory_path.exists() {
-            create_dir_all(json_directory_path)?;
-        }
-
-        let json_name = format!(
-            "{}.json",
-            image_id.replace("/", "_")
-        );
-        let json_path = json_directory_path.join(json_name);
 
+        let json_path = format!("{}/{}", staging_path, IMAGE_JSON);
         serde_json::to_writer(&File::create(&json_path)?, &body)?;
-        debug!("json path: {}", json_path.to_str().unwrap());
+        debug!("json path: {}", json_path);
 
         info!("written image json");
         Ok(())
     }
+    /// Get the path of the image json, in the image directory or in the images/json directory for older images
+    fn image_json_path(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let json_path = format!("{}/{}", &self.path, IMAGE_JSON);
+        if Path::new(&json_path).exists() {
+            return Ok(json_path)
+        }
+        Ok(format!("{}/{}.json", paths::get_image_json_path()?, self.escaped_id()))
+    }
     /// Extract the fs_layers field from the json
     fn extract_layers_from_body(&self, body: Value) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
         info!("extracting fs_layers...");
@@ -139,23 +142,189 @@ impl Image {
         info!("extracted fs_layers.");
         Ok(fs_layers.clone())
     }
+    /// Get the url used to request a pull token for the image's repository
+    fn authentication_url(&self) -> String {
+        format!(
+            "https://auth.docker.io/token?service=registry.docker.io&scope=repository:{}:pull",
+            &self.name
+        )
+    }
+    /// Get the url of a blob in the image's repository
+    fn blob_url(&self, blob_sum: &str) -> String {
+        format!(
+            "https://registry.hub.docker.com/v2/{}/blobs/{}",
+            self.name, blob_sum
+        )
+    }
+    /// Download a manifest, keeping its raw content so its digest can be checked
+    fn get_manifest(&self, token: &str, reference: &str, media_types: &[&str]) -> Result<(Vec<u8>, Value), Box<dyn std::error::Error>> {
+        let manifest_url = format!(
+            "https://registry.hub.docker.com/v2/{}/manifests/{}",
+            self.name, reference
+        );
+        debug!("sending manifest request to: {}...", manifest_url);
+
+        let response = reqwest::blocking::Client::new()
+            .get(manifest_url.as_str())
+            .bearer_auth(token)
+            .header(reqwest::header::ACCEPT, media_types.join(", "))
+            .send()?;
+        if !response.status().is_success() {
+            return Err(format!("manifest {} not found: {}", reference, response.status()).into());
+        }
+
+        let content = response.bytes()?.to_vec();
+        let body: Value = serde_json::from_slice(&content)?;
+        Ok((content, body))
+    }
+    /// Download a small blob, checking its digest
+    fn get_blob(&self, token: &str, digest: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let response = reqwest::blocking::Client::new()
+            .get(self.blob_url(digest).as_str())
+            .bearer_auth(token)
+            .send()?;
+        let content = response.bytes()?.to_vec();
+
+        if trust::digest(&content) != digest {
+            return Err(format!("blob {} doesn't match its digest", digest).into());
+        }
+        Ok(content)
+    }
+    /// Download the OCI or schema 2 manifest of the image for this platform
+    ///
+    /// Returns the digest of the manifest the reference points to, which may be an index, and the platform manifest
+    fn get_platform_manifest(&self, token: &str) -> Result<(String, Value), Box<dyn std::error::Error>> {
+        let (content, manifest) = self.get_manifest(token, &self.reference, &[
+            "application/vnd.oci.image.index.v1+json",
+            "application/vnd.docker.distribution.manifest.list.v2+json",
+            "application/vnd.oci.image.manifest.v1+json",
+            "application/vnd.docker.distribution.manifest.v2+json",
+        ])?;
+        let manifest_digest = trust::digest(&content);
+        debug!("manifest digest: {}", manifest_digest);
+
+        let manifests = match &manifest["manifests"] {
+            Value::Array(manifests) => manifests,
+            _ => return Ok((manifest_digest, manifest))
+        };
+        let architecture = match std::env::consts::ARCH {
+            "x86_64"  => "amd64",
+            "aarch64" => "arm64",
+            arch      => arch,
+        };
+        let platform_digest = manifests
+            .iter()
+            .find(|m| m["platform"]["os"] == "linux" && m["platform"]["architecture"] == architecture)
+            .and_then(|m| m["digest"].as_str())
+            .ok_or("no manifest for this platform")?
+            .to_string();
+
+        let (content, platform_manifest) = self.get_manifest(token, &platform_digest, &[
+            "application/vnd.oci.image.manifest.v1+json",
+            "application/vnd.docker.distribution.manifest.v2+json",
+        ])?;
+        if trust::digest(&content) != platform_digest {
+            return Err(format!("manifest {} doesn't match its digest", platform_digest).into());
+        }
+        Ok((manifest_digest, platform_manifest))
+    }
+    /// Get the toc digest of each seekable layer, from the annotations of the platform manifest
+    fn get_toc_digests(&self, token: &str) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+        let (_, manifest) = self.get_platform_manifest(token)?;
+        let toc_digests = match &manifest["layers"] {
+            Value::Array(layers) => layers
+                .iter()
+                .filter_map(|layer| Some((
+                    layer["digest"].as_str()?.to_string(),
+                    layer["annotations"][stargz::TOC_DIGEST_ANNOTATION].as_str()?.to_string(),
+                )))
+                .collect(),
+            _ => HashMap::new(),
+        };
+        Ok(toc_digests)
+    }
+    /// Verify that the image is signed by one of the trusted keys
+    ///
+    /// Signatures are looked up under the 'sha256-<manifest digest>.sig' tag.
+    /// Returns the digests of the layers of the signed manifest for this platform, from the base one
+    fn verify_signatures(&self, token: &str, keys: &[PKey<Public>]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        info!("verifying image signatures...");
+
+        // Signatures cover the whole index, the layers come from the manifest of this platform
+        let (manifest_digest, manifest) = self.get_platform_manifest(token)?;
+
+        let (_, signature_manifest) = self.get_manifest(token, &trust::signature_tag(&manifest_digest), &[
+            "application/vnd.oci.image.manifest.v1+json",
+            "application/vnd.docker.distribution.manifest.v2+json",
+        ]).map_err(|e| format!("no signatures found for {}: {}", self.id, e))?;
+
+        let mut signed = false;
+        for (payload_digest, signature) in trust::signature_layers(&signature_manifest) {
+            let payload = self.get_blob(token, &payload_digest)?;
+            if trust::verify_payload(keys, &payload, &signature, &manifest_digest, &self.name)? {
+                signed = true;
+                break
+            }
+        }
+        if !signed {
+            return Err(format!("image {} is not signed by a trusted key", self.id).into());
+        }
+
+        let layers = match &manifest["layers"] {
+            Value::Array(layers) => layers
+                .iter()
+                .filter_map(|layer| layer["digest"].as_str().map(String::from))
+                .collect::<Vec<String>>(),
+            _ => return Err("signed manifest has no layers".into()),
+        };
+
+        info!("verified image signatures.");
+        Ok(layers)
+    }
+    /// Store the toc of a seekable fs_layer instead of downloading it, and create its mountpoint
+    ///
+    /// Returns false if the layer is not seekable, or if the manifest has no toc digest to check its toc against
+    fn pull_lazy_layer(&mut self, fs_layer: &Value, image_path: &str, toc_digests: &HashMap<String, String>) -> Result<bool, Box<dyn std::error::Error>> {
+        let blob_sum = match &fs_layer["blobSum"] {
+            Value::String(blob_sum) => blob_sum,
+            _ => return Err("blobSum not found".into())
+        };
+        let digest = blob_sum.replace("sha256:", "");
+
+        let toc_digest = match toc_digests.get(blob_sum) {
+            Some(toc_digest) => toc_digest,
+            None => {
+                info!("layer {} has no toc digest.", digest);
+                return Ok(false)
+            }
+        };
+        let blob = stargz::Blob {
+            url: self.blob_url(blob_sum),
+            auth_url: Some(self.authentication_url()),
+        };
+        if !stargz::pull_layer(blob, &digest, toc_digest)? {
+            return Ok(false)
+        }
+
+        create_dir_all(format!("{}/{}", image_path, digest))?;
+        self.fs_layers.push(digest);
+
+        Ok(true)
+    }
     /// Download the blob for a single fs_layer
-    fn download_layer(&mut self, token: &str, fs_layer: &Value) -> Result<(), Box<dyn std::error::Error>> {
+    fn download_layer(&mut self, token: &str, fs_layer: &Value, image_path: &str) -> Result<(), Box<dyn std::error::Error>> {
         if let Value::String(blob_sum) = &fs_layer["blobSum"] {
             let digest = blob_sum.replace("sha256:", "");
             // let digest = blob_sum.split_off(blob_sum.find(':')?);
 
             let tar_path = format!(
                 "{}/{}.tar.gz",
-                &self.path, digest
+                image_path, digest
             );
 
             self.fs_layers.push(digest.clone());
 
-            let blob_url = format!(
-                "https://registry.hub.docker.com/v2/{}/blobs/{}",
-                self.name, blob_sum
-            );
+            let blob_url = self.blob_url(blob_sum);
 
             let mut response = reqwest::blocking::Client::new()
                 .get(blob_url.as_str())
@@ -163,6 +332,11 @@ impl Image {
                 .send()?;
             let mut tar_output = File::create(&tar_path)?;
             copy(&mut response, &mut tar_output)?;
+
+            let tar_digest = trust::file_digest(tar_path.as_str())?;
+            if &tar_digest != blob_sum {
+                return Err(format!("layer {} doesn't match its digest, got {}", blob_sum, tar_digest).into());
+            }
         } else {
             return Err("blobSum not found".into());
         }
@@ -171,24 +345,31 @@ impl Image {
     }
     // TODO: Change the way unpacking is skipped
     /// Unpack archive containing an image layer
-    fn unpack_image_layers(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    ///
+    /// If the host stores layers as filesystem images, the layer is converted instead
+    /// and an empty directory is left as its mountpoint
+    fn unpack_image_layers(&mut self, image_path: &str, layer_format: LayerFormat) -> Result<(), Box<dyn std::error::Error>> {
         info!("unpacking image layers...");
 
         for fs_layer in &self.fs_layers {
-            let image_path_str = utils::get_image_path(&self)?;
             let layer_path = format!(
                 "{}/{}",
-                image_path_str, fs_layer
+                image_path, fs_layer
             );
             let tar_path = format!(
                 "{}.tar.gz",
                 layer_path
             );
-            let tar_gz = File::open(&tar_path)?;
-            let tar = GzDecoder::new(tar_gz);
-            let mut archive = Archive::new(tar);
 
-            if !Path::new(layer_path.as_str()).exists() {
+            if !Path::new(layer_path.as_str()).exists() && layer_format != LayerFormat::directory {
+                Image::convert_layer(tar_path.as_str(), fs_layer, layer_format)?;
+                create_dir_all(layer_path.clone())?;
+                info!("converted layer {}", fs_layer);
+            } else if !Path::new(layer_path.as_str()).exists() {
+                let tar_gz = File::open(&tar_path)?;
+                let tar = GzDecoder::new(tar_gz);
+                let mut archive = Archive::new(tar);
+
                 create_dir_all(layer_path.clone())?;
                 archive.unpack(layer_path)?;
                 info!("unpacked layer {}", fs_layer);
@@ -201,15 +382,97 @@ impl Image {
 
         Ok(())
     }
+    /// Get the path of a layer converted to a filesystem image, and the image's format
+    fn get_layer_archive_path(digest: &str) -> Result<Option<(String, LayerFormat)>, Box<dyn std::error::Error>> {
+        let layer_path = paths::get_layer_path_with_str(digest)?;
+
+        for layer_format in &[LayerFormat::squashfs, LayerFormat::erofs] {
+            let archive_path = format!("{}/layer.{:?}", layer_path, layer_format);
+            if Path::new(&archive_path).exists() {
+                return Ok(Some((archive_path, *layer_format)))
+            }
+        }
+
+        Ok(None)
+    }
+    /// Convert a downloaded layer into a read-only filesystem image in the layer store
+    ///
+    /// Layers are stored by digest, so each one is converted only once for all images
+    fn convert_layer(tar_path: &str, digest: &str, layer_format: LayerFormat) -> Result<(), Box<dyn std::error::Error>> {
+        if Image::get_layer_archive_path(digest)?.is_some() {
+            info!("layer {} already converted. skipping...", digest);
+            return Ok(())
+        }
+        info!("converting layer {} to {:?}...", digest, layer_format);
+
+        let layer_path = paths::get_layer_path_with_str(digest)?;
+        let temporary_path = format!("{}.{}", layer_path, std::process::id());
+        let root_path = format!("{}/root", temporary_path);
+        let temporary_archive_path = format!("{}/layer.{:?}", temporary_path, layer_format);
+        create_dir_all(&root_path)?;
+
+        let tar_gz = File::open(tar_path)?;
+        let mut archive = Archive::new(GzDecoder::new(tar_gz));
+        archive.set_preserve_permissions(true);
+        archive.unpack(&root_path)?;
+
+        let output = match layer_format {
+            LayerFormat::squashfs => Command::new("mksquashfs")
+                .arg(&root_path).arg(&temporary_archive_path)
+                .arg("-noappend").arg("-quiet")
+                .output()?,
+            LayerFormat::erofs => Command::new("mkfs.erofs")
+                .arg(&temporary_archive_path).arg(&root_path)
+                .output()?,
+            LayerFormat::directory => return Err("layer format is not an archive".into())
+        };
+        debug!("output: {}", output.status);
+        if !output.status.success() {
+            remove_dir_all(&temporary_path)?;
+            return Err(format!(
+                "converting layer failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ).into());
+        }
+
+        create_dir_all(&layer_path)?;
+        rename(&temporary_archive_path, format!("{}/layer.{:?}", layer_path, layer_format))?;
+        remove_dir_all(&temporary_path)?;
+
+        info!("converted layer {}.", digest);
+        Ok(())
+    }
+    /// Loop-mount a layer filesystem image read-only, if it isn't already mounted
+    fn mount_archive_layer(archive_path: &str, layer_format: LayerFormat, mountpoint: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if utils::is_mounted(mountpoint)? {
+            return Ok(())
+        }
+
+        info!("mount -t {:?} -o loop,ro {} {}", layer_format, archive_path, mountpoint);
+        let output = Command::new("mount")
+            .arg("-t").arg(format!("{:?}", layer_format))
+            .arg("-o").arg("loop,ro")
+            .arg(archive_path).arg(mountpoint)
+            .output()?;
+        debug!("output: {}", output.status);
+        if !output.status.success() {
+            return Err(format!(
+                "mounting layer failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ).into());
+        }
+
+        Ok(())
+    }
     // TODO: Check if file exists before removal?
     /// Remove all archives after unpacking them
-    fn remove_archives(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    fn remove_archives(&mut self, image_path: &str) -> Result<(), Box<dyn std::error::Error>> {
         info!("cleaning up image directory...");
 
         for fs_layer in &self.fs_layers {
             let layer_path = format!(
                 "{}/{}",
-                &self.path, fs_layer
+                image_path, fs_layer
             );
             let tar_path = format!(
                 "{}.tar.gz",
@@ -225,14 +488,16 @@ impl Image {
         info!("cleaned up image directory.");
         Ok(())
     }
-    /// Pull an image from the docker repository and store it
-    fn pull_from_docker(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Pull an image from the docker repository into the staging directory
+    ///
+    /// With lazy pulling, seekable layers are mounted on demand and only their toc is fetched
+    ///
+    /// If the trust policy requires signatures, they are verified before any layer is downloaded,
+    /// and every layer is downloaded fully so its digest can be checked
+    fn pull_from_docker(&mut self, staging_path: &str, lazy: bool) -> Result<(), Box<dyn std::error::Error>> {
         info!("pulling image from docker repository...");
 
-        let authentication_url = format!(
-            "https://auth.docker.io/token?service=registry.docker.io&scope=repository:{}:pull",
-            &self.name
-        );
+        let authentication_url = self.authentication_url();
         let token = self.get_authentication_token(authentication_url.as_str())?;
 
         let manifests_url = format!(
@@ -240,28 +505,132 @@ impl Image {
             &self.name, &self.reference
         );
         let json = self.get_image_json(token.as_str(), manifests_url.as_str())?;
-        self.write_image_json(json.clone())?;
-        let fs_layers = self.extract_layers_from_body(json)?;
+        let fs_layers = self.extract_layers_from_body(json.clone())?;
+        let layer_format = Config::load()?.storage.layer_format;
+        if utils::is_rootless() && layer_format != LayerFormat::directory {
+            return Err(format!("the {:?} layer format needs root to loop-mount layers", layer_format).into());
+        }
+
+        let mut lazy = lazy;
+        if let Some(keys) = Policy::load()?.keys(&self.name)? {
+            let signed_layers: Vec<String> = self.verify_signatures(token.as_str(), &keys)?
+                .into_iter()
+                .filter(|layer| layer != trust::EMPTY_LAYER)
+                .collect();
+
+            // Schema1 manifests list the layers from the top one, and add empty ones for the metadata only steps
+            let mut pulled_layers = Vec::new();
+            for fs_layer in fs_layers.iter().rev() {
+                let blob_sum = fs_layer["blobSum"].as_str().ok_or("blobSum not found")?;
+                if blob_sum != trust::EMPTY_LAYER {
+                    pulled_layers.push(blob_sum.to_string());
+                }
+            }
+            if pulled_layers != signed_layers {
+                return Err(format!("layers of {} don't match the signed image", self.id).into());
+            }
+            if lazy {
+                info!("image is signed. pulling every layer fully...");
+                lazy = false;
+            }
+        }
+
+        // The toc of a lazy layer is checked against the manifest, and its chunks against the toc
+        let toc_digests = if lazy {
+            self.get_toc_digests(token.as_str()).unwrap_or_else(|e| {
+                info!("cannot get the toc digests: {}. pulling every layer fully...", e);
+                HashMap::new()
+            })
+        } else {
+            HashMap::new()
+        };
 
-        info!("creating image directory...");
-        create_dir_all(&self.path)?;
+        info!("creating staging directory...");
+        create_dir_all(staging_path)?;
 
         let number_of_layers = fs_layers.len();
         for (index, fs_layer) in fs_layers.iter().enumerate() {
+            if lazy && self.pull_lazy_layer(fs_layer, staging_path, &toc_digests)? {
+                info!("layer {} out of {} will be fetched on demand", index + 1, number_of_layers);
+                continue
+            }
+
             info!("downloading layer {} out of {}...", index + 1, number_of_layers);
-            self.download_layer(token.as_str(), &fs_layer)?;
+            self.download_layer(token.as_str(), fs_layer, staging_path)?;
             info!("downloaded layer successfully");
         }
 
-        self.unpack_image_layers()?;
+        self.unpack_image_layers(staging_path, layer_format)?;
+
+        self.remove_archives(staging_path)?;
 
-        self.remove_archives()?;
+        self.write_image_json(staging_path, json)?;
+        self.commit_staging_directory(staging_path)?;
 
         info!("pulled image from docker repository.");
         Ok(())
     }
+    /// Move a fully pulled image from the staging directory to its final path
+    ///
+    /// Both directories live under the images directory, so the rename is atomic
+    fn commit_staging_directory(&self, staging_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        info!("moving image from staging directory...");
+
+        if let Some(parent) = Path::new(&self.path).parent() {
+            create_dir_all(parent)?;
+        }
+        rename(staging_path, &self.path)?;
+
+        info!("moved image from staging directory.");
+        Ok(())
+    }
+    /// Get the image id in a form usable as a file name
+    fn escaped_id(&self) -> String {
+        self.id.replace("/", "_")
+    }
+    /// Acquire the image lock, so only one process pulls or deletes an image at a time
+    ///
+    /// The lock is released when the returned file is dropped
+    fn lock(&self) -> Result<File, Box<dyn std::error::Error>> {
+        let lock_directory_path = paths::get_image_lock_path()?;
+        create_dir_all(&lock_directory_path)?;
+
+        let lock_path = format!("{}/{}.lock", lock_directory_path, self.escaped_id());
+        let lock_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)?;
+
+        info!("waiting for image lock...");
+        flock(lock_file.as_raw_fd(), FlockArg::LockExclusive)?;
+
+        Ok(lock_file)
+    }
+    /// Acquire the layer store lock, shared by pulls and exclusive when unused layers are removed
+    ///
+    /// So no layer is removed while a pull may be reusing it. The lock is released when the returned file is dropped
+    fn lock_layers(exclusive: bool) -> Result<File, Box<dyn std::error::Error>> {
+        let lock_path = paths::get_layer_lock_path()?;
+        if let Some(parent) = Path::new(&lock_path).parent() {
+            create_dir_all(parent)?;
+        }
+        let lock_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)?;
+
+        info!("waiting for layer lock...");
+        flock(lock_file.as_raw_fd(), if exclusive { FlockArg::LockExclusive } else { FlockArg::LockShared })?;
+
+        Ok(lock_file)
+    }
     /// Pull and store an image
-    pub fn pull(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    ///
+    /// Layers are downloaded into a staging directory, under the image lock,
+    /// and the image is moved into place only after all of them are unpacked
+    pub fn pull(&mut self, lazy: bool) -> Result<(), Box<dyn std::error::Error>> {
         info!("pulling image...");
 
         if Path::new(&self.path).exists() {
@@ -269,25 +638,238 @@ impl Image {
             return Ok(())
         }
 
-        self.pull_from_docker()?;
+        let _lock = self.lock()?;
+        if Path::new(&self.path).exists() {
+            info!("image was pulled by another process. skipping pull...");
+            return Ok(())
+        }
+        let _layers_lock = Image::lock_layers(false)?;
+
+        let staging_path = format!("{}/{}", paths::get_image_staging_path()?, self.escaped_id());
+        if Path::new(&staging_path).exists() {
+            info!("removing leftover staging directory...");
+            remove_dir_all(&staging_path)?;
+        }
+
+        if let Err(e) = self.pull_from_docker(staging_path.as_str(), lazy) {
+            info!("pull failed. removing staging directory...");
+            if let Err(e) = remove_dir_all(&staging_path) {
+                error!("error removing staging directory: {}", e);
+            }
+            return Err(e);
+        }
 
         info!("pulled image.");
         Ok(())
     }
+    /// Remove staging directories left behind by interrupted pulls
+    ///
+    /// Directories whose lock is still held belong to a pull in progress and are kept
+    pub fn cleanup_staging() -> Result<(), Box<dyn std::error::Error>> {
+        let staging_directory_path = paths::get_image_staging_path()?;
+        let staging_directory_path = Path::new(&staging_directory_path);
+        if !staging_directory_path.exists() {
+            return Ok(())
+        }
+
+        let lock_directory_path = paths::get_image_lock_path()?;
+        create_dir_all(&lock_directory_path)?;
+
+        for entry in staging_directory_path.read_dir()? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_str().unwrap();
+
+            let lock_path = format!("{}/{}.lock", lock_directory_path, name);
+            let lock_file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&lock_path)?;
+            if flock(lock_file.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_err() {
+                debug!("staging directory {} is in use. skipping...", name);
+                continue
+            }
+
+            info!("removing stale staging directory {}...", name);
+            remove_dir_all(entry.path())?;
+        }
+
+        Ok(())
+    }
+
+    /// Mount the layers that aren't stored as plain directories
+    ///
+    /// These are the layers converted to filesystem images and the ones fetched on demand
+    pub fn mount_layers(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for fs_layer in &self.fs_layers {
+            let layer_path = format!("{}/{}", &self.path, fs_layer);
+
+            if let Some((archive_path, layer_format)) = Image::get_layer_archive_path(fs_layer)? {
+                Image::mount_archive_layer(archive_path.as_str(), layer_format, layer_path.as_str())?;
+            } else if stargz::is_lazy(fs_layer) {
+                stargz::mount_layer(fs_layer, layer_path.as_str())?;
+            }
+        }
+
+        Ok(())
+    }
+    /// Get the paths of the image layers, from the top one to the base one
+    ///
+    /// The order comes from the stored image json, falling back to the directory order without it
+    pub fn layer_paths(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let json_path = self.image_json_path()?;
+
+        let mut fs_layers: Vec<String> = Vec::new();
+        if Path::new(&json_path).exists() {
+            let body: Value = serde_json::from_reader(File::open(&json_path)?)?;
+            for fs_layer in self.extract_layers_from_body(body)? {
+                if let Value::String(blob_sum) = &fs_layer["blobSum"] {
+                    let digest = blob_sum.replace("sha256:", "");
+                    if !fs_layers.contains(&digest) {
+                        fs_layers.push(digest);
+                    }
+                }
+            }
+        }
+        if fs_layers.is_empty() {
+            fs_layers = self.fs_layers.clone();
+        }
+
+        Ok(fs_layers
+            .iter()
+            .map(|fs_layer| format!("{}/{}", &self.path, fs_layer))
+            .collect())
+    }
+    /// Unmount all the layers mounted from the layer store
+    fn unmount_layers(&self, fs_layers: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        for fs_layer in fs_layers {
+            let layer_path = format!("{}/{}", &self.path, fs_layer);
+            if utils::is_mounted(layer_path.as_str())? {
+                info!("unmounting layer {}...", fs_layer);
+                umount2(layer_path.as_str(), MntFlags::MNT_DETACH)?;
+            }
+        }
 
-    /// Delete image's json from the jsons folder
+        Ok(())
+    }
+    /// Get the ids of all stored images, sorted
+    ///
+    /// Image directories are '<namespace>/<name>:<reference>' and hold the image json,
+    /// the images pulled by older versions have theirs in the images/json directory.
+    /// Entries starting with '.', like the staging directory of the pulls in progress, aren't images
+    pub fn list_ids() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut ids = Vec::new();
+        let is_hidden = |path: &Path| path.file_name().is_none_or(|name| name.to_string_lossy().starts_with('.'));
+
+        let images_path = paths::get_images_path()?;
+        if Path::new(&images_path).exists() {
+            for namespace in Path::new(&images_path).read_dir()? {
+                let namespace = namespace?.path();
+                if !namespace.is_dir() || is_hidden(&namespace) {
+                    continue
+                }
+                for image in namespace.read_dir()? {
+                    let image = image?.path();
+                    if !is_hidden(&image) && image.join(IMAGE_JSON).exists() {
+                        ids.push(format!(
+                            "{}/{}",
+                            namespace.file_name().unwrap().to_string_lossy(),
+                            image.file_name().unwrap().to_string_lossy()
+                        ));
+                    }
+                }
+            }
+        }
+
+        let json_directory_path_str = paths::get_image_json_path()?;
+        let json_directory_path = Path::new(&json_directory_path_str);
+        if json_directory_path.exists() {
+            for entry in json_directory_path.read_dir()? {
+                let entry = entry?.path();
+                let image_name = entry.file_stem().unwrap().to_str().unwrap();
+                ids.push(image_name.replace("_", "/"));
+            }
+        }
+
+        ids.sort();
+        ids.dedup();
+        Ok(ids)
+    }
+    /// Remove the layers from the layer store that no other image uses, including the ones being staged
+    ///
+    /// No pull is in progress while the layer lock is held exclusively
+    fn remove_unused_layers(&self, fs_layers: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        let _lock = Image::lock_layers(true)?;
+
+        let mut used_layers = HashSet::new();
+        for image_id in Image::list_ids()? {
+            if image_id == self.id {
+                continue
+            }
+            if let Some(image) = Image::load(image_id.as_str())? {
+                used_layers.extend(image.fs_layers);
+            }
+        }
+        used_layers.extend(Image::staging_layers()?);
+
+        for fs_layer in fs_layers {
+            let layer_path = paths::get_layer_path_with_str(fs_layer)?;
+            if !used_layers.contains(fs_layer) && Path::new(&layer_path).exists() {
+                info!("removing unused layer {}...", fs_layer);
+                remove_dir_all(layer_path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the layers of the images in the staging directory
+    fn staging_layers() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut layers = Vec::new();
+
+        let staging_directory_path = paths::get_image_staging_path()?;
+        if !Path::new(&staging_directory_path).exists() {
+            return Ok(layers)
+        }
+        for staging_path in Path::new(&staging_directory_path).read_dir()? {
+            let staging_path = staging_path?.path();
+            if !staging_path.is_dir() {
+                continue
+            }
+            for layer in staging_path.read_dir()? {
+                layers.push(layer?.file_name().to_string_lossy().to_string());
+            }
+        }
+
+        Ok(layers)
+    }
+    /// Get the containers created from the image
+    ///
+    /// Their 'lower' link points to the image directory
+    fn containers(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut containers = Vec::new();
+
+        let containers_path = paths::get_containers_path()?;
+        if !Path::new(&containers_path).exists() {
+            return Ok(containers)
+        }
+        for container_path in Path::new(&containers_path).read_dir()? {
+            let container_path = container_path?.path();
+            if let Ok(image_path) = container_path.join("lower").read_link() {
+                if image_path == Path::new(&self.path) {
+                    containers.push(container_path.file_name().unwrap_or_default().to_string_lossy().to_string());
+                }
+            }
+        }
+
+        Ok(containers)
+    }
+    /// Delete image's json from the jsons folder, where older versions kept it
     fn delete_image_json(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("deleting image json...");
 
-        // let home = match dirs::home_dir() {
-        //     Some(path) => path,
-        //     None       => return Err("error getting home directory".into())
-        // };
-        // let json_directory_path_str = format!(
-        //     "{}/.minato/images/json",
-        //     home.display()
-        // );
-        let json_directory_path_str = format!("/var/lib/minato/images/json");
+        let json_directory_path_str = paths::get_image_json_path()?;
         let json_directory_path = Path::new(json_directory_path_str.as_str());
 
         let json_name = format!(
@@ -323,6 +905,31 @@ impl Image {
         info!("deleted image directory.");
         Ok(())
     }
+    /// Delete the btrfs copy of the image, if a container was created from it with the btrfs driver
+    ///
+    /// The container snapshots don't depend on it
+    fn delete_image_subvolume(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let subvolume_path = paths::get_image_subvolume_path(self)?;
+        if !Path::new(&subvolume_path).exists() {
+            return Ok(())
+        }
+
+        info!("deleting image subvolume...");
+        let output = Command::new("btrfs")
+            .arg("subvolume").arg("delete")
+            .arg(&subvolume_path)
+            .output()?;
+        debug!("output: {}", output.status);
+        if !output.status.success() {
+            return Err(format!(
+                "deleting image subvolume failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ).into());
+        }
+
+        info!("deleted image subvolume.");
+        Ok(())
+    }
     /// Delete image from storage
     pub fn delete(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("deleting image...");
@@ -332,8 +939,29 @@ impl Image {
             return Ok(())
         }
 
+        let _lock = self.lock()?;
+
+        // Their root filesystems are built on the image layers, and they can't be loaded without the image
+        let containers = self.containers()?;
+        if !containers.is_empty() {
+            return Err(format!("image {} is used by the containers {}", self.id, containers.join(", ")).into());
+        }
+
+        let mut fs_layers = Vec::new();
+        for entry in Path::new(&self.path).read_dir()? {
+            let name = entry?.file_name()
+                .into_string()
+                .map_err(|name| format!("invalid layer name {:?}", name))?;
+            if name != IMAGE_JSON {
+                fs_layers.push(name);
+            }
+        }
+
+        self.unmount_layers(&fs_layers)?;
         self.delete_image_json()?;
         self.delete_image_directory()?;
+        self.delete_image_subvolume()?;
+        self.remove_unused_layers(&fs_layers)?;
 
         info!("deleted image.");
         Ok(())
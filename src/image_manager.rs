@@ -1,4 +1,3 @@
-use std::path::Path;
 
 use log::{info, error};
 extern crate clap;
@@ -13,6 +12,10 @@ pub struct ImageManager<'a> {
 }
 impl<'a>  ImageManager<'a> {
     pub fn new() -> ImageManager<'a> {
+        if let Err(e) = Image::cleanup_staging() {
+            error!("error cleaning up staging directories: {}", e);
+        }
+
         ImageManager {
             image_list: Vec::new()
         }
@@ -22,11 +25,13 @@ impl<'a>  ImageManager<'a> {
     /// Pull an image from the docker repository and store it; using arguments passed to the executable as parameters
     pub fn pull_with_args(&self, args: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
         let image_id = args.value_of("image-id").unwrap();
-        self.pull(image_id)
+        self.pull(image_id, args.is_present("lazy"))
     }
 
     /// Pull an image from the docker repository and store it
-    pub fn pull(&self, image_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    ///
+    /// With lazy pulling, seekable (eStargz) layers are fetched on demand when a container reads them
+    pub fn pull(&self, image_id: &str, lazy: bool) -> Result<(), Box<dyn std::error::Error>> {
         info!("pulling image...");
 
         // let mut image = match Image::load(image_id).unwrap() {
@@ -37,45 +42,20 @@ impl<'a>  ImageManager<'a> {
         info!("image: {} {} {} {}",
             image.id, image.name, image.reference, image.path);
 
-        image.pull()?;
+        image.pull(lazy)?;
         info!("pulled image.");
         Ok(())
     }
 
     /// List all stored images
     pub fn list(&self) -> Result<(), Box<dyn std::error::Error>> {
-        // let home = match dirs::home_dir() {
-        //     Some(path) => path,
-        //     None       => return Err("error getting home directory".into())
-        // };
-        // let images_path = format!("{}/.minato/images/json", home.display());
-        let images_path = format!("/var/lib/minato/images/json");
-        let images_path = Path::new(&images_path);
-        if !images_path.exists() {
-            error!("images path not found. exiting...");
-            return Ok(());
-        };
-
-        // debug!("{}", containers_path.display());
-        let images = images_path.read_dir()?;
-        let images = images
-            .map(|dir|
-                format!("{}",
-                    dir.unwrap()
-                    .path()
-                    .file_name().unwrap()
-                    .to_str().unwrap()))
-            .collect::<Vec<String>>()
-            .clone();
+        let images = Image::list_ids()?;
 
         // debug!("{:?}", images);
         println!(
             "{:25} {:25} {:25} {}",
             "id", "name", "reference", "path");
-        for i in images {
-            let image_name = Path::new(&i).file_stem().unwrap()
-                .to_str().unwrap();
-            let image_name = image_name.replace("_", "/");
+        for image_name in images {
             let image = match Image::load(image_name.as_str()) {
                 Ok(i) => i,
                 Err(e) => {
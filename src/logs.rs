@@ -0,0 +1,202 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use nix::libc;
+
+use log::{info, error};
+
+use crate::utils;
+use crate::attach::{self, Clients};
+
+
+/// A line of output of a container, stored as a JSON line in its log file
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Entry {
+    pub time: String,
+    pub stream: String,
+    pub log: String,
+}
+
+/// Append a line to a container log file
+pub fn append(log_path: &str, stream: &str, line: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let entry = Entry {
+        time: utils::get_timestamp(),
+        stream: stream.to_string(),
+        log: line.to_string(),
+    };
+    let mut buffer = serde_json::to_vec(&entry)?;
+    buffer.push(b'\n');
+
+    // A single write in append mode, so lines of different streams don't mix
+    let mut file = OpenOptions::new().create(true).append(true).open(log_path)?;
+    file.write_all(&buffer)?;
+    Ok(())
+}
+/// Copy the output read from a pipe to a container log file, line by line, and to the attached clients, in a new thread
+///
+/// The thread ends when every writer of the pipe is closed
+pub fn capture(fd: RawFd, stream: &'static str, log_path: &str, clients: Clients) -> JoinHandle<()> {
+    let log_path = log_path.to_string();
+    let stream_id = if stream == "stderr" { attach::STDERR } else { attach::STDOUT };
+    thread::spawn(move || {
+        let mut pipe = unsafe { File::from_raw_fd(fd) };
+        let mut buffer = [0u8; 4096];
+        let mut line = Vec::new();
+        loop {
+            let size = match pipe.read(&mut buffer) {
+                Ok(0)                                                => break,
+                Ok(size)                                             => size,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // Reading the master of a terminal fails with EIO once the slave is closed
+                Err(ref e) if e.raw_os_error() == Some(libc::EIO)    => break,
+                Err(e)                                               => {
+                    error!("error reading container {}: {}", stream, e);
+                    break
+                }
+            };
+            clients.broadcast(stream_id, &buffer[..size]);
+
+            for byte in &buffer[..size] {
+                line.push(*byte);
+                if *byte == b'\n' {
+                    write_line(&log_path, stream, &mut line);
+                }
+            }
+        }
+        if !line.is_empty() {
+            write_line(&log_path, stream, &mut line);
+        }
+    })
+}
+/// Print the output of a container from its log file
+///
+/// Only the entries from 'since' on are printed, and only the last 'tail' of them.
+/// When following, waits for new entries until 'is_running' returns false
+pub fn print(log_path: &str, follow: bool, since: Option<SystemTime>, tail: Option<usize>, is_running: impl Fn() -> bool) -> Result<(), Box<dyn std::error::Error>> {
+    let file = match File::open(log_path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound && !follow => {
+            info!("no logs found.");
+            return Ok(())
+        },
+        Err(e) => return Err(e.into())
+    };
+    let mut reader = BufReader::new(file);
+
+    let mut entries = Vec::new();
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        if line.ends_with('\n') {
+            entries.push(parse(&line)?);
+            line.clear();
+        }
+    }
+    let entries: Vec<Entry> = entries.into_iter()
+        .filter(|entry| is_since(entry, since))
+        .collect();
+    let skip = tail.map_or(0, |tail| entries.len().saturating_sub(tail));
+    for entry in &entries[skip..] {
+        write_entry(entry)?;
+    }
+
+    if !follow {
+        return Ok(())
+    }
+    loop {
+        // Check before reading, so the lines written right before the end are printed
+        let running = is_running();
+        while reader.read_line(&mut line)? > 0 {
+            if !line.ends_with('\n') {
+                continue
+            }
+            let entry = parse(&line)?;
+            if is_since(&entry, since) {
+                write_entry(&entry)?;
+            }
+            line.clear();
+        }
+        if !running {
+            return Ok(())
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+/// Parse the value of '--since', a duration before now like '10m', '1h30m' or '45s', or a timestamp
+pub fn parse_since(since: &str) -> Result<SystemTime, Box<dyn std::error::Error>> {
+    if since.contains('T') {
+        return utils::parse_timestamp(since)
+    }
+
+    let mut seconds = 0;
+    let mut number = String::new();
+    for c in since.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            _   => return Err(format!("invalid duration '{}', expected e.g. '10m' or '1h30m'", since).into())
+        };
+        seconds += number.parse::<u64>()
+            .map_err(|_| format!("invalid duration '{}', expected e.g. '10m' or '1h30m'", since))? * unit;
+        number.clear();
+    }
+    if !number.is_empty() || seconds == 0 {
+        return Err(format!("invalid duration '{}', expected e.g. '10m' or '1h30m'", since).into())
+    }
+
+    Ok(SystemTime::now() - Duration::from_secs(seconds))
+}
+
+/// Append a line to the log file and clear it
+fn write_line(log_path: &str, stream: &str, line: &mut Vec<u8>) {
+    if let Err(e) = append(log_path, stream, &String::from_utf8_lossy(line)) {
+        error!("error writing container log: {}", e);
+    }
+    line.clear();
+}
+fn parse(line: &str) -> Result<Entry, Box<dyn std::error::Error>> {
+    Ok(serde_json::from_str(line)?)
+}
+fn is_since(entry: &Entry, since: Option<SystemTime>) -> bool {
+    match since {
+        Some(since) => utils::parse_timestamp(&entry.time).map_or(true, |time| time >= since),
+        None        => true
+    }
+}
+/// Write the line of an entry to the standard stream it was read from
+fn write_entry(entry: &Entry) -> Result<(), Box<dyn std::error::Error>> {
+    if entry.stream == "stderr" {
+        io::stderr().write_all(entry.log.as_bytes())?;
+    } else {
+        let mut stdout = io::stdout();
+        stdout.write_all(entry.log.as_bytes())?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    #[test]
+    fn parse_since_durations_and_timestamps() {
+        let before = SystemTime::now();
+        let since = parse_since("1h30m10s").unwrap();
+        let elapsed = before.duration_since(since).unwrap();
+        assert!(elapsed >= Duration::from_secs(5409) && elapsed <= Duration::from_secs(5411), "{:?}", elapsed);
+
+        assert_eq!(parse_since("1970-01-01T00:01:00Z").unwrap(), UNIX_EPOCH + Duration::from_secs(60));
+        for since in ["", "10", "0s", "5x", "m", "1h30"].iter() {
+            assert!(parse_since(since).is_err(), "{}", since);
+        }
+    }
+}
@@ -1,7 +1,8 @@
+// Older serde_derive versions put the derived impls in a 'const _' block, which this lint flags in every struct
+#![allow(non_local_definitions)]
 use std::process::exit;
 use std::option::Option;
 use std::str::FromStr;
-use regex::Regex;
 
 extern crate structopt;
 use structopt::{StructOpt, clap::crate_name};
@@ -18,8 +19,26 @@ mod container_manager;
 mod utils;
 mod networking;
 mod daemon;
+mod devices;
+mod device_filter;
 mod client;
+mod logs;
+mod mounts;
+mod attach;
+mod capabilities;
+mod cgroups;
+mod config;
+mod paths;
+mod seccomp;
 mod spec;
+mod stargz;
+mod state;
+mod stats;
+mod storage;
+mod syscalls;
+mod terminal;
+mod trust;
+mod userns;
 
 
 #[derive(Debug, StructOpt)]
@@ -41,34 +60,31 @@ pub struct Opt {
         default_value = crate_name!())]
     log_level: String,
 
+    #[structopt(long,
+        about = "Directory holding the images, containers and sockets, instead of the default ones",
+        env = "MINATO_ROOT")]
+    root: Option<String>,
+
     #[structopt(subcommand)]
     subcommand: Option<Subcommand>
 }
 impl FromStr for Opt {
-    type Err = std::string::ParseError;
+    type Err = Box<dyn std::error::Error>;
 
+    /// Parse the command line arguments sent by a client, as a JSON array
     fn from_str(opt_str: &str) ->  Result<Self, Self::Err> {
-        let regex_str = r####"(?:Opt \{ daemon: )(true|false)(?:, exit: )(true|false)(?:, debug: )(true|false)(?:, log_level: ")([A-Za-z]+\w)(?:", subcommand: )(None|.+)(?: \})"####;
-        let regex = Regex::new(regex_str).unwrap();
-        let matches = regex.captures(opt_str).unwrap();
-        let daemon     = matches.get(1).map_or("", |m| m.as_str());
-        let exit       = matches.get(2).map_or("", |m| m.as_str());
-        let debug      = matches.get(3).map_or("", |m| m.as_str());
-        let log_level  = matches.get(4).map_or("", |m| m.as_str());
-        let subcommand = matches.get(5).map_or("", |m| m.as_str());
-
-        let subcommand_conv = match Subcommand::from_str(subcommand) {
-            Ok(s) => Some(s),
-            Err(_) => None
-        };
-
-        Ok(Opt {
-            daemon:     bool::from_str(daemon).unwrap(),
-            exit:       bool::from_str(exit).unwrap(),
-            debug:      bool::from_str(debug).unwrap(),
-            log_level:  String::from(log_level),
-            subcommand: subcommand_conv
-        })
+        let args: Vec<String> = serde_json::from_str(opt_str)?;
+        Ok(Opt::from_iter_safe(args)?)
+    }
+}
+impl Opt {
+    /// Check if the command works on the terminal of the user, like exec and attach
+    ///
+    /// The daemon has no access to it, so a client runs these commands itself
+    pub fn is_interactive(&self) -> bool {
+        matches!(&self.subcommand, Some(Subcommand::Container {
+            action: ContainerAction::Open { .. } | ContainerAction::Exec { .. } | ContainerAction::Attach { .. }
+        }))
     }
 }
 
@@ -84,35 +100,62 @@ enum Subcommand {
     Container {
         #[structopt(subcommand, about = "create|run|delete")]
         action: ContainerAction
-    }
-}
-impl FromStr for Subcommand {
-    type Err = std::io::Error;
+    },
 
-    fn from_str(opt_str: &str) ->  Result<Self, Self::Err> {
-        if opt_str == "None" {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "None"));
-        }
-        let regex_str = r####"((?:Some\()(.+)(?:\))|None)"####;
-        let regex = Regex::new(regex_str).unwrap();
-        let matches = regex.captures(opt_str).unwrap();
-        let subcommand = matches.get(2).map_or("", |m| m.as_str());
-
-        match subcommand.chars().next() {
-            Some('I') => Ok(
-                Subcommand::Image{
-                    action: ImageAction::from_str(subcommand).unwrap()
-                }
-            ),
-            Some('C') => Ok(
-                Subcommand::Container{
-                    action: ContainerAction::from_str(subcommand).unwrap()
-                }
-            ),
-            _ => Err(
-                std::io::Error::new(std::io::ErrorKind::Other, "invalid subcommand")
-            )
-        }
+    #[structopt(name = "create", about = "Create a container from an OCI bundle, without starting its process")]
+    Create {
+        #[structopt(name = "bundle",
+            about = "Path to the bundle directory",
+            short = "b", long = "bundle",
+            default_value = ".")]
+        bundle: String,
+
+        #[structopt(name = "pid-file",
+            about = "File to write the container process pid to",
+            long = "pid-file")]
+        pid_file: Option<String>,
+
+        #[structopt(name = "console-socket",
+            about = "Unix socket receiving the master end of the container terminal",
+            long = "console-socket")]
+        console_socket: Option<String>,
+
+        #[structopt(name = "container-id", about = "Container ID")]
+        container_id: String,
+    },
+
+    #[structopt(name = "start", about = "Start the process of a created container")]
+    Start {
+        #[structopt(name = "container-id", about = "Container ID")]
+        container_id: String,
+    },
+
+    #[structopt(name = "state", about = "Print the state of a container")]
+    State {
+        #[structopt(name = "container-id", about = "Container ID")]
+        container_id: String,
+    },
+
+    #[structopt(name = "kill", about = "Send a signal to the container process")]
+    Kill {
+        #[structopt(name = "container-id", about = "Container ID")]
+        container_id: String,
+
+        #[structopt(name = "signal",
+            about = "Signal name or number",
+            default_value = "SIGTERM")]
+        signal: String,
+    },
+
+    #[structopt(name = "delete", about = "Delete a stopped container")]
+    Delete {
+        #[structopt(name = "force",
+            about = "Kill the container process first, if it's still running",
+            short = "f", long = "force")]
+        force: bool,
+
+        #[structopt(name = "container-id", about = "Container ID")]
+        container_id: String,
     }
 }
 
@@ -125,6 +168,11 @@ enum ImageAction {
             short = "i", long = "image-id",
             default_value = "library/alpine:latest")]
         image_id: String,
+
+        #[structopt(name = "lazy",
+            about = "Fetch seekable (eStargz) layers on demand instead of downloading them",
+            long = "lazy")]
+        lazy: bool,
     },
 
     #[structopt(name = "list", about = "List pulled images")]
@@ -138,37 +186,11 @@ enum ImageAction {
         image_id: String,
     }
 }
-impl FromStr for ImageAction {
-    type Err = std::io::Error;
-
-    fn from_str(opt_str: &str) ->  Result<Self, Self::Err> {
-        let regex_str = r####"(?:Image \{ action: )(Pull|Delete)(?: \{ image_id: ")(.+)(?:" \} \})"####;
-        let regex = Regex::new(regex_str).unwrap();
-        let matches = regex.captures(opt_str).unwrap();
-        let action = matches.get(1).map_or("", |m| m.as_str());
-        let subcommand = matches.get(2).map_or("", |m| m.as_str());
-
-        match action.chars().next() {
-            Some('P') => Ok(
-                ImageAction::Pull{
-                    image_id: String::from(subcommand)
-                }
-            ),
-            Some('D') => Ok(
-                ImageAction::Delete{
-                    image_id: String::from(subcommand)
-                }
-            ),
-            _ => Err(
-                std::io::Error::new(std::io::ErrorKind::Other, "invalid action")
-            )
-        }
-    }
-}
 
 #[derive(Debug, StructOpt)]
 enum ContainerAction {
-    #[structopt(name = "create", about = "Create a container")]
+    #[structopt(name = "create", about = "Create a container",
+        setting = structopt::clap::AppSettings::TrailingVarArg)]
     Create {
         #[structopt(name = "container-name",
             about = "Container name",
@@ -180,9 +202,13 @@ enum ContainerAction {
             short = "i", long = "image-id",
             default_value = "library/alpine:latest")]
         image_id: String,
+
+        #[structopt(flatten)]
+        overrides: spec::Overrides,
     },
 
-    #[structopt(name = "run", about = "Run a container")]
+    #[structopt(name = "run", about = "Run a container",
+        setting = structopt::clap::AppSettings::TrailingVarArg)]
     Run {
         #[structopt(name = "container-name",
             about = "Container name",
@@ -203,6 +229,14 @@ enum ContainerAction {
             about = "IP address for container-to-host communication",
             short = "C", long = "container-ip")]
         container_ip: Option<String>,
+
+        #[structopt(name = "detach",
+            about = "Run in the background, writing the output to the container log",
+            short = "d", long = "detach")]
+        detach: bool,
+
+        #[structopt(flatten)]
+        overrides: spec::Overrides,
     },
 
     #[structopt(name = "open", about = "Open a container")]
@@ -213,6 +247,44 @@ enum ContainerAction {
         container_name: String,
     },
 
+    #[structopt(name = "exec", about = "Execute a command in a running container",
+        setting = structopt::clap::AppSettings::TrailingVarArg)]
+    Exec {
+        #[structopt(name = "interactive",
+            about = "Keep the standard input open",
+            short = "i", long = "interactive")]
+        interactive: bool,
+
+        #[structopt(name = "tty",
+            about = "Allocate a terminal",
+            short = "t", long = "tty")]
+        tty: bool,
+
+        #[structopt(name = "user",
+            about = "User and group ids of the command (uid[:gid])",
+            short = "u", long = "user")]
+        user: Option<String>,
+
+        #[structopt(name = "env",
+            about = "Set an environment variable (KEY=VALUE)",
+            short = "e", long = "env",
+            number_of_values = 1)]
+        env: Vec<String>,
+
+        #[structopt(name = "workdir",
+            about = "Working directory of the command, inside the container",
+            short = "w", long = "workdir")]
+        workdir: Option<String>,
+
+        #[structopt(name = "container-name", about = "Container name")]
+        container_name: String,
+
+        #[structopt(name = "command",
+            about = "Command to execute, and its arguments",
+            required = true)]
+        command: Vec<String>,
+    },
+
     #[structopt(name = "stop", about = "Stop a container")]
     Stop {
         #[structopt(name = "container-name",
@@ -221,6 +293,22 @@ enum ContainerAction {
         container_name: String,
     },
 
+    #[structopt(name = "pause", about = "Pause a container, freezing its processes")]
+    Pause {
+        #[structopt(name = "container-name",
+            about = "Container name",
+            short = "c", long = "container-name")]
+        container_name: String,
+    },
+
+    #[structopt(name = "unpause", about = "Unpause a paused container")]
+    Unpause {
+        #[structopt(name = "container-name",
+            about = "Container name",
+            short = "c", long = "container-name")]
+        container_name: String,
+    },
+
     #[structopt(name = "list", about = "List containers")]
     List,
 
@@ -230,69 +318,69 @@ enum ContainerAction {
             about = "Container name",
             short = "c", long = "container-nane")]
         container_name: String,
-    }
-}
-impl FromStr for ContainerAction {
-    type Err = std::io::Error;
+    },
 
-    fn from_str(opt_str: &str) ->  Result<Self, Self::Err> {
-        let regex_str = r####"(?:Container \{ action: )(Create(?: \{ container_name: ")(.[^"]+)(?:", image_id: ")*(.[^"]+)|Run(?: \{ container_name: ")(.[^"]+)"(?:, volume: (Some\(".+"\)|None))(?:, host_ip: (Some\(".+"\)|None))(?:, container_ip: (Some\(".+"\)|None))|Stop(?: \{ container_name: ")(.[^"]+)"|Delete(?: \{ container_name: ")(.[^"]+)")(?: \} \})"####;
-        let regex = Regex::new(regex_str).unwrap();
-        let matches = regex.captures(opt_str).unwrap();
-        let action                = matches.get(1).map_or("", |m| m.as_str());
-        let create_container_name = matches.get(2).map_or("", |m| m.as_str());
-        let create_image_id       = matches.get(3).map_or("", |m| m.as_str());
-        let run_container_name    = matches.get(4).map_or("", |m| m.as_str());
-        let run_volume            = matches.get(5).map_or("", |m| m.as_str());
-        let run_host_ip           = matches.get(6).map_or("", |m| m.as_str());
-        let run_container_ip      = matches.get(7).map_or("", |m| m.as_str());
-        let stop_container_name   = matches.get(8).map_or("", |m| m.as_str());
-        let delete_container_name = matches.get(9).map_or("", |m| m.as_str());
-
-        match action.chars().next() {
-            Some('C') => Ok(
-                ContainerAction::Create {
-                    container_name: String::from(create_container_name),
-                    image_id:       String::from(create_image_id)
-                }
-            ),
-            Some('R') => Ok(
-                ContainerAction::Run {
-                    container_name: String::from(run_container_name),
-                    volume:         option_from_str(run_volume),
-                    host_ip:        option_from_str(run_host_ip),
-                    container_ip:   option_from_str(run_container_ip)
-                }
-            ),
-            Some('S') => Ok(
-                ContainerAction::Stop {
-                    container_name: String::from(stop_container_name)
-                }
-            ),
-            Some('D') => Ok(
-                ContainerAction::Delete {
-                    container_name: String::from(delete_container_name)
-                }
-            ),
-            _ => Err(
-                std::io::Error::new(std::io::ErrorKind::Other, "invalid action")
-            )
-        }
-    }
-}
-fn option_from_str(option: &str) ->  Option<String> {
-    let regex_str = r####"(?:Some\("(.+)"\))"####;
-    let regex = Regex::new(regex_str).unwrap();
-    let matches = regex.captures(option);
-    if let None = matches {
-        return None
+    #[structopt(name = "attach", about = "Attach to the input and output of a container run in the background")]
+    Attach {
+        #[structopt(name = "container-name",
+            about = "Container name",
+            short = "c", long = "container-name")]
+        container_name: String,
+
+        #[structopt(name = "detach-keys",
+            about = "Key sequence to detach, leaving the container running",
+            long = "detach-keys", default_value = "ctrl-p,ctrl-q")]
+        detach_keys: String,
+    },
+
+    #[structopt(name = "logs", about = "Print the output of a container run in the background")]
+    Logs {
+        #[structopt(name = "container-name",
+            about = "Container name",
+            short = "c", long = "container-name")]
+        container_name: String,
+
+        #[structopt(name = "follow",
+            about = "Keep printing new output until the container stops",
+            short = "f", long = "follow")]
+        follow: bool,
+
+        #[structopt(name = "since",
+            about = "Only print the output since a timestamp, or a duration ago (e.g. 10m, 1h30m)",
+            long = "since")]
+        since: Option<String>,
+
+        #[structopt(name = "tail",
+            about = "Only print the last lines",
+            long = "tail")]
+        tail: Option<usize>,
+    },
+
+    #[structopt(name = "stats", about = "Print the resource usage of containers")]
+    Stats {
+        #[structopt(name = "no-stream",
+            about = "Print the usage once instead of refreshing it every second",
+            long = "no-stream")]
+        no_stream: bool,
+
+        #[structopt(name = "json",
+            about = "Print the usage as JSON, one line per refresh",
+            long = "json")]
+        json: bool,
+
+        #[structopt(name = "container-names", about = "Container names, all the running containers if none")]
+        container_names: Vec<String>,
+    },
+
+    #[structopt(name = "diff", about = "List the changes made by a container to its image")]
+    Diff {
+        #[structopt(name = "container-name",
+            about = "Container name",
+            short = "c", long = "container-name")]
+        container_name: String,
     }
-    let string = matches.unwrap()
-        .get(1).map_or("", |m| m.as_str());
-    return Some(String::from(string));
 }
 
-
 /**
  * * General
  *   TODO: Add .minato folder creation
@@ -305,10 +393,6 @@ fn option_from_str(option: &str) ->  Option<String> {
  *   TODO: Find a use for lib.rs file
  *
  * * Container
- *   TODO: Add container states (more code)
- *   TODO: Add contianer state check (i.e. before deletion)
- *   TODO: Remove dev mount and add ttys
- *   TODO: Populate 'sys' and 'dev' instead of mounting them from parent (maybe remove target)
  *   TODO: Pull containers from LXC repository
  *   TODO: Check if the inner fork is required or it works only with the execve
  *   * Namespaces
@@ -318,7 +402,6 @@ fn option_from_str(option: &str) ->  Option<String> {
  *   * CGroups
  *     ? /dev might have to be binded to the parent
  *     TODO: Try 'mount -t cgroup -o all cgroup /sys/fs/cgroup' to mount all cgroups faster
- *     TODO: Configure cgroups
  *   * Mounts
  *     TODO: Try archivemount instead of unarchiving layers
  *     TODO: Check if 'index=on' is needed when mounting overlayfs
@@ -345,6 +428,17 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
         .init();
 
     let opt = Opt::from_args();
+
+    if let Some(root) = &opt.root {
+        if let Err(e) = paths::set_root(root) {
+            error!("{}", e);
+            exit(1);
+        }
+        info!("using root {}", root);
+    }
+    if utils::is_rootless() {
+        info!("running rootless");
+    }
     // println!("{:?}", opt);
 
     // println!("{:?}", spec::Spec::load("src/config.json")?);
@@ -356,7 +450,7 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
             info!("running as client");
             match client::Client::new() {
                 Ok(client) => {
-                    let message = format!("{:?}", opt);
+                    let message = serde_json::to_string(&std::env::args().collect::<Vec<String>>())?;
                     client.send(message.as_bytes())?;
                     return Ok(())
                 },
@@ -386,12 +480,17 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
                     }
                 }
             }
+            Some(_) if opt.is_interactive() => {
+                info!("running in the client, the daemon has no access to the terminal");
+                run_in_process(opt);
+                Ok(())
+            }
             Some(_) => {
                 info!("running as client");
 
                 match client::Client::new() {
                     Ok(client) => {
-                        let message = format!("{:?}", opt);
+                        let message = serde_json::to_string(&std::env::args().collect::<Vec<String>>())?;
                         client.send(message.as_bytes())?;
                         Ok(())
                     },
@@ -406,13 +505,23 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
     } else {
         info!("running in daemonless mode");
 
-        let image_manager = image_manager::ImageManager::new();
-        let container_manager = container_manager::ContainerManager::new();
+        run_in_process(opt);
+        Ok(())
+    }
+
+}
+
+/// Run a command in this process, exiting with the exit code of the command if it failed
+fn run_in_process(opt: Opt) {
+    let image_manager = image_manager::ImageManager::new();
+    let container_manager = container_manager::ContainerManager::new();
 
-        if let Err(e) = utils::run_command(opt, &image_manager, &container_manager) {
+    match utils::run_command(opt, &image_manager, &container_manager) {
+        Ok(0)    => {},
+        Ok(code) => exit(code),
+        Err(e)   => {
             error!("program exited with error: {}", e);
+            exit(1);
         }
-        Ok(())
     }
-
 }
@@ -0,0 +1,350 @@
+use std::fs::{self, create_dir_all, read_to_string, File};
+use std::ffi::{CString, OsString};
+use std::os::unix::io::RawFd;
+use std::path::{Component, Path, PathBuf};
+use nix::errno::Errno;
+use nix::libc;
+use nix::mount::{mount, MsFlags};
+
+use log::info;
+
+use crate::spec::Mount;
+
+
+// New mount API, missing from libc
+const SYS_OPEN_TREE: libc::c_long = 428;
+const SYS_MOVE_MOUNT: libc::c_long = 429;
+const SYS_MOUNT_SETATTR: libc::c_long = 442;
+const OPEN_TREE_CLONE: libc::c_uint = 1;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x0000_0004;
+const MOUNT_ATTR_IDMAP: u64 = 0x0010_0000;
+/// Symlinks followed when resolving a path in the container root, as the kernel limit
+const MAX_SYMLINKS: usize = 40;
+
+/// Attributes to change with mount_setattr
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// Mount flags and filesystem data of the options of a mount
+struct MountOptions {
+    flags: MsFlags,
+    propagation: MsFlags,
+    data: String,
+}
+impl MountOptions {
+    /// Parse the options of a mount, like 'mount -o' does
+    ///
+    /// Options that aren't flags are passed to the filesystem as data
+    fn parse(options: &[String]) -> MountOptions {
+        let mut flags = MsFlags::empty();
+        let mut propagation = MsFlags::empty();
+        let mut data = Vec::new();
+
+        for option in options {
+            match option.as_str() {
+                "defaults"      => {},
+                "ro"            => flags.insert(MsFlags::MS_RDONLY),
+                "rw"            => flags.remove(MsFlags::MS_RDONLY),
+                "nosuid"        => flags.insert(MsFlags::MS_NOSUID),
+                "suid"          => flags.remove(MsFlags::MS_NOSUID),
+                "nodev"         => flags.insert(MsFlags::MS_NODEV),
+                "dev"           => flags.remove(MsFlags::MS_NODEV),
+                "noexec"        => flags.insert(MsFlags::MS_NOEXEC),
+                "exec"          => flags.remove(MsFlags::MS_NOEXEC),
+                "sync"          => flags.insert(MsFlags::MS_SYNCHRONOUS),
+                "async"         => flags.remove(MsFlags::MS_SYNCHRONOUS),
+                "dirsync"       => flags.insert(MsFlags::MS_DIRSYNC),
+                "remount"       => flags.insert(MsFlags::MS_REMOUNT),
+                "mand"          => flags.insert(MsFlags::MS_MANDLOCK),
+                "nomand"        => flags.remove(MsFlags::MS_MANDLOCK),
+                "atime"         => flags.remove(MsFlags::MS_NOATIME),
+                "noatime"       => flags.insert(MsFlags::MS_NOATIME),
+                "diratime"      => flags.remove(MsFlags::MS_NODIRATIME),
+                "nodiratime"    => flags.insert(MsFlags::MS_NODIRATIME),
+                "relatime"      => flags.insert(MsFlags::MS_RELATIME),
+                "norelatime"    => flags.remove(MsFlags::MS_RELATIME),
+                "strictatime"   => flags.insert(MsFlags::MS_STRICTATIME),
+                "nostrictatime" => flags.remove(MsFlags::MS_STRICTATIME),
+                "bind"          => flags.insert(MsFlags::MS_BIND),
+                "rbind"         => flags.insert(MsFlags::MS_BIND | MsFlags::MS_REC),
+                "private"       => propagation = MsFlags::MS_PRIVATE,
+                "rprivate"      => propagation = MsFlags::MS_PRIVATE | MsFlags::MS_REC,
+                "shared"        => propagation = MsFlags::MS_SHARED,
+                "rshared"       => propagation = MsFlags::MS_SHARED | MsFlags::MS_REC,
+                "slave"         => propagation = MsFlags::MS_SLAVE,
+                "rslave"        => propagation = MsFlags::MS_SLAVE | MsFlags::MS_REC,
+                "unbindable"    => propagation = MsFlags::MS_UNBINDABLE,
+                "runbindable"   => propagation = MsFlags::MS_UNBINDABLE | MsFlags::MS_REC,
+                _               => data.push(option.as_str()),
+            }
+        }
+
+        MountOptions {
+            flags,
+            propagation,
+            data: data.join(","),
+        }
+    }
+    /// Filesystem data, if any
+    fn data(&self) -> Option<&str> {
+        Some(self.data.as_str()).filter(|data| !data.is_empty())
+    }
+}
+
+/// Mount an entry of the spec mounts, under the root directory of the container
+///
+/// Missing mountpoints are created: files for bind mounts of files, directories otherwise
+pub fn mount_entry(entry: &Mount, rootfs: &str) -> Result<(), Box<dyn std::error::Error>> {
+    info!("mounting {} to {}...", entry.source, entry.destination);
+
+    let destination = destination_path(rootfs, &entry.destination)?;
+    let options = MountOptions::parse(&entry.options);
+
+    if entry.typ == "cgroup" {
+        return mount_cgroups(&destination, &options)
+    }
+
+    let is_bind = options.flags.contains(MsFlags::MS_BIND);
+    if is_bind && Path::new(&entry.source).is_file() {
+        if let Some(parent) = Path::new(&destination).parent() {
+            create_dir_all(parent)?;
+        }
+        if !Path::new(&destination).exists() {
+            File::create(&destination)?;
+        }
+    } else {
+        create_dir_all(&destination)?;
+    }
+
+    let source = Some(entry.source.as_str()).filter(|source| !source.is_empty());
+    let typ = Some(entry.typ.as_str()).filter(|typ| !typ.is_empty() && !is_bind);
+    let result = match mount(source, destination.as_str(), typ, options.flags, options.data()) {
+        // devpts refuses owners that aren't mapped in the user namespace, e.g. 'gid=5'
+        Err(nix::Error::Sys(Errno::EINVAL)) if entry.typ == "devpts" => {
+            info!("mounting devpts without owner options...");
+            let data: Vec<&str> = options.data.split(',')
+                .filter(|option| !option.starts_with("uid=") && !option.starts_with("gid="))
+                .collect();
+            let data = data.join(",");
+            mount(source, destination.as_str(), typ, options.flags, Some(data.as_str()).filter(|data| !data.is_empty()))
+        },
+        result => result
+    };
+    result.map_err(|e| format!("cannot mount {} to {}: {}", entry.source, entry.destination, e))?;
+
+    // Bind mounts ignore the other flags, they're applied by remounting
+    let bind_flags = MsFlags::MS_BIND | MsFlags::MS_REC;
+    if is_bind && !(options.flags - bind_flags).is_empty() {
+        mount(
+            None::<&str>,
+            destination.as_str(),
+            None::<&str>,
+            options.flags | MsFlags::MS_REMOUNT,
+            None::<&str>,
+        ).map_err(|e| format!("cannot remount {}: {}", entry.destination, e))?;
+    }
+
+    if !options.propagation.is_empty() {
+        mount(None::<&str>, destination.as_str(), None::<&str>, options.propagation, None::<&str>)
+            .map_err(|e| format!("cannot change the propagation of {}: {}", entry.destination, e))?;
+    }
+
+    Ok(())
+}
+
+/// Hide a path of the container: files get /dev/null bound over them, directories an empty read-only tmpfs
+///
+/// Paths that don't exist are skipped
+pub fn mask_path(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let result = match fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            mount(Some("tmpfs"), path, Some("tmpfs"), MsFlags::MS_RDONLY, None::<&str>)
+        },
+        Ok(_)                             => {
+            mount(Some("/dev/null"), path, None::<&str>, MsFlags::MS_BIND, None::<&str>)
+        },
+        Err(_)                            => return Ok(())
+    };
+    result.map_err(|e| format!("cannot mask {}: {}", path, e))?;
+    Ok(())
+}
+/// Make a path of the container read-only, by binding it over itself and remounting the bind read-only
+///
+/// Paths that don't exist are skipped
+pub fn make_readonly(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if !Path::new(path).exists() {
+        return Ok(())
+    }
+
+    mount(Some(path), path, None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REC, None::<&str>)
+        .and_then(|_| mount(
+            Some(path),
+            path,
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            None::<&str>,
+        ))
+        .map_err(|e| format!("cannot make {} read-only: {}", path, e))?;
+    Ok(())
+}
+
+/// Bind a directory with the ids of a user namespace, so its files are owned by the ids they map to
+///
+/// Needs a filesystem that supports idmapped mounts
+pub fn mount_idmapped(source: &str, target: &str, userns: RawFd) -> Result<(), Box<dyn std::error::Error>> {
+    let source_path = CString::new(source)?;
+    let target_path = CString::new(target)?;
+    let empty = CString::new("")?;
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_IDMAP,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: userns as u64,
+    };
+
+    let tree = unsafe {
+        libc::syscall(SYS_OPEN_TREE, libc::AT_FDCWD, source_path.as_ptr(), OPEN_TREE_CLONE | libc::O_CLOEXEC as libc::c_uint)
+    };
+    if tree < 0 {
+        return Err(format!("cannot clone the mount of {}: {}", source, Errno::last()).into())
+    }
+    let tree = tree as RawFd;
+
+    let result = unsafe {
+        libc::syscall(
+            SYS_MOUNT_SETATTR, tree, empty.as_ptr(), libc::AT_EMPTY_PATH,
+            &attr as *const MountAttr, std::mem::size_of::<MountAttr>(),
+        )
+    };
+    let result = if result < 0 {
+        Err(format!("cannot idmap the mount of {}: {}", source, Errno::last()))
+    } else {
+        let moved = unsafe {
+            libc::syscall(SYS_MOVE_MOUNT, tree, empty.as_ptr(), libc::AT_FDCWD, target_path.as_ptr(), MOVE_MOUNT_F_EMPTY_PATH)
+        };
+        if moved < 0 { Err(format!("cannot mount {} to {}: {}", source, target, Errno::last())) } else { Ok(()) }
+    };
+    unsafe { libc::close(tree) };
+
+    Ok(result?)
+}
+
+/// Path of a mount destination under the root directory of the container
+///
+/// Destinations must be absolute, and can't go up the tree. Symlinks of the image are followed in the container root
+pub fn destination_path(rootfs: &str, destination: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let path = Path::new(destination);
+    if !path.is_absolute() || path.components().any(|component| component == Component::ParentDir) {
+        return Err(format!("invalid mount destination '{}', expected an absolute path", destination).into())
+    }
+    resolve_in_root(rootfs, destination)
+}
+/// Path of a node to create under the root directory of the container, like a device or a link
+///
+/// Only its parent directory is resolved in the container root, so an existing symlink at the path is replaced, not followed
+pub fn node_path(rootfs: &str, path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let (parent, name) = match (Path::new(path).parent(), Path::new(path).file_name()) {
+        (Some(parent), Some(name)) => (parent, name),
+        _                          => return Err(format!("invalid path '{}', expected an absolute file path", path).into())
+    };
+    let parent = destination_path(rootfs, &parent.to_string_lossy())?;
+    Ok(Path::new(&parent).join(name).to_string_lossy().into_owned())
+}
+/// Resolve a path in the root directory of the container, as if it was the root, like securejoin does
+///
+/// Absolute symlink targets start from the container root, and '..' stops at it.
+/// Missing components are kept as they are, so the path can be created
+fn resolve_in_root(rootfs: &str, path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let root = Path::new(rootfs);
+    let mut resolved = PathBuf::new();
+    let mut components: Vec<OsString> = to_components(Path::new(path));
+    components.reverse();
+    let mut symlinks = 0;
+
+    while let Some(component) = components.pop() {
+        if component == ".." {
+            resolved.pop();
+            continue
+        }
+
+        let next = resolved.join(&component);
+        match fs::symlink_metadata(root.join(&next)) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    return Err(format!("cannot resolve '{}' in the container root: too many symlinks", path).into())
+                }
+                let target = fs::read_link(root.join(&next))?;
+                if target.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                components.extend(to_components(&target).into_iter().rev());
+            },
+            _ => resolved = next
+        }
+    }
+
+    // The path was only resolved in the root, but the existing part of it must also be there for the kernel
+    let resolved = root.join(resolved);
+    let existing = resolved.ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(root);
+    if !fs::canonicalize(existing)?.starts_with(fs::canonicalize(root)?) {
+        return Err(format!("'{}' resolves outside the container root", path).into())
+    }
+    Ok(resolved.to_string_lossy().into_owned())
+}
+/// Get the names and '..' of a path, the root and '.' don't change the resolved path
+fn to_components(path: &Path) -> Vec<OsString> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir    => Some(OsString::from("..")),
+            _                       => None
+        })
+        .collect()
+}
+/// Mount the cgroup hierarchies of the current process, on a tmpfs
+///
+/// Each cgroup v1 hierarchy gets a directory named after its controllers, the cgroup v2 one is 'unified'.
+/// On a cgroup v2 only host, the destination is the cgroup v2 hierarchy itself
+fn mount_cgroups(destination: &str, options: &MountOptions) -> Result<(), Box<dyn std::error::Error>> {
+    create_dir_all(destination)?;
+
+    // Lines are 'hierarchy-id:controllers:path', the controllers are empty for cgroup v2
+    let cgroups = read_to_string("/proc/self/cgroup")?;
+    let hierarchies: Vec<&str> = cgroups.lines()
+        .filter_map(|line| line.split(':').nth(1))
+        .collect();
+    let flags = options.flags | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+
+    if hierarchies.iter().all(|controllers| controllers.is_empty()) {
+        mount(Some("cgroup2"), destination, Some("cgroup2"), flags, None::<&str>)
+            .map_err(|e| format!("cannot mount cgroup2 to {}: {}", destination, e))?;
+        return Ok(())
+    }
+
+    mount(Some("tmpfs"), destination, Some("tmpfs"), flags - MsFlags::MS_RDONLY, Some("mode=755"))?;
+    for controllers in hierarchies {
+        let (name, typ, data) = match controllers {
+            ""          => ("unified", "cgroup2", None),
+            controllers => (controllers.trim_start_matches("name="), "cgroup", Some(controllers))
+        };
+        let path = format!("{}/{}", destination, name);
+        create_dir_all(&path)?;
+
+        info!("mounting cgroup {}...", name);
+        mount(Some(typ), path.as_str(), Some(typ), flags, data)
+            .map_err(|e| format!("cannot mount cgroup {}: {}", name, e))?;
+    }
+    if flags.contains(MsFlags::MS_RDONLY) {
+        mount(None::<&str>, destination, None::<&str>, flags | MsFlags::MS_REMOUNT, None::<&str>)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,216 @@
+use std::fs::create_dir_all;
+use std::path::Path;
+use std::sync::RwLock;
+use nix::unistd::Uid;
+
+use crate::utils;
+use crate::image::Image;
+use crate::container::Container;
+
+
+/// Directory set with '--root', holding all the state of this minato instance
+static ROOT: RwLock<Option<String>> = RwLock::new(None);
+
+/// Set the directory holding the images, containers, sockets and pid file, instead of the default ones
+///
+/// It's created if missing, and kept as an absolute path since containers change their working directory
+pub fn set_root(root: &str) -> Result<(), Box<dyn std::error::Error>> {
+    create_dir_all(root)
+        .map_err(|e| format!("cannot create root directory '{}': {}", root, e))?;
+    let root = Path::new(root).canonicalize()?;
+    *ROOT.write().map_err(|_| "root lock poisoned")? = Some(format!("{}", root.display()));
+    Ok(())
+}
+/// Get the directory set with '--root', if any
+fn get_root() -> Option<String> {
+    ROOT.read().ok()?.clone()
+}
+/// Get path to the directory holding the images and containers
+///
+/// The root set with '--root' if any, else '/var/lib/minato' as root, '$XDG_DATA_HOME/minato' rootless
+pub fn get_minato_path() -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(root) = get_root() {
+        return Ok(root)
+    }
+    if !utils::is_rootless() {
+        return Ok(String::from("/var/lib/minato"))
+    }
+    match dirs::data_dir() {
+        Some(path) => Ok(format!("{}/minato", path.display())),
+        None       => Err("error getting the user data directory, set XDG_DATA_HOME".into())
+    }
+}
+/// Get path to the directory holding the daemon socket and pid
+///
+/// The root set with '--root' if any, so each instance has its own daemon,
+/// else '/var/lib/minato' as root, '$XDG_RUNTIME_DIR/minato' rootless, or a directory in /tmp without it
+pub fn get_runtime_path() -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(root) = get_root() {
+        return Ok(root)
+    }
+    if !utils::is_rootless() {
+        return Ok(String::from("/var/lib/minato"))
+    }
+    match dirs::runtime_dir() {
+        Some(path) => Ok(format!("{}/minato", path.display())),
+        None       => Ok(format!("/tmp/minato-{}", Uid::effective()))
+    }
+}
+/// Get path to the directory of the images
+pub fn get_images_path() -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!("{}/images", get_minato_path()?))
+}
+/// Get path to the directory holding the image jsons
+pub fn get_image_json_path() -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!("{}/images/json", get_minato_path()?))
+}
+/// Get path to image, from the image id
+pub fn get_image_path_with_str(image_id: &str) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!(
+        "{}/{}",
+        get_images_path()?, image_id
+    ))
+}
+/// Get path to image, from image object
+pub fn get_image_path(image: &Image) -> Result<String, Box<dyn std::error::Error>> {
+    get_image_path_with_str(image.id.as_str())
+}
+/// Get path to the directory where images are downloaded before being moved in place
+pub fn get_image_staging_path() -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!("{}/images/.staging", get_minato_path()?))
+}
+/// Get path to the directory holding the per-image lock files
+pub fn get_image_lock_path() -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!("{}/images/.locks", get_minato_path()?))
+}
+/// Get path to the lock held while container id ranges are allocated
+pub fn get_id_lock_path() -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!("{}/ids.lock", get_minato_path()?))
+}
+/// Get path to the lock guarding the layers stored outside of the image directories
+pub fn get_layer_lock_path() -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!("{}/layers.lock", get_minato_path()?))
+}
+/// Get path to a layer stored outside of the image directories, from the layer digest
+pub fn get_layer_path_with_str(digest: &str) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!(
+        "{}/layers/{}",
+        get_minato_path()?, digest
+    ))
+}
+/// Get path to the btrfs subvolume holding a copy of an image, from image object
+pub fn get_image_subvolume_path(image: &Image) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!(
+        "{}/subvolumes/{}",
+        get_minato_path()?, image.id.replace("/", "_")
+    ))
+}
+
+
+/// Get path to the init executable bound in the containers
+///
+/// It must be installed in the state directory, each root has its own
+pub fn get_tini_path() -> Result<String, Box<dyn std::error::Error>> {
+    let tini_path = format!("{}/tini", get_minato_path()?);
+    if !Path::new(&tini_path).exists() {
+        return Err(format!("tini not found, install it as {}", tini_path).into())
+    }
+    Ok(tini_path)
+}
+/// Get path to the host configuration file
+///
+/// In the root set with '--root' if any, so instances don't share it. Rootless, it's the user one, in '$XDG_CONFIG_HOME/minato'
+pub fn get_config_path() -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(root) = get_root() {
+        return Ok(format!("{}/minato.json", root))
+    }
+    if !utils::is_rootless() {
+        return Ok(String::from("/etc/minato/minato.json"))
+    }
+    match dirs::config_dir() {
+        Some(path) => Ok(format!("{}/minato/minato.json", path.display())),
+        None       => Err("error getting the user configuration directory, set XDG_CONFIG_HOME".into())
+    }
+}
+/// Get path to the image trust policy
+///
+/// In the root set with '--root' if any, like the configuration
+pub fn get_policy_path() -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(root) = get_root() {
+        return Ok(format!("{}/policy.json", root))
+    }
+    Ok(String::from("/etc/minato/policy.json"))
+}
+
+
+/// Get path to the directory of the containers
+pub fn get_containers_path() -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!("{}/containers", get_minato_path()?))
+}
+/// Check that a container id is a single path component, like `[A-Za-z0-9][A-Za-z0-9_.-]*`
+pub fn validate_container_id(container_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut chars = container_id.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+    if !valid {
+        return Err(format!("invalid container id '{}': it must match [A-Za-z0-9][A-Za-z0-9_.-]*", container_id).into())
+    }
+    Ok(())
+}
+/// Get path to container, from the container id
+pub fn get_container_path_with_str(container_id: &str) -> Result<String, Box<dyn std::error::Error>> {
+    validate_container_id(container_id)?;
+    Ok(format!(
+        "{}/{}",
+        get_containers_path()?, container_id
+    ))
+}
+/// Get path to container, from container object
+pub fn get_container_path(container: &Container) -> Result<String, Box<dyn std::error::Error>> {
+    get_container_path_with_str(container.id.as_str())
+}
+
+/// Get path to the pid file of the daemon
+pub fn get_pid_path() -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!("{}/pid", get_runtime_path()?))
+}
+/// Get path to socket
+pub fn get_socket_path(socket_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!(
+        "{}/{}",
+        get_runtime_path()?, socket_name
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+
+    use super::*;
+
+    #[test]
+    fn set_root_moves_every_path() {
+        let temp_dir = std::env::temp_dir().join(format!("minato-paths-{}", std::process::id()));
+        let root = temp_dir.join("root");
+        set_root(root.to_str().unwrap()).unwrap();
+
+        let root = root.canonicalize().unwrap().display().to_string();
+        assert_eq!(get_minato_path().unwrap(), root);
+        assert_eq!(get_pid_path().unwrap(), format!("{}/pid", root));
+        assert_eq!(get_container_path_with_str("web").unwrap(), format!("{}/containers/web", root));
+        assert_eq!(get_image_staging_path().unwrap(), format!("{}/images/.staging", root));
+
+        *ROOT.write().unwrap() = None;
+        remove_dir_all(&temp_dir).unwrap();
+    }
+
+    #[test]
+    fn container_ids_are_single_path_components() {
+        for id in ["web", "Web-1", "a1.b_c-d", "0"].iter() {
+            assert!(validate_container_id(id).is_ok(), "{}", id);
+        }
+        for id in ["", ".", "..", ".hidden", "-web", "../web", "a/b", "a b", "web\n"].iter() {
+            assert!(validate_container_id(id).is_err(), "{}", id);
+        }
+    }
+}
@@ -0,0 +1,523 @@
+use std::io;
+use nix::libc;
+
+use log::{debug, info};
+
+use crate::spec::{CapabilityType, Seccomp, SeccompAction, SeccompOperator, Syscall, SyscallArg};
+use crate::syscalls;
+
+
+// Classic BPF instructions, from linux/filter.h
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_ALU_AND_K: u16 = 0x54;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGT_K: u16 = 0x25;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+const BPF_MAXINSNS: usize = 4096;
+
+// Offsets in struct seccomp_data
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+const ARGS_OFFSET: u32 = 16;
+
+// Filter return values, from linux/seccomp.h
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_KILL_THREAD: u32 = 0x0000_0000;
+const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_DATA: u32 = 0x0000_ffff;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+/// x32 syscalls are made with the x86_64 architecture, and this bit set in their number
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Namespace flags of clone, which only CAP_SYS_ADMIN may use in the default profile
+const CLONE_NAMESPACE_FLAGS: u64 = (libc::CLONE_NEWNS | libc::CLONE_NEWUTS | libc::CLONE_NEWIPC | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID | libc::CLONE_NEWNET | libc::CLONE_NEWCGROUP) as u64;
+/// Personalities allowed by the default profile: Linux, with or without the 32 bit address space, and the query
+const PERSONALITIES: [u64; 5] = [0x0, 0x8, 0x20000, 0x20008, 0xffff_ffff];
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+/// Target of a conditional jump in the argument comparisons of a rule
+#[derive(Clone, Copy)]
+enum Jump {
+    /// The next instruction
+    Next,
+    /// The next comparison, the argument matched
+    Pass,
+    /// The end of the rule, the argument didn't match
+    Fail,
+}
+/// Instruction code, constant, and jump targets if true and false, of an argument comparison
+type Comparison = (u16, u32, Jump, Jump);
+
+/// Compile a seccomp profile and install it on the current process, it's inherited across execve
+///
+/// Without no_new_privileges, this requires CAP_SYS_ADMIN
+pub fn install(seccomp: &Seccomp) -> Result<(), Box<dyn std::error::Error>> {
+    info!("installing seccomp filter...");
+
+    let filter = compile(seccomp)?;
+    let program = SockFprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr(),
+    };
+    let result = unsafe {
+        libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER as libc::c_ulong, &program as *const SockFprog)
+    };
+    if result < 0 {
+        return Err(format!("cannot install seccomp filter: {}", io::Error::last_os_error()).into())
+    }
+
+    info!("installed seccomp filter ({} instructions).", filter.len());
+    Ok(())
+}
+
+/// Compile a seccomp profile to a BPF program
+///
+/// Only the native architecture is filtered, syscalls of other architectures kill the process.
+/// Rules are checked in order, the first one matching the syscall and its arguments applies.
+/// Syscalls unknown to this architecture are skipped
+fn compile(seccomp: &Seccomp) -> Result<Vec<SockFilter>, Box<dyn std::error::Error>> {
+    let arch = match AUDIT_ARCH {
+        Some(arch) => arch,
+        None       => return Err("seccomp is not supported on this architecture".into())
+    };
+    let default_action = action(seccomp.default_action, seccomp.default_errno_ret);
+
+    let mut filter = vec![
+        statement(BPF_LD_W_ABS, ARCH_OFFSET),
+        jump(BPF_JMP_JEQ_K, arch, 1, 0),
+        statement(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        statement(BPF_LD_W_ABS, NR_OFFSET),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    filter.extend_from_slice(&[
+        jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1),
+        statement(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+    ]);
+
+    for rule in &seccomp.syscalls {
+        let body = rule_body(rule, action(rule.action, rule.errno_ret))?;
+
+        for name in &rule.names {
+            let number = match syscalls::number(name) {
+                Some(number) => number,
+                None         => {
+                    debug!("skipping unknown syscall '{}'...", name);
+                    continue
+                }
+            };
+            filter.push(jump(BPF_JMP_JEQ_K, number as u32, 0, body.len() as u8));
+            filter.extend_from_slice(&body);
+        }
+    }
+    filter.push(statement(BPF_RET_K, default_action));
+
+    if filter.len() > BPF_MAXINSNS {
+        return Err(format!("seccomp filter is too long: {} instructions, at most {}", filter.len(), BPF_MAXINSNS).into())
+    }
+    Ok(filter)
+}
+
+/// Instructions run once the syscall number of a rule matched: the argument comparisons and the action
+///
+/// When an argument doesn't match, the syscall number is loaded again for the next rules
+fn rule_body(rule: &Syscall, rule_action: u32) -> Result<Vec<SockFilter>, Box<dyn std::error::Error>> {
+    let mut comparisons: Vec<Comparison> = Vec::new();
+    let mut ends = Vec::new();
+    for arg in &rule.args {
+        comparisons.extend(comparison(arg)?);
+        ends.push(comparisons.len());
+    }
+
+    let fail = comparisons.len() + 1;
+    let mut body = Vec::new();
+    for (i, (code, k, jt, jf)) in comparisons.into_iter().enumerate() {
+        let pass = *ends.iter().find(|end| **end > i).unwrap();
+        let offset = |target: Jump| match target {
+            Jump::Next => 0,
+            Jump::Pass => (pass - i - 1) as u8,
+            Jump::Fail => (fail - i - 1) as u8,
+        };
+        body.push(jump(code, k, offset(jt), offset(jf)));
+    }
+    body.push(statement(BPF_RET_K, rule_action));
+    if !rule.args.is_empty() {
+        body.push(statement(BPF_LD_W_ABS, NR_OFFSET));
+    }
+
+    Ok(body)
+}
+
+/// Instructions comparing a syscall argument, as two 32 bit halves
+///
+/// For SCMP_CMP_MASKED_EQ, 'value' is the mask and 'valueTwo' the value compared
+fn comparison(arg: &SyscallArg) -> Result<Vec<Comparison>, Box<dyn std::error::Error>> {
+    if arg.index > 5 {
+        return Err(format!("invalid seccomp argument index {}, syscalls have 6 arguments", arg.index).into())
+    }
+    let offset = ARGS_OFFSET + arg.index * 8;
+    let (low, high) = if cfg!(target_endian = "little") { (offset, offset + 4) } else { (offset + 4, offset) };
+    let load_high = (BPF_LD_W_ABS, high, Jump::Next, Jump::Next);
+    let load_low = (BPF_LD_W_ABS, low, Jump::Next, Jump::Next);
+    let (value_high, value_low) = ((arg.value >> 32) as u32, arg.value as u32);
+
+    let instructions = match arg.op {
+        SeccompOperator::SCMP_CMP_EQ => vec![
+            load_high, (BPF_JMP_JEQ_K, value_high, Jump::Next, Jump::Fail),
+            load_low,  (BPF_JMP_JEQ_K, value_low, Jump::Pass, Jump::Fail),
+        ],
+        SeccompOperator::SCMP_CMP_NE => vec![
+            load_high, (BPF_JMP_JEQ_K, value_high, Jump::Next, Jump::Pass),
+            load_low,  (BPF_JMP_JEQ_K, value_low, Jump::Fail, Jump::Pass),
+        ],
+        SeccompOperator::SCMP_CMP_GT => vec![
+            load_high, (BPF_JMP_JGT_K, value_high, Jump::Pass, Jump::Next),
+                       (BPF_JMP_JEQ_K, value_high, Jump::Next, Jump::Fail),
+            load_low,  (BPF_JMP_JGT_K, value_low, Jump::Pass, Jump::Fail),
+        ],
+        SeccompOperator::SCMP_CMP_GE => vec![
+            load_high, (BPF_JMP_JGT_K, value_high, Jump::Pass, Jump::Next),
+                       (BPF_JMP_JEQ_K, value_high, Jump::Next, Jump::Fail),
+            load_low,  (BPF_JMP_JGE_K, value_low, Jump::Pass, Jump::Fail),
+        ],
+        SeccompOperator::SCMP_CMP_LT => vec![
+            load_high, (BPF_JMP_JGE_K, value_high, Jump::Next, Jump::Pass),
+                       (BPF_JMP_JEQ_K, value_high, Jump::Next, Jump::Fail),
+            load_low,  (BPF_JMP_JGE_K, value_low, Jump::Fail, Jump::Pass),
+        ],
+        SeccompOperator::SCMP_CMP_LE => vec![
+            load_high, (BPF_JMP_JGE_K, value_high, Jump::Next, Jump::Pass),
+                       (BPF_JMP_JEQ_K, value_high, Jump::Next, Jump::Fail),
+            load_low,  (BPF_JMP_JGT_K, value_low, Jump::Fail, Jump::Pass),
+        ],
+        SeccompOperator::SCMP_CMP_MASKED_EQ => vec![
+            load_high, (BPF_ALU_AND_K, value_high, Jump::Next, Jump::Next),
+                       (BPF_JMP_JEQ_K, (arg.value_two >> 32) as u32, Jump::Next, Jump::Fail),
+            load_low,  (BPF_ALU_AND_K, value_low, Jump::Next, Jump::Next),
+                       (BPF_JMP_JEQ_K, arg.value_two as u32, Jump::Pass, Jump::Fail),
+        ],
+    };
+    Ok(instructions)
+}
+
+/// Filter return value of an action
+///
+/// SCMP_ACT_ERRNO and SCMP_ACT_TRACE return EPERM, unless another errno is given
+fn action(action: SeccompAction, errno: Option<u32>) -> u32 {
+    let errno = errno.unwrap_or(libc::EPERM as u32) & SECCOMP_RET_DATA;
+    match action {
+        SeccompAction::SCMP_ACT_KILL         => SECCOMP_RET_KILL_THREAD,
+        SeccompAction::SCMP_ACT_KILL_THREAD  => SECCOMP_RET_KILL_THREAD,
+        SeccompAction::SCMP_ACT_KILL_PROCESS => SECCOMP_RET_KILL_PROCESS,
+        SeccompAction::SCMP_ACT_TRAP         => SECCOMP_RET_TRAP,
+        SeccompAction::SCMP_ACT_ERRNO        => SECCOMP_RET_ERRNO | errno,
+        SeccompAction::SCMP_ACT_TRACE        => SECCOMP_RET_TRACE | errno,
+        SeccompAction::SCMP_ACT_LOG          => SECCOMP_RET_LOG,
+        SeccompAction::SCMP_ACT_ALLOW        => SECCOMP_RET_ALLOW,
+    }
+}
+
+fn statement(code: u16, k: u32) -> SockFilter {
+    SockFilter { code, jt: 0, jf: 0, k }
+}
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter { code, jt, jf, k }
+}
+
+/// The default profile of containers created from an image, similar to Docker's
+///
+/// Syscalls are denied with EPERM unless allowed. The ones needing a capability are allowed when it's in the
+/// bounding set, namespaces can only be created with CAP_SYS_ADMIN
+pub fn default_profile(bounding: &[CapabilityType]) -> Seccomp {
+    let allow = |names: &[&str]| Syscall {
+        names: names.iter().map(|name| name.to_string()).collect(),
+        action: SeccompAction::SCMP_ACT_ALLOW,
+        errno_ret: None,
+        args: Vec::new(),
+    };
+
+    let mut syscalls = vec![allow(&ALLOWED_SYSCALLS)];
+    for personality in PERSONALITIES.iter() {
+        syscalls.push(Syscall {
+            args: vec![SyscallArg { index: 0, value: *personality, value_two: 0, op: SeccompOperator::SCMP_CMP_EQ }],
+            ..allow(&["personality"])
+        });
+    }
+    for (capability, names) in CAPABILITY_SYSCALLS.iter() {
+        if bounding.contains(capability) {
+            syscalls.push(allow(names));
+        }
+    }
+    if !bounding.contains(&CapabilityType::CAP_SYS_ADMIN) {
+        syscalls.push(Syscall {
+            args: vec![SyscallArg { index: 0, value: CLONE_NAMESPACE_FLAGS, value_two: 0, op: SeccompOperator::SCMP_CMP_MASKED_EQ }],
+            ..allow(&["clone"])
+        });
+        // Makes the C library fall back to clone, whose flags can be checked
+        syscalls.push(Syscall {
+            action: SeccompAction::SCMP_ACT_ERRNO,
+            errno_ret: Some(libc::ENOSYS as u32),
+            ..allow(&["clone3"])
+        });
+    }
+
+    Seccomp {
+        default_action: SeccompAction::SCMP_ACT_ERRNO,
+        default_errno_ret: None,
+        architectures: vec![String::from("SCMP_ARCH_X86_64"), String::from("SCMP_ARCH_AARCH64")],
+        syscalls,
+    }
+}
+
+/// Syscalls allowed by the default profile, whatever the capabilities
+const ALLOWED_SYSCALLS: [&str; 353] = [
+    "accept", "accept4", "access", "adjtimex", "alarm", "arch_prctl", "bind", "brk", "cachestat", "capget",
+    "capset", "chdir", "chmod", "chown", "chown32", "clock_adjtime", "clock_adjtime64", "clock_getres",
+    "clock_getres_time64", "clock_gettime", "clock_gettime64", "clock_nanosleep", "clock_nanosleep_time64",
+    "close", "close_range", "connect", "copy_file_range", "creat", "dup", "dup2", "dup3", "epoll_create",
+    "epoll_create1", "epoll_ctl", "epoll_ctl_old", "epoll_pwait", "epoll_pwait2", "epoll_wait", "epoll_wait_old",
+    "eventfd", "eventfd2", "execve", "execveat", "exit", "exit_group", "faccessat", "faccessat2", "fadvise64",
+    "fadvise64_64", "fallocate", "fanotify_mark", "fchdir", "fchmod", "fchmodat", "fchmodat2", "fchown",
+    "fchown32", "fchownat", "fcntl", "fcntl64", "fdatasync", "fgetxattr", "flistxattr", "flock", "fork",
+    "fremovexattr", "fsetxattr", "fstat", "fstat64", "fstatat64", "fstatfs", "fstatfs64", "fsync", "ftruncate",
+    "ftruncate64", "futex", "futex_requeue", "futex_time64", "futex_wait", "futex_waitv", "futex_wake",
+    "futimesat", "get_robust_list", "get_thread_area", "getcpu", "getcwd", "getdents", "getdents64", "getegid",
+    "getegid32", "geteuid", "geteuid32", "getgid", "getgid32", "getgroups", "getgroups32", "getitimer",
+    "getpeername", "getpgid", "getpgrp", "getpid", "getppid", "getpriority", "getrandom", "getresgid",
+    "getresgid32", "getresuid", "getresuid32", "getrlimit", "getrusage", "getsid", "getsockname", "getsockopt",
+    "gettid", "gettimeofday", "getuid", "getuid32", "getxattr", "inotify_add_watch", "inotify_init",
+    "inotify_init1", "inotify_rm_watch", "io_cancel", "io_destroy", "io_getevents", "io_pgetevents",
+    "io_pgetevents_time64", "io_setup", "io_submit", "ioctl", "ioprio_get", "ioprio_set", "ipc", "kill",
+    "landlock_add_rule", "landlock_create_ruleset", "landlock_restrict_self", "lchown", "lchown32", "lgetxattr",
+    "link", "linkat", "listen", "listxattr", "llistxattr", "lremovexattr", "lseek", "lsetxattr", "lstat",
+    "lstat64", "madvise", "map_shadow_stack", "membarrier", "memfd_create", "memfd_secret", "mincore", "mkdir",
+    "mkdirat", "mknod", "mknodat", "mlock", "mlock2", "mlockall", "mmap", "mmap2", "modify_ldt", "mprotect",
+    "mq_getsetattr", "mq_notify", "mq_open", "mq_timedreceive", "mq_timedreceive_time64", "mq_timedsend",
+    "mq_timedsend_time64", "mq_unlink", "mremap", "msgctl", "msgget", "msgrcv", "msgsnd", "msync", "munlock",
+    "munlockall", "munmap", "name_to_handle_at", "nanosleep", "newfstatat", "open", "openat", "openat2",
+    "pause", "pidfd_open", "pidfd_send_signal", "pipe", "pipe2", "pkey_alloc", "pkey_free", "pkey_mprotect",
+    "poll", "ppoll", "ppoll_time64", "prctl", "pread64", "preadv", "preadv2", "prlimit64", "process_mrelease",
+    "pselect6", "pselect6_time64", "pwrite64", "pwritev", "pwritev2", "read", "readahead", "readlink",
+    "readlinkat", "readv", "recv", "recvfrom", "recvmmsg", "recvmmsg_time64", "recvmsg", "remap_file_pages",
+    "removexattr", "rename", "renameat", "renameat2", "restart_syscall", "rmdir", "rseq", "rt_sigaction",
+    "rt_sigpending", "rt_sigprocmask", "rt_sigqueueinfo", "rt_sigreturn", "rt_sigsuspend", "rt_sigtimedwait",
+    "rt_sigtimedwait_time64", "rt_tgsigqueueinfo", "sched_get_priority_max", "sched_get_priority_min",
+    "sched_getaffinity", "sched_getattr", "sched_getparam", "sched_getscheduler", "sched_rr_get_interval",
+    "sched_rr_get_interval_time64", "sched_setaffinity", "sched_setattr", "sched_setparam",
+    "sched_setscheduler", "sched_yield", "seccomp", "select", "semctl", "semget", "semop", "semtimedop",
+    "semtimedop_time64", "send", "sendfile", "sendfile64", "sendmmsg", "sendmsg", "sendto", "set_robust_list",
+    "set_thread_area", "set_tid_address", "setfsgid", "setfsgid32", "setfsuid", "setfsuid32", "setgid",
+    "setgid32", "setgroups", "setgroups32", "setitimer", "setpgid", "setpriority", "setregid", "setregid32",
+    "setresgid", "setresgid32", "setresuid", "setresuid32", "setreuid", "setreuid32", "setrlimit", "setsid",
+    "setsockopt", "setuid", "setuid32", "setxattr", "shmat", "shmctl", "shmdt", "shmget", "shutdown",
+    "sigaltstack", "signalfd", "signalfd4", "sigprocmask", "sigreturn", "socket", "socketcall", "socketpair",
+    "splice", "stat", "stat64", "statfs", "statfs64", "statx", "symlink", "symlinkat", "sync",
+    "sync_file_range", "syncfs", "sysinfo", "tee", "tgkill", "time", "timer_create", "timer_delete",
+    "timer_getoverrun", "timer_gettime", "timer_gettime64", "timer_settime", "timer_settime64",
+    "timerfd_create", "timerfd_gettime", "timerfd_gettime64", "timerfd_settime", "timerfd_settime64", "times",
+    "tkill", "truncate", "truncate64", "ugetrlimit", "umask", "uname", "unlink", "unlinkat", "utime",
+    "utimensat", "utimensat_time64", "utimes", "vfork", "vmsplice", "wait4", "waitid", "waitpid", "write",
+    "writev",
+];
+
+/// Syscalls allowed by the default profile when the bounding set has a capability
+const CAPABILITY_SYSCALLS: [(CapabilityType, &[&str]); 14] = [
+    (CapabilityType::CAP_DAC_READ_SEARCH, &["open_by_handle_at"]),
+    (CapabilityType::CAP_SYS_ADMIN,       &["bpf", "clone", "clone3", "fanotify_init", "fsconfig", "fsmount",
+                                            "fsopen", "fspick", "lookup_dcookie", "mount", "mount_setattr",
+                                            "move_mount", "open_tree", "perf_event_open", "quotactl", "quotactl_fd",
+                                            "setdomainname", "sethostname", "setns", "syslog", "umount", "umount2",
+                                            "unshare"]),
+    (CapabilityType::CAP_SYS_BOOT,        &["reboot"]),
+    (CapabilityType::CAP_SYS_CHROOT,      &["chroot"]),
+    (CapabilityType::CAP_SYS_MODULE,      &["delete_module", "finit_module", "init_module"]),
+    (CapabilityType::CAP_SYS_PACCT,       &["acct"]),
+    (CapabilityType::CAP_SYS_PTRACE,      &["kcmp", "pidfd_getfd", "process_madvise", "process_vm_readv",
+                                            "process_vm_writev", "ptrace"]),
+    (CapabilityType::CAP_SYS_RAWIO,       &["ioperm", "iopl"]),
+    (CapabilityType::CAP_SYS_TIME,        &["clock_settime", "clock_settime64", "settimeofday", "stime"]),
+    (CapabilityType::CAP_SYS_TTY_CONFIG,  &["vhangup"]),
+    (CapabilityType::CAP_SYS_NICE,        &["get_mempolicy", "mbind", "set_mempolicy", "set_mempolicy_home_node"]),
+    (CapabilityType::CAP_SYSLOG,          &["syslog"]),
+    (CapabilityType::CAP_BPF,             &["bpf"]),
+    (CapabilityType::CAP_PERFMON,         &["perf_event_open"]),
+];
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    use super::*;
+
+    const DENIED: u32 = SECCOMP_RET_ERRNO | 1;
+
+    fn profile(syscalls: Vec<Syscall>) -> Seccomp {
+        Seccomp {
+            default_action: SeccompAction::SCMP_ACT_ALLOW,
+            default_errno_ret: None,
+            architectures: Vec::new(),
+            syscalls,
+        }
+    }
+    fn rule(names: &[&str], errno: u32, args: Vec<SyscallArg>) -> Syscall {
+        Syscall {
+            names: names.iter().map(|name| name.to_string()).collect(),
+            action: SeccompAction::SCMP_ACT_ERRNO,
+            errno_ret: Some(errno),
+            args,
+        }
+    }
+    fn arg(index: u32, op: SeccompOperator, value: u64, value_two: u64) -> SyscallArg {
+        SyscallArg { index, value, value_two, op }
+    }
+
+    /// Run a filter on a syscall, failing if a jump leaves the program
+    fn run(filter: &[SockFilter], name: &str, args: [u64; 6]) -> u32 {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(syscalls::number(name).unwrap() as u32).to_ne_bytes());
+        data.extend_from_slice(&AUDIT_ARCH.unwrap().to_ne_bytes());
+        data.extend_from_slice(&0u64.to_ne_bytes());
+        for arg in args.iter() {
+            data.extend_from_slice(&arg.to_ne_bytes());
+        }
+
+        let (mut pc, mut accumulator) = (0, 0);
+        loop {
+            let instruction = filter[pc];
+            pc += 1;
+            let taken = match instruction.code {
+                BPF_LD_W_ABS  => {
+                    let offset = instruction.k as usize;
+                    accumulator = u32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap());
+                    continue
+                },
+                BPF_ALU_AND_K => {
+                    accumulator &= instruction.k;
+                    continue
+                },
+                BPF_RET_K     => return instruction.k,
+                BPF_JMP_JEQ_K => accumulator == instruction.k,
+                BPF_JMP_JGT_K => accumulator > instruction.k,
+                BPF_JMP_JGE_K => accumulator >= instruction.k,
+                code          => panic!("unexpected instruction {:#x}", code)
+            };
+            pc += if taken { instruction.jt } else { instruction.jf } as usize;
+        }
+    }
+
+    #[test]
+    fn comparisons_match_on_both_halves() {
+        let value: u64 = 0x1_0000_0005;
+        let args = [0, 4, 5, 6, 0xffff_ffff, value - 1, value, value + 1, 0x1_ffff_ffff, 0x2_0000_0000, u64::MAX];
+        let operators = [
+            SeccompOperator::SCMP_CMP_EQ, SeccompOperator::SCMP_CMP_NE, SeccompOperator::SCMP_CMP_GT,
+            SeccompOperator::SCMP_CMP_GE, SeccompOperator::SCMP_CMP_LT, SeccompOperator::SCMP_CMP_LE,
+        ];
+
+        for op in operators.iter() {
+            let filter = compile(&profile(vec![rule(&["getppid"], 1, vec![arg(2, *op, value, 0)])])).unwrap();
+            for a in args.iter() {
+                let expected = match op {
+                    SeccompOperator::SCMP_CMP_EQ => *a == value,
+                    SeccompOperator::SCMP_CMP_NE => *a != value,
+                    SeccompOperator::SCMP_CMP_GT => *a > value,
+                    SeccompOperator::SCMP_CMP_GE => *a >= value,
+                    SeccompOperator::SCMP_CMP_LT => *a < value,
+                    _                            => *a <= value,
+                };
+                let denied = run(&filter, "getppid", [0, 0, *a, 0, 0, 0]) == DENIED;
+                assert_eq!(denied, expected, "{:?} {:#x}", op, a);
+            }
+        }
+    }
+
+    #[test]
+    fn masked_eq_compares_the_masked_argument() {
+        let (mask, value) = (0x0000_00ff_0000_00f0, 0x0000_0012_0000_0030);
+        let filter = compile(&profile(vec![rule(&["getppid"], 1, vec![arg(0, SeccompOperator::SCMP_CMP_MASKED_EQ, mask, value)])])).unwrap();
+        for a in [0, value, value | 0xff00_ff00_ff00_ff0f, 0x0000_0012_0000_0031, 0x0000_0013_0000_0030, 0x30].iter() {
+            let denied = run(&filter, "getppid", [*a, 0, 0, 0, 0, 0]) == DENIED;
+            assert_eq!(denied, a & mask == value, "{:#x}", a);
+        }
+    }
+
+    #[test]
+    fn rules_apply_in_order_once_every_argument_matches() {
+        let filter = compile(&profile(vec![
+            rule(&["getppid"], 1, vec![arg(0, SeccompOperator::SCMP_CMP_GE, 10, 0), arg(1, SeccompOperator::SCMP_CMP_LE, 20, 0)]),
+            rule(&["getppid"], 2, Vec::new()),
+        ])).unwrap();
+        assert_eq!(run(&filter, "getppid", [10, 20, 0, 0, 0, 0]), DENIED);
+        assert_eq!(run(&filter, "getppid", [9, 20, 0, 0, 0, 0]), SECCOMP_RET_ERRNO | 2);
+        assert_eq!(run(&filter, "getppid", [10, 21, 0, 0, 0, 0]), SECCOMP_RET_ERRNO | 2);
+        assert_eq!(run(&filter, "getpid", [10, 20, 0, 0, 0, 0]), SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn unknown_syscalls_are_skipped() {
+        let known = compile(&profile(vec![rule(&["getppid"], 1, Vec::new())])).unwrap();
+        let filter = compile(&profile(vec![rule(&["not_a_syscall", "getppid"], 1, Vec::new())])).unwrap();
+        assert_eq!(filter.len(), known.len());
+        assert_eq!(run(&filter, "getppid", [0; 6]), DENIED);
+    }
+
+    #[test]
+    fn filters_longer_than_the_kernel_limit_are_rejected() {
+        assert!(compile(&profile(vec![rule(&["getppid"; 2000], 1, Vec::new())])).is_ok());
+        assert!(compile(&profile(vec![rule(&["getppid"; 2100], 1, Vec::new())])).is_err());
+    }
+
+    #[test]
+    fn denied_syscalls_fail_with_their_errno() {
+        let seccomp = profile(vec![
+            rule(&["getppid"], libc::EPERM as u32, Vec::new()),
+            rule(&["kill"], libc::EACCES as u32, vec![arg(1, SeccompOperator::SCMP_CMP_EQ, 0, 0)]),
+        ]);
+        let filter = compile(&seccomp).unwrap();
+        let program = SockFprog { len: filter.len() as u16, filter: filter.as_ptr() };
+
+        // The child doesn't allocate, the other threads of the tests may hold the allocator lock
+        match fork().unwrap() {
+            ForkResult::Child => unsafe {
+                let errno = || *libc::__errno_location();
+                let installed = libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0
+                    && libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER as libc::c_ulong, &program as *const SockFprog) == 0;
+                let getppid_denied = libc::syscall(libc::SYS_getppid) == -1 && errno() == libc::EPERM;
+                let kill_denied = libc::syscall(libc::SYS_kill, libc::getpid(), 0) == -1 && errno() == libc::EACCES;
+                let kill_allowed = libc::syscall(libc::SYS_kill, libc::getpid(), libc::SIGCHLD) == 0;
+                libc::_exit(if installed && getppid_denied && kill_denied && kill_allowed { 0 } else { 1 });
+            },
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+}
@@ -3,8 +3,15 @@ use std::path::Path;
 use std::collections::HashMap;
 // use failure::Error;
 use serde_json;
-use std::fs::File;
+use std::fs::{File, read_to_string};
 use std::io::BufReader;
+use structopt::StructOpt;
+
+use crate::utils;
+
+
+/// Period of the cpu quota set with '--cpus', in microseconds
+const CPUS_PERIOD: u64 = 100_000;
 
 fn is_false(b: &bool) -> bool {
     !b
@@ -30,7 +37,13 @@ pub struct Spec {
 }
 impl Spec {
     /// Create a new spec object
+    ///
+    /// Uses the 'config.json' in the working directory, or the one built in the executable
     pub fn new() -> Result<Spec, Box<dyn std::error::Error>> {
+        if !Path::new("config.json").exists() {
+            let spec: Spec = serde_json::from_str(include_str!("../config.json"))?;
+            return Ok(spec)
+        }
         let spec = Spec::load("config.json")?;
         Ok(spec)
     }
@@ -52,6 +65,232 @@ impl Spec {
         serde_json::to_writer(&mut file, &self)?;
         Ok(())
     }
+
+    /// Apply the process overrides passed on the command line
+    ///
+    /// Environment variables replace the ones with the same name, the env file is applied before '-e'.
+    /// The entrypoint and the command replace the process arguments, like the image entrypoint and command would
+    pub fn apply_overrides(&mut self, overrides: &Overrides) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(env_file) = &overrides.env_file {
+            let content = read_to_string(env_file)
+                .map_err(|e| format!("cannot read env file '{}': {}", env_file, e))?;
+            for line in content.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue
+                }
+                self.set_env(line)?;
+            }
+        }
+        for env in &overrides.env {
+            self.set_env(env)?;
+        }
+
+        if let Some(workdir) = &overrides.workdir {
+            if !workdir.starts_with('/') {
+                return Err(format!("working directory '{}' is not an absolute path", workdir).into())
+            }
+            self.process.cwd = workdir.clone();
+        }
+
+        if let Some(user) = &overrides.user {
+            let mut ids = user.splitn(2, ':');
+            let uid = ids.next().unwrap_or_default();
+            self.process.user.uid = uid.parse()
+                .map_err(|_| format!("invalid uid '{}', expected uid[:gid]", uid))?;
+            if let Some(gid) = ids.next() {
+                self.process.user.gid = gid.parse()
+                    .map_err(|_| format!("invalid gid '{}', expected uid[:gid]", gid))?;
+            }
+            self.process.user.additional_gids = Vec::new();
+        }
+
+        if let Some(hostname) = &overrides.hostname {
+            self.hostname = hostname.clone();
+        }
+
+        if let Some(entrypoint) = &overrides.entrypoint {
+            self.process.args = vec![entrypoint.clone()];
+            self.process.args.extend(overrides.command.iter().cloned());
+        } else if !overrides.command.is_empty() {
+            self.process.args = overrides.command.clone();
+        }
+
+        for ulimit in &overrides.ulimit {
+            self.set_rlimit(ulimit)?;
+        }
+        if let Some(oom_score_adj) = overrides.oom_score_adj {
+            if !(-1000..=1000).contains(&oom_score_adj) {
+                return Err(format!("invalid OOM score adjustment {}, expected -1000 to 1000", oom_score_adj).into())
+            }
+            self.process.oom_score_adj = Some(oom_score_adj);
+        }
+
+        if overrides.memory.is_some() || overrides.cpus.is_some() || overrides.pids_limit.is_some() {
+            let linux = self.linux.as_mut().ok_or("missing linux section")?;
+            let resources = linux.resources.get_or_insert_with(Resources::default);
+            if let Some(memory) = &overrides.memory {
+                resources.memory.get_or_insert_with(MemoryCgroup::default).limit = utils::parse_size(memory)?;
+            }
+            if let Some(cpus) = overrides.cpus {
+                if cpus.is_nan() || cpus <= 0.0 {
+                    return Err(format!("invalid number of cpus {}, expected a positive number", cpus).into())
+                }
+                let cpu = resources.cpu.get_or_insert_with(CpuCgroup::default);
+                cpu.period = CPUS_PERIOD;
+                cpu.quota = (cpus * CPUS_PERIOD as f64).round() as i64;
+            }
+            if let Some(pids_limit) = overrides.pids_limit {
+                resources.pids.get_or_insert_with(Pids::default).limit = pids_limit;
+            }
+        }
+
+        // Dropped first, so '--cap-drop ALL --cap-add X' keeps only X
+        if !overrides.cap_drop.is_empty() || !overrides.cap_add.is_empty() {
+            if self.process.capabilities.is_none() {
+                self.process.capabilities = Some(Spec::default_capabilities()?);
+            }
+            if let Some(capabilities) = &mut self.process.capabilities {
+                for name in &overrides.cap_drop {
+                    capabilities.remove(&CapabilityType::parse(name)?);
+                }
+                for name in &overrides.cap_add {
+                    capabilities.add(&CapabilityType::parse(name)?);
+                }
+            }
+        }
+
+        Ok(())
+    }
+    /// Get the capabilities of the default config.json, which the capability flags start from when a spec has none
+    fn default_capabilities() -> Result<Capability, Box<dyn std::error::Error>> {
+        let spec: Spec = serde_json::from_str(include_str!("../config.json"))?;
+        Ok(spec.process.capabilities.unwrap_or_default())
+    }
+    /// Set an environment variable, given as 'KEY=VALUE'
+    ///
+    /// A bare 'KEY' takes its value from the current environment, and is ignored if it's not set there
+    fn set_env(&mut self, env: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let variable = match env.find('=') {
+            Some(_) => env.to_string(),
+            None    => match std::env::var(env) {
+                Ok(value) => format!("{}={}", env, value),
+                Err(_)    => return Ok(())
+            }
+        };
+        let key_len = variable.find('=').unwrap();
+        if key_len == 0 {
+            return Err(format!("invalid environment variable '{}'", env).into())
+        }
+
+        let key = &variable[..=key_len];
+        self.process.env.retain(|e| !e.starts_with(key));
+        self.process.env.push(variable);
+        Ok(())
+    }
+    /// Set a resource limit, given as 'type=soft[:hard]'
+    ///
+    /// The hard limit defaults to the soft one, 'unlimited' or -1 means no limit
+    fn set_rlimit(&mut self, ulimit: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let invalid = || format!("invalid resource limit '{}', expected type=soft[:hard]", ulimit);
+        let parse = |limit: &str| match limit {
+            "unlimited" | "-1" => Ok(u64::MAX),
+            limit              => limit.parse::<u64>().map_err(|_| invalid())
+        };
+
+        let mut parts = ulimit.splitn(2, '=');
+        let typ = RlimitType::parse(parts.next().unwrap_or_default())?;
+        let mut limits = parts.next().ok_or_else(invalid)?.splitn(2, ':');
+        let soft = parse(limits.next().unwrap_or_default())?;
+        let hard = match limits.next() {
+            Some(hard) => parse(hard)?,
+            None       => soft
+        };
+        if soft > hard {
+            return Err(format!("invalid resource limit '{}', the soft limit is above the hard one", ulimit).into())
+        }
+
+        self.process.rlimits.retain(|rlimit| rlimit.typ != typ);
+        self.process.rlimits.push(Rlimit { typ, soft, hard });
+        Ok(())
+    }
+}
+
+/// Process settings that can be overridden when creating or running a container
+#[derive(Debug, Default, StructOpt)]
+pub struct Overrides {
+    #[structopt(name = "env",
+        about = "Set an environment variable (KEY=VALUE, or KEY to copy it from the current environment)",
+        short = "e", long = "env",
+        number_of_values = 1)]
+    pub env: Vec<String>,
+
+    #[structopt(name = "env-file",
+        about = "Read environment variables from a file, one per line",
+        long = "env-file")]
+    pub env_file: Option<String>,
+
+    #[structopt(name = "workdir",
+        about = "Working directory of the process, inside the container",
+        short = "w", long = "workdir")]
+    pub workdir: Option<String>,
+
+    #[structopt(name = "user",
+        about = "User and group ids of the process (uid[:gid])",
+        short = "u", long = "user")]
+    pub user: Option<String>,
+
+    #[structopt(name = "hostname",
+        about = "Container hostname",
+        long = "hostname")]
+    pub hostname: Option<String>,
+
+    #[structopt(name = "entrypoint",
+        about = "Executable to run instead of the default one",
+        long = "entrypoint")]
+    pub entrypoint: Option<String>,
+
+    #[structopt(name = "cap-add",
+        about = "Give the process a capability (e.g. NET_ADMIN), or ALL of them",
+        long = "cap-add",
+        number_of_values = 1)]
+    pub cap_add: Vec<String>,
+
+    #[structopt(name = "cap-drop",
+        about = "Take a capability from the process (e.g. KILL), or ALL of them",
+        long = "cap-drop",
+        number_of_values = 1)]
+    pub cap_drop: Vec<String>,
+
+    #[structopt(name = "ulimit",
+        about = "Set a resource limit of the process (e.g. nofile=1024:2048, or core=unlimited)",
+        long = "ulimit",
+        number_of_values = 1)]
+    pub ulimit: Vec<String>,
+
+    #[structopt(name = "oom-score-adj",
+        about = "Adjust the OOM killer score of the process, from -1000 to 1000",
+        long = "oom-score-adj",
+        allow_hyphen_values = true)]
+    pub oom_score_adj: Option<i64>,
+
+    #[structopt(name = "memory",
+        about = "Memory limit of the container (e.g. 512m, 2g)",
+        short = "m", long = "memory")]
+    pub memory: Option<String>,
+
+    #[structopt(name = "cpus",
+        about = "Number of cpus the container can use, as a cpu time quota (e.g. 1.5)",
+        long = "cpus")]
+    pub cpus: Option<f64>,
+
+    #[structopt(name = "pids-limit",
+        about = "Maximum number of processes in the container, -1 for no limit",
+        long = "pids-limit",
+        allow_hyphen_values = true)]
+    pub pids_limit: Option<i64>,
+
+    #[structopt(name = "command", about = "Command to run, and its arguments")]
+    pub command: Vec<String>,
 }
 
 
@@ -96,8 +335,8 @@ pub struct Process {
     pub apparmor_profile: String,
     #[serde(default, rename = "noNewPrivileges")]
     pub no_new_privileges: bool,
-    #[serde(default, rename = "oomScoreAdj")]
-    pub oom_score_adj: i64,
+    #[serde(default, rename = "oomScoreAdj", skip_serializing_if = "Option::is_none")]
+    pub oom_score_adj: Option<i64>,
     #[serde(default, rename = "selinuxLabel")]
     pub selinux_label: String,
 }
@@ -112,15 +351,45 @@ pub struct ConsoleSize {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Rlimit {
-    #[serde(default, rename = "type")]
-    pub typ: String,
+    #[serde(rename = "type")]
+    pub typ: RlimitType,
     #[serde(default)]
     pub soft: u64,
     #[serde(default)]
     pub hard: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RlimitType {
+    RLIMIT_AS,
+    RLIMIT_CORE,
+    RLIMIT_CPU,
+    RLIMIT_DATA,
+    RLIMIT_FSIZE,
+    RLIMIT_LOCKS,
+    RLIMIT_MEMLOCK,
+    RLIMIT_MSGQUEUE,
+    RLIMIT_NICE,
+    RLIMIT_NOFILE,
+    RLIMIT_NPROC,
+    RLIMIT_RSS,
+    RLIMIT_RTPRIO,
+    RLIMIT_RTTIME,
+    RLIMIT_SIGPENDING,
+    RLIMIT_STACK,
+}
+impl RlimitType {
+    /// Parse a resource limit name, with or without the 'RLIMIT_' prefix
+    pub fn parse(name: &str) -> Result<RlimitType, Box<dyn std::error::Error>> {
+        let name = name.to_uppercase();
+        let name = if name.starts_with("RLIMIT_") { name } else { format!("RLIMIT_{}", name) };
+        let typ = serde_json::from_value(serde_json::Value::String(name.clone()))
+            .map_err(|_| format!("unknown resource limit '{}'", name))?;
+        Ok(typ)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Capability {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub effective: Vec<CapabilityType>,
@@ -133,8 +402,26 @@ pub struct Capability {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub ambient: Vec<CapabilityType>,
 }
+impl Capability {
+    /// Add capabilities to the bounding, effective, inheritable and permitted sets
+    fn add(&mut self, capabilities: &[CapabilityType]) {
+        for set in [&mut self.bounding, &mut self.effective, &mut self.inheritable, &mut self.permitted] {
+            for capability in capabilities {
+                if !set.contains(capability) {
+                    set.push(*capability);
+                }
+            }
+        }
+    }
+    /// Remove capabilities from every set
+    fn remove(&mut self, capabilities: &[CapabilityType]) {
+        for set in [&mut self.effective, &mut self.bounding, &mut self.inheritable, &mut self.permitted, &mut self.ambient] {
+            set.retain(|capability| !capabilities.contains(capability));
+        }
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 #[repr(u8)]
 pub enum CapabilityType {
     CAP_CHOWN,
@@ -172,9 +459,72 @@ pub enum CapabilityType {
     CAP_MAC_OVERRIDE,
     CAP_MAC_ADMIN,
     CAP_SYSLOG,
-    CAP_WAKE_ALERM,
+    #[serde(alias = "CAP_WAKE_ALERM")]
+    CAP_WAKE_ALARM,
     CAP_BLOCK_SUSPEND,
-    CAP_AUDIT_READ
+    CAP_AUDIT_READ,
+    CAP_PERFMON,
+    CAP_BPF,
+    CAP_CHECKPOINT_RESTORE
+}
+impl CapabilityType {
+    /// Every capability, in the order of their numbers
+    const ALL: [CapabilityType; 41] = [
+        CapabilityType::CAP_CHOWN,
+        CapabilityType::CAP_DAC_OVERRIDE,
+        CapabilityType::CAP_DAC_READ_SEARCH,
+        CapabilityType::CAP_FOWNER,
+        CapabilityType::CAP_FSETID,
+        CapabilityType::CAP_KILL,
+        CapabilityType::CAP_SETGID,
+        CapabilityType::CAP_SETUID,
+        CapabilityType::CAP_SETPCAP,
+        CapabilityType::CAP_LINUX_IMMUTABLE,
+        CapabilityType::CAP_NET_BIND_SERVICE,
+        CapabilityType::CAP_NET_BROADCAST,
+        CapabilityType::CAP_NET_ADMIN,
+        CapabilityType::CAP_NET_RAW,
+        CapabilityType::CAP_IPC_LOCK,
+        CapabilityType::CAP_IPC_OWNER,
+        CapabilityType::CAP_SYS_MODULE,
+        CapabilityType::CAP_SYS_RAWIO,
+        CapabilityType::CAP_SYS_CHROOT,
+        CapabilityType::CAP_SYS_PTRACE,
+        CapabilityType::CAP_SYS_PACCT,
+        CapabilityType::CAP_SYS_ADMIN,
+        CapabilityType::CAP_SYS_BOOT,
+        CapabilityType::CAP_SYS_NICE,
+        CapabilityType::CAP_SYS_RESOURCE,
+        CapabilityType::CAP_SYS_TIME,
+        CapabilityType::CAP_SYS_TTY_CONFIG,
+        CapabilityType::CAP_MKNOD,
+        CapabilityType::CAP_LEASE,
+        CapabilityType::CAP_AUDIT_WRITE,
+        CapabilityType::CAP_AUDIT_CONTROL,
+        CapabilityType::CAP_SETFCAP,
+        CapabilityType::CAP_MAC_OVERRIDE,
+        CapabilityType::CAP_MAC_ADMIN,
+        CapabilityType::CAP_SYSLOG,
+        CapabilityType::CAP_WAKE_ALARM,
+        CapabilityType::CAP_BLOCK_SUSPEND,
+        CapabilityType::CAP_AUDIT_READ,
+        CapabilityType::CAP_PERFMON,
+        CapabilityType::CAP_BPF,
+        CapabilityType::CAP_CHECKPOINT_RESTORE,
+    ];
+
+    /// Parse a capability name, with or without the 'CAP_' prefix, or 'ALL' for every capability
+    pub fn parse(name: &str) -> Result<Vec<CapabilityType>, Box<dyn std::error::Error>> {
+        let name = name.to_uppercase();
+        if name == "ALL" {
+            return Ok(CapabilityType::ALL.to_vec())
+        }
+
+        let name = if name.starts_with("CAP_") { name } else { format!("CAP_{}", name) };
+        let capability = serde_json::from_value(serde_json::Value::String(name.clone()))
+            .map_err(|_| format!("unknown capability '{}'", name))?;
+        Ok(vec![capability])
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -203,6 +553,12 @@ pub struct Linux {
     pub cgroup_path: String,
     #[serde(default)]
     pub resources: Option<Resources>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty", rename = "maskedPaths")]
+    pub masked_paths: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty", rename = "readonlyPaths")]
+    pub readonly_paths: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seccomp: Option<Seccomp>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -267,7 +623,61 @@ impl Default for DeviceType {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Seccomp {
+    #[serde(rename = "defaultAction")]
+    pub default_action: SeccompAction,
+    #[serde(default, rename = "defaultErrnoRet", skip_serializing_if = "Option::is_none")]
+    pub default_errno_ret: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub architectures: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub syscalls: Vec<Syscall>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SeccompAction {
+    SCMP_ACT_KILL,
+    SCMP_ACT_KILL_PROCESS,
+    SCMP_ACT_KILL_THREAD,
+    SCMP_ACT_TRAP,
+    SCMP_ACT_ERRNO,
+    SCMP_ACT_TRACE,
+    SCMP_ACT_LOG,
+    SCMP_ACT_ALLOW,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Syscall {
+    pub names: Vec<String>,
+    pub action: SeccompAction,
+    #[serde(default, rename = "errnoRet", skip_serializing_if = "Option::is_none")]
+    pub errno_ret: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<SyscallArg>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyscallArg {
+    pub index: u32,
+    pub value: u64,
+    #[serde(default, rename = "valueTwo")]
+    pub value_two: u64,
+    pub op: SeccompOperator,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum SeccompOperator {
+    SCMP_CMP_NE,
+    SCMP_CMP_LT,
+    SCMP_CMP_LE,
+    SCMP_CMP_EQ,
+    SCMP_CMP_GE,
+    SCMP_CMP_GT,
+    SCMP_CMP_MASKED_EQ,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Resources {
     #[serde(default)]
     pub devices: Vec<DeviceCgroup>,
@@ -285,7 +695,7 @@ pub struct Resources {
     pub pids: Option<Pids>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DeviceCgroup {
     #[serde(default)]
     pub allow: bool,
@@ -299,7 +709,7 @@ pub struct DeviceCgroup {
     pub access: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct MemoryCgroup {
     #[serde(default)]
     pub limit: i64,
@@ -311,13 +721,13 @@ pub struct MemoryCgroup {
     pub kernel: i64,
     #[serde(default, rename = "kernelTCP")]
     pub kernel_tcp: i64,
-    #[serde(default)]
-    pub swappiness: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swappiness: Option<u64>,
     #[serde(default, rename = "disableOOMKiller")]
     pub disable_oom_killer: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct CpuCgroup {
     #[serde(default)]
     pub shares: u64,
@@ -395,7 +805,7 @@ pub struct Priority {
     pub priority: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Pids {
     #[serde(default)]
     pub limit: i64,
@@ -0,0 +1,693 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, create_dir_all, File};
+use std::io::Read;
+use std::path::Path;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, UNIX_EPOCH};
+use std::process::exit;
+use nix::libc::{EIO, ENOENT, ENODATA};
+use nix::sys::stat::makedev;
+use nix::unistd::{close, fork, pipe, read, setsid, write, ForkResult};
+
+use fuser::{FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyXattr, Request, Session};
+use flate2::read::GzDecoder;
+use log::{debug, info, error};
+use reqwest::{self, StatusCode, header::{CONTENT_RANGE, RANGE}};
+use serde_json::{self, Value};
+use tar::Archive;
+
+use crate::paths;
+use crate::trust;
+use crate::utils;
+
+
+/// Size of the eStargz footer, a gzip member carrying the TOC offset in its extra field
+const FOOTER_SIZE: u64 = 51;
+/// Size of the footer written by the original stargz format
+const LEGACY_FOOTER_SIZE: u64 = 47;
+/// Name of the TOC inside the last tar stream of the layer
+const TOC_NAME: &str = "stargz.index.json";
+/// Annotation of a layer in the image manifest holding the digest of its TOC
+pub const TOC_DIGEST_ANNOTATION: &str = "containerd.io/snapshot/stargz/toc.digest";
+/// Layers never change, so the kernel may cache attributes for a long time
+const TTL: Duration = Duration::from_secs(3600);
+
+/// Location of a layer blob in a registry
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Blob {
+    pub url: String,
+    #[serde(default, rename = "authUrl", skip_serializing_if = "Option::is_none")]
+    pub auth_url: Option<String>,
+}
+
+/// Table of contents of a seekable layer
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Toc {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub entries: Vec<TocEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TocEntry {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default, rename = "type")]
+    pub typ: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default, rename = "linkName")]
+    pub link_name: String,
+    #[serde(default)]
+    pub mode: u32,
+    #[serde(default)]
+    pub uid: u32,
+    #[serde(default)]
+    pub gid: u32,
+    #[serde(default, rename = "devMajor")]
+    pub dev_major: u64,
+    #[serde(default, rename = "devMinor")]
+    pub dev_minor: u64,
+    #[serde(default)]
+    pub offset: u64,
+    #[serde(default, rename = "chunkOffset")]
+    pub chunk_offset: u64,
+    #[serde(default, rename = "chunkSize")]
+    pub chunk_size: u64,
+    #[serde(default, rename = "chunkDigest")]
+    pub chunk_digest: String,
+}
+
+/// Everything needed to serve a layer without downloading it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LazyLayer {
+    pub blob: Blob,
+    #[serde(rename = "tocOffset")]
+    pub toc_offset: u64,
+    #[serde(default, rename = "tocDigest")]
+    pub toc_digest: String,
+    pub toc: Toc,
+}
+
+
+/// Reads byte ranges of a blob over HTTP, authenticating when the registry asks for it
+pub struct BlobReader {
+    blob: Blob,
+    token: Option<String>,
+    client: reqwest::blocking::Client,
+}
+impl BlobReader {
+    /// Create a new blob reader object
+    pub fn new(blob: Blob) -> BlobReader {
+        BlobReader {
+            blob,
+            token: None,
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+
+    /// Get a new token from the registry's authentication server
+    fn authenticate(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let auth_url = match &self.blob.auth_url {
+            Some(url) => url,
+            None      => return Err("registry requires authentication".into())
+        };
+        debug!("sending authentication token request to: {}...", auth_url);
+
+        let response = self.client.get(auth_url.as_str()).send()?;
+        let body: Value = serde_json::from_str(response.text()?.as_str())?;
+        match &body["token"] {
+            Value::String(t) => self.token = Some(t.clone()),
+            _ => return Err("token retrieval failed".into()),
+        };
+
+        Ok(())
+    }
+
+    /// Fetch a byte range of the blob, returning the data and the total size of the blob
+    ///
+    /// The range is given in the HTTP format ("start-end" or "-suffix_length")
+    pub fn read_range(&mut self, range: &str) -> Result<(Vec<u8>, u64), Box<dyn std::error::Error>> {
+        for attempt in 0..2 {
+            let mut request = self.client
+                .get(self.blob.url.as_str())
+                .header(RANGE, format!("bytes={}", range));
+            if let Some(token) = &self.token {
+                request = request.bearer_auth(token);
+            }
+            let response = request.send()?;
+
+            if response.status() == StatusCode::UNAUTHORIZED && attempt == 0 {
+                self.authenticate()?;
+                continue
+            }
+            if response.status() != StatusCode::PARTIAL_CONTENT {
+                return Err(format!("range request failed with status {}", response.status()).into());
+            }
+
+            let total = match response.headers().get(CONTENT_RANGE) {
+                Some(value) => value.to_str()?
+                    .rsplit('/')
+                    .next().unwrap()
+                    .parse::<u64>()?,
+                None => return Err("missing content range in response".into())
+            };
+            let data = response.bytes()?.to_vec();
+
+            return Ok((data, total))
+        }
+
+        Err("registry refused the credentials".into())
+    }
+}
+
+/// Parse the footer at the end of a layer, returning the TOC offset and the footer size
+fn parse_footer(footer: &[u8]) -> Option<(u64, u64)> {
+    let parse_offset = |hex: &[u8]| {
+        std::str::from_utf8(hex).ok()
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+    };
+
+    if footer.len() as u64 >= FOOTER_SIZE {
+        let f = &footer[footer.len() - FOOTER_SIZE as usize..];
+        if f[0..2] == [0x1f, 0x8b] && f[10..12] == [26, 0] && &f[12..14] == b"SG" && &f[32..38] == b"STARGZ" {
+            return parse_offset(&f[16..32]).map(|offset| (offset, FOOTER_SIZE));
+        }
+    }
+
+    if footer.len() as u64 >= LEGACY_FOOTER_SIZE {
+        let f = &footer[footer.len() - LEGACY_FOOTER_SIZE as usize..];
+        if f[0..2] == [0x1f, 0x8b] && f[10..12] == [22, 0] && &f[28..34] == b"STARGZ" {
+            return parse_offset(&f[12..28]).map(|offset| (offset, LEGACY_FOOTER_SIZE));
+        }
+    }
+
+    None
+}
+
+/// Fetch the TOC of a layer blob, if the layer is in a seekable format, checking it against its digest
+pub fn fetch_toc(reader: &mut BlobReader, toc_digest: &str) -> Result<Option<(u64, Toc)>, Box<dyn std::error::Error>> {
+    info!("fetching layer footer...");
+    let (footer, size) = reader.read_range(format!("-{}", FOOTER_SIZE).as_str())?;
+    let (toc_offset, footer_size) = match parse_footer(&footer) {
+        Some(footer) => footer,
+        None => {
+            info!("layer is not seekable.");
+            return Ok(None)
+        }
+    };
+
+    info!("fetching layer toc...");
+    let range = format!("{}-{}", toc_offset, size - footer_size - 1);
+    let (data, _) = reader.read_range(range.as_str())?;
+    let mut archive = Archive::new(GzDecoder::new(&data[..]));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.to_str() == Some(TOC_NAME) {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            if trust::digest(&content) != toc_digest {
+                return Err(format!("layer toc doesn't match its digest {}", toc_digest).into());
+            }
+            let toc: Toc = serde_json::from_slice(&content)?;
+            info!("fetched layer toc.");
+            return Ok(Some((toc_offset, toc)))
+        }
+    }
+
+    Err("layer footer found, but the toc is missing".into())
+}
+
+/// Get the path to the lazy layer metadata
+fn get_lazy_layer_json_path(digest: &str) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!("{}/stargz.json", paths::get_layer_path_with_str(digest)?))
+}
+/// Check whether a layer is served on demand
+pub fn is_lazy(digest: &str) -> bool {
+    match get_lazy_layer_json_path(digest) {
+        Ok(path) => Path::new(&path).exists(),
+        Err(_)   => false
+    }
+}
+/// Load the stored metadata of a lazy layer
+fn load_layer(digest: &str) -> Result<LazyLayer, Box<dyn std::error::Error>> {
+    Ok(serde_json::from_reader(File::open(get_lazy_layer_json_path(digest)?)?)?)
+}
+/// Store the TOC of a seekable layer, so it can be mounted without downloading it
+///
+/// The TOC must match the digest from the image manifest, and give the digest of every chunk, which are
+/// checked when they are read. Returns false if the layer is not seekable and has to be downloaded
+pub fn pull_layer(blob: Blob, digest: &str, toc_digest: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    if is_lazy(digest) && load_layer(digest)?.toc_digest == toc_digest {
+        info!("layer {} toc already stored.", digest);
+        return Ok(true)
+    }
+
+    let mut reader = BlobReader::new(blob.clone());
+    let (toc_offset, toc) = match fetch_toc(&mut reader, toc_digest)? {
+        Some(toc) => toc,
+        None      => return Ok(false)
+    };
+    let unchecked = toc.entries.iter()
+        .any(|entry| (entry.typ == "chunk" || (entry.typ == "reg" && entry.size > 0)) && entry.chunk_digest.is_empty());
+    if unchecked {
+        info!("layer {} toc has chunks without digest.", digest);
+        return Ok(false)
+    }
+
+    let layer_path = paths::get_layer_path_with_str(digest)?;
+    let temporary_path = format!("{}.{}", layer_path, std::process::id());
+    create_dir_all(&temporary_path)?;
+    let layer = LazyLayer { blob, toc_offset, toc_digest: toc_digest.to_string(), toc };
+    serde_json::to_writer(&File::create(format!("{}/stargz.json", temporary_path))?, &layer)?;
+
+    if Path::new(&layer_path).exists() {
+        fs::remove_dir_all(&layer_path)?;
+    }
+    fs::rename(&temporary_path, &layer_path)?;
+
+    Ok(true)
+}
+
+/// Mount a lazy layer through FUSE, if it isn't already mounted
+///
+/// The filesystem is served by a detached child process that lives until the layer is unmounted
+pub fn mount_layer(digest: &str, mountpoint: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if utils::is_mounted(mountpoint)? {
+        debug!("layer {} already mounted.", digest);
+        return Ok(())
+    }
+    info!("mounting lazy layer {}...", digest);
+
+    let layer = load_layer(digest)?;
+    let cache_path = format!("{}/cache", paths::get_layer_path_with_str(digest)?);
+    create_dir_all(&cache_path)?;
+    create_dir_all(mountpoint)?;
+
+    let (ready_read, ready_write) = pipe()?;
+    match fork()? {
+        ForkResult::Child => {
+            // The child never returns, or it would carry on as a second minato in the caller
+            let code = match serve_layer(layer, cache_path, mountpoint, ready_read, ready_write) {
+                Ok(()) => 0,
+                Err(e) => {
+                    error!("error serving layer {}: {}", mountpoint, e);
+                    1
+                }
+            };
+            exit(code);
+        }
+        ForkResult::Parent { .. } => {
+            close(ready_write)?;
+            let mut buffer = [0; 1];
+            let size = read(ready_read, &mut buffer)?;
+            close(ready_read)?;
+            if size == 0 {
+                return Err(format!("layer server for {} failed to start", digest).into());
+            }
+        }
+    }
+
+    info!("mounted lazy layer {}.", digest);
+    Ok(())
+}
+
+/// Serve a lazy layer on its mountpoint until it's unmounted, from the child process of mount_layer
+///
+/// A byte is written to the ready pipe once the layer is mounted.
+/// Other users can only read it as root, FUSE refuses 'allow_other' to users without 'user_allow_other' in /etc/fuse.conf
+fn serve_layer(layer: LazyLayer, cache_path: String, mountpoint: &str, ready_read: RawFd, ready_write: RawFd) -> Result<(), Box<dyn std::error::Error>> {
+    close(ready_read)?;
+    setsid()?;
+
+    let filesystem = LayerFs::new(layer, cache_path);
+    let prefetch = filesystem.prefetch_list();
+    let mut options = vec![
+        MountOption::RO,
+        MountOption::DefaultPermissions,
+        MountOption::FSName(String::from("minato-stargz")),
+    ];
+    if !utils::is_rootless() {
+        options.push(MountOption::AllowOther);
+    }
+    let mut session = Session::new(filesystem, Path::new(mountpoint), &options)
+        .map_err(|e| format!("cannot mount layer: {}", e))?;
+    write(ready_write, b"1")?;
+    close(ready_write)?;
+
+    std::thread::spawn(move || prefetch.run());
+    session.run()?;
+    Ok(())
+}
+
+/// A part of a file, stored as its own gzip member in the blob
+#[derive(Clone)]
+struct Chunk {
+    offset: u64,
+    chunk_offset: u64,
+    chunk_size: u64,
+    /// Digest of the uncompressed data, from the TOC
+    digest: String,
+}
+
+/// Fetches chunks from the blob and keeps them in the layer cache directory
+#[derive(Clone)]
+struct ChunkCache {
+    blob: Blob,
+    cache_path: String,
+    /// Sorted offsets of all gzip members, used to know where a chunk ends
+    offsets: Vec<u64>,
+}
+impl ChunkCache {
+    /// Get a chunk's data from the cache, fetching it if needed
+    ///
+    /// The data is checked against the chunk digest both when it's fetched and when it's read from the cache
+    fn get(&self, reader: &mut BlobReader, chunk: &Chunk) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let chunk_path = format!("{}/{}", self.cache_path, chunk.offset);
+        if Path::new(&chunk_path).exists() {
+            let data = fs::read(&chunk_path)?;
+            if trust::digest(&data) == chunk.digest {
+                return Ok(data)
+            }
+            error!("cached chunk at {} doesn't match its digest. fetching it again...", chunk.offset);
+            fs::remove_file(&chunk_path)?;
+        }
+
+        let end = match self.offsets.iter().find(|offset| **offset > chunk.offset) {
+            Some(end) => *end,
+            None      => return Err("chunk offset outside of the layer".into())
+        };
+        debug!("fetching chunk {}-{}...", chunk.offset, end - 1);
+        let (compressed, _) = reader.read_range(format!("{}-{}", chunk.offset, end - 1).as_str())?;
+        let mut data = Vec::with_capacity(chunk.chunk_size as usize);
+        GzDecoder::new(&compressed[..])
+            .take(chunk.chunk_size)
+            .read_to_end(&mut data)?;
+        if trust::digest(&data) != chunk.digest {
+            return Err(format!("chunk at {} doesn't match its digest {}", chunk.offset, chunk.digest).into());
+        }
+
+        let temporary_path = format!("{}.{}", chunk_path, std::process::id());
+        fs::write(&temporary_path, &data)?;
+        fs::rename(temporary_path, chunk_path)?;
+
+        Ok(data)
+    }
+}
+
+/// Downloads every chunk of the layer in the background, so reads stop hitting the network
+struct Prefetch {
+    cache: ChunkCache,
+    chunks: Vec<Chunk>,
+}
+impl Prefetch {
+    fn run(self) {
+        let mut reader = BlobReader::new(self.cache.blob.clone());
+        for chunk in &self.chunks {
+            if let Err(e) = self.cache.get(&mut reader, chunk) {
+                error!("error prefetching chunk at {}: {}", chunk.offset, e);
+                return
+            }
+        }
+        info!("prefetched {} chunks.", self.chunks.len());
+    }
+}
+
+struct Node {
+    parent: u64,
+    kind: FileType,
+    perm: u16,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    nlink: u32,
+    rdev: u32,
+    link_name: String,
+    children: BTreeMap<String, u64>,
+    chunks: Vec<Chunk>,
+}
+impl Node {
+    fn new(parent: u64, kind: FileType) -> Node {
+        Node {
+            parent,
+            kind,
+            perm: 0o755,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            nlink: 1,
+            rdev: 0,
+            link_name: String::new(),
+            children: BTreeMap::new(),
+            chunks: Vec::new(),
+        }
+    }
+}
+
+/// Read-only filesystem built from a layer's TOC, reading file contents on demand
+struct LayerFs {
+    /// Inode n is stored at index n - 1; the root is inode 1
+    nodes: Vec<Node>,
+    reader: BlobReader,
+    cache: ChunkCache,
+}
+impl LayerFs {
+    fn new(layer: LazyLayer, cache_path: String) -> LayerFs {
+        let mut offsets: Vec<u64> = layer.toc.entries.iter()
+            .filter(|entry| entry.offset > 0)
+            .map(|entry| entry.offset)
+            .collect();
+        offsets.push(layer.toc_offset);
+        offsets.sort();
+        offsets.dedup();
+
+        let mut filesystem = LayerFs {
+            nodes: vec![Node::new(1, FileType::Directory)],
+            reader: BlobReader::new(layer.blob.clone()),
+            cache: ChunkCache {
+                blob: layer.blob,
+                cache_path,
+                offsets,
+            },
+        };
+
+        let mut paths: HashMap<String, u64> = HashMap::new();
+        paths.insert(String::new(), 1);
+        let mut last_file = 0;
+        for entry in &layer.toc.entries {
+            let name = entry.name.trim_start_matches("./").trim_matches('/').to_string();
+            match entry.typ.as_str() {
+                "chunk" => {
+                    if last_file != 0 {
+                        filesystem.nodes[last_file as usize - 1].chunks.push(Chunk {
+                            offset: entry.offset,
+                            chunk_offset: entry.chunk_offset,
+                            chunk_size: entry.chunk_size,
+                            digest: entry.chunk_digest.clone(),
+                        });
+                    }
+                }
+                "hardlink" => {
+                    let target = entry.link_name.trim_start_matches("./").trim_matches('/');
+                    if let Some(ino) = paths.get(target).cloned() {
+                        let parent = filesystem.parent_of(&mut paths, &name);
+                        filesystem.nodes[ino as usize - 1].nlink += 1;
+                        filesystem.nodes[parent as usize - 1].children.insert(base_name(&name), ino);
+                    }
+                }
+                typ => {
+                    let kind = match typ {
+                        "dir"     => FileType::Directory,
+                        "symlink" => FileType::Symlink,
+                        "char"    => FileType::CharDevice,
+                        "block"   => FileType::BlockDevice,
+                        "fifo"    => FileType::NamedPipe,
+                        _         => FileType::RegularFile,
+                    };
+                    let ino = filesystem.node_at(&mut paths, &name, kind);
+                    let node = &mut filesystem.nodes[ino as usize - 1];
+                    node.perm = (entry.mode & 0o7777) as u16;
+                    node.uid = entry.uid;
+                    node.gid = entry.gid;
+                    node.link_name = entry.link_name.clone();
+                    node.rdev = makedev(entry.dev_major, entry.dev_minor) as u32;
+                    if kind == FileType::RegularFile {
+                        node.size = entry.size;
+                        if entry.size > 0 {
+                            let chunk_size = if entry.chunk_size > 0 { entry.chunk_size } else { entry.size };
+                            node.chunks.push(Chunk {
+                                offset: entry.offset,
+                                chunk_offset: 0,
+                                chunk_size,
+                                digest: entry.chunk_digest.clone(),
+                            });
+                        }
+                        last_file = ino;
+                    }
+                }
+            }
+        }
+
+        filesystem
+    }
+
+    /// Get the inode of a path's parent directory, creating missing directories
+    fn parent_of(&mut self, paths: &mut HashMap<String, u64>, name: &str) -> u64 {
+        match name.rfind('/') {
+            Some(index) => self.node_at(paths, &name[..index], FileType::Directory),
+            None        => 1
+        }
+    }
+    /// Get the inode at a path, creating it if it doesn't exist
+    fn node_at(&mut self, paths: &mut HashMap<String, u64>, name: &str, kind: FileType) -> u64 {
+        if let Some(ino) = paths.get(name) {
+            return *ino
+        }
+
+        let parent = self.parent_of(paths, name);
+        self.nodes.push(Node::new(parent, kind));
+        let ino = self.nodes.len() as u64;
+        self.nodes[parent as usize - 1].children.insert(base_name(name), ino);
+        paths.insert(name.to_string(), ino);
+
+        ino
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        if ino == 0 {
+            return None
+        }
+        self.nodes.get(ino as usize - 1)
+    }
+
+    fn attr(&self, ino: u64, node: &Node) -> FileAttr {
+        FileAttr {
+            ino,
+            size: node.size,
+            blocks: node.size.div_ceil(512),
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: node.kind,
+            perm: node.perm,
+            nlink: node.nlink,
+            uid: node.uid,
+            gid: node.gid,
+            rdev: node.rdev,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    /// Get the list of all chunks for the background download
+    fn prefetch_list(&self) -> Prefetch {
+        Prefetch {
+            cache: self.cache.clone(),
+            chunks: self.nodes.iter()
+                .flat_map(|node| node.chunks.iter().cloned())
+                .collect(),
+        }
+    }
+}
+impl Filesystem for LayerFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let ino = match self.node(parent).and_then(|node| node.children.get(name.to_str().unwrap_or(""))) {
+            Some(ino) => *ino,
+            None => return reply.error(ENOENT)
+        };
+        let attr = self.attr(ino, self.node(ino).unwrap());
+        reply.entry(&TTL, &attr, 0);
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.node(ino) {
+            Some(node) => reply.attr(&TTL, &self.attr(ino, node)),
+            None       => reply.error(ENOENT)
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.node(ino) {
+            Some(node) => reply.data(node.link_name.as_bytes()),
+            None       => reply.error(ENOENT)
+        }
+    }
+
+    fn read(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
+        // Borrow the fields separately, the reader is needed mutably while walking the chunks
+        let node = match self.nodes.get((ino as usize).wrapping_sub(1)) {
+            Some(node) => node,
+            None => return reply.error(ENOENT)
+        };
+        let start = offset as u64;
+        let end = std::cmp::min(start + size as u64, node.size);
+
+        let mut data = Vec::new();
+        for chunk in &node.chunks {
+            let chunk_end = chunk.chunk_offset + chunk.chunk_size;
+            if chunk_end <= start || chunk.chunk_offset >= end {
+                continue
+            }
+
+            let chunk_data = match self.cache.get(&mut self.reader, chunk) {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("error reading chunk at {}: {}", chunk.offset, e);
+                    return reply.error(EIO)
+                }
+            };
+            let from = (std::cmp::max(start, chunk.chunk_offset) - chunk.chunk_offset) as usize;
+            let to = std::cmp::min((std::cmp::min(end, chunk_end) - chunk.chunk_offset) as usize, chunk_data.len());
+            if from < to {
+                data.extend_from_slice(&chunk_data[from..to]);
+            }
+        }
+
+        reply.data(&data);
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let node = match self.node(ino) {
+            Some(node) => node,
+            None => return reply.error(ENOENT)
+        };
+
+        let mut entries = vec![
+            (ino, FileType::Directory, String::from(".")),
+            (node.parent, FileType::Directory, String::from("..")),
+        ];
+        for (name, child) in &node.children {
+            entries.push((*child, self.node(*child).unwrap().kind, name.clone()));
+        }
+
+        for (index, (child, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(child, (index + 1) as i64, kind, name) {
+                break
+            }
+        }
+        reply.ok();
+    }
+
+    fn getxattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+        reply.error(ENODATA);
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, _ino: u64, size: u32, reply: ReplyXattr) {
+        if size == 0 {
+            reply.size(0);
+        } else {
+            reply.data(&[]);
+        }
+    }
+}
+
+/// Get the last component of a path
+fn base_name(name: &str) -> String {
+    match name.rfind('/') {
+        Some(index) => name[index + 1..].to_string(),
+        None        => name.to_string()
+    }
+}
@@ -0,0 +1,186 @@
+#![allow(non_camel_case_types)]
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+use std::collections::HashMap;
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+
+use log::info;
+
+use crate::utils;
+
+
+/// Runtime state of a container, stored as 'state.json' in the container directory
+///
+/// Follows the OCI runtime state, with the timestamps and the exit code added
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct State {
+    #[serde(rename = "ociVersion")]
+    pub oci_version: String,
+    pub id: String,
+    pub status: Status,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub pid: i32,
+    /// Start time of the process, in clock ticks after boot, telling it apart from a later one reusing its pid
+    #[serde(default, rename = "startTime", skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<u64>,
+    pub bundle: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+    pub created: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished: Option<String>,
+    #[serde(default, rename = "exitCode", skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+}
+impl State {
+    /// Create the state of a newly created container
+    pub fn new(id: &str, bundle: &str, annotations: HashMap<String, String>) -> State {
+        State {
+            oci_version: String::from("1.0.2"),
+            id: id.to_string(),
+            status: Status::created,
+            pid: 0,
+            start_time: None,
+            bundle: bundle.to_string(),
+            annotations,
+            created: utils::get_timestamp(),
+            started: None,
+            finished: None,
+            exit_code: None,
+        }
+    }
+    /// Load the state of a container, from its directory
+    ///
+    /// A container whose process is gone is marked as stopped, e.g. after a crash
+    pub fn load(container_path: &str) -> Result<Option<State>, Box<dyn std::error::Error>> {
+        let state_path = format!("{}/state.json", container_path);
+        if !Path::new(&state_path).exists() {
+            return Ok(None)
+        }
+
+        let file = File::open(state_path)?;
+        let buf_reader = BufReader::new(file);
+        let mut state: State = serde_json::from_reader(buf_reader)?;
+
+        if state.pid != 0 && !is_alive(state.pid, state.start_time) {
+            info!("container process {} is gone. marking container as stopped...", state.pid);
+            state.status = Status::stopped;
+            state.pid = 0;
+            state.start_time = None;
+            state.finished = Some(utils::get_timestamp());
+            state.save(container_path)?;
+        }
+
+        Ok(Some(state))
+    }
+    /// Store the state in the container directory
+    ///
+    /// The file is replaced atomically, so readers never see a partial state
+    pub fn save(&self, container_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let state_path = format!("{}/state.json", container_path);
+        let temporary_path = format!("{}.{}", state_path, std::process::id());
+
+        serde_json::to_writer(&File::create(&temporary_path)?, &self)?;
+        fs::rename(temporary_path, state_path)?;
+        Ok(())
+    }
+    /// Check if the container has a process
+    pub fn is_active(&self) -> bool {
+        self.status == Status::running || self.status == Status::paused
+    }
+    /// Record the container process, created but waiting to be started
+    pub fn set_created(&mut self, pid: i32) {
+        self.status = Status::created;
+        self.pid = pid;
+        self.start_time = start_time(pid);
+    }
+    /// Record the start of the container process
+    pub fn set_running(&mut self, pid: i32) {
+        self.status = Status::running;
+        self.pid = pid;
+        self.start_time = start_time(pid);
+        self.started = Some(utils::get_timestamp());
+        self.finished = None;
+        self.exit_code = None;
+    }
+    /// Record the container process being frozen, or thawed
+    pub fn set_paused(&mut self, paused: bool) {
+        self.status = if paused { Status::paused } else { Status::running };
+    }
+    /// Record the end of the container process
+    pub fn set_stopped(&mut self, exit_code: Option<i32>) {
+        self.status = Status::stopped;
+        self.pid = 0;
+        self.start_time = None;
+        self.finished = Some(utils::get_timestamp());
+        self.exit_code = exit_code;
+    }
+    /// Fail if the container is not in one of the given statuses
+    pub fn check(&self, statuses: &[Status], operation: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if statuses.contains(&self.status) {
+            return Ok(())
+        }
+
+        Err(format!("cannot {} container '{}': container is {:?}", operation, self.id, self.status).into())
+    }
+}
+
+/// Container status
+///
+///   - creating: the container directory is being prepared
+///   - created: the container can be run
+///   - running: the container process is running
+///   - paused: the container process is frozen
+///   - stopped: the container process exited, it can be run again
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    creating,
+    created,
+    running,
+    paused,
+    stopped,
+}
+
+fn is_zero(n: &i32) -> bool {
+    *n == 0
+}
+/// Fields of '/proc/<pid>/stat' after the command name, which is in parentheses and may hold spaces
+///
+/// The first one is the process state, field 3 of proc(5)
+fn stat_fields(pid: i32) -> Option<Vec<String>> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let end = stat.rfind(')')?;
+    Some(stat[end + 1..].split_whitespace().map(String::from).collect())
+}
+/// Start time of a process, field 22 of '/proc/<pid>/stat'
+fn start_time(pid: i32) -> Option<u64> {
+    stat_fields(pid)?.get(22 - 3)?.parse().ok()
+}
+/// Check if a process exists and hasn't exited yet
+///
+/// Zombies count as exited, their parent may not have reaped them yet. With a start time, a process
+/// started at another time is a different one that reused the pid
+fn is_alive(pid: i32, expected_start_time: Option<u64>) -> bool {
+    if pid <= 0 {
+        return false
+    }
+    let exists = match kill(Pid::from_raw(pid), None) {
+        Ok(())                             => true,
+        Err(nix::Error::Sys(Errno::EPERM)) => true,
+        Err(_)                             => false,
+    };
+
+    let fields = stat_fields(pid).unwrap_or_default();
+    let zombie = fields.first().map(String::as_str) == Some("Z");
+    let reused = match expected_start_time {
+        Some(expected_start_time) => fields.get(22 - 3).and_then(|time| time.parse().ok()) != Some(expected_start_time),
+        None                      => false,
+    };
+
+    exists && !zombie && !reused
+}
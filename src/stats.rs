@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io::{stdout, Write};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::cgroups;
+use crate::container::Container;
+use crate::state::State;
+
+
+/// Time between two readings of the counters
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Resource usage of a container, missing values are printed as '--'
+#[derive(Serialize, Debug)]
+pub struct Stats {
+    pub id: String,
+    #[serde(rename = "cpuPercent")]
+    pub cpu_percent: Option<f64>,
+    #[serde(rename = "memoryUsage")]
+    pub memory_usage: Option<u64>,
+    #[serde(rename = "memoryLimit")]
+    pub memory_limit: Option<u64>,
+    #[serde(rename = "memoryPercent")]
+    pub memory_percent: Option<f64>,
+    #[serde(rename = "networkReceived")]
+    pub network_received: Option<u64>,
+    #[serde(rename = "networkSent")]
+    pub network_sent: Option<u64>,
+    #[serde(rename = "blockRead")]
+    pub block_read: Option<u64>,
+    #[serde(rename = "blockWritten")]
+    pub block_written: Option<u64>,
+    pub pids: Option<u64>,
+}
+
+/// Print the resource usage of containers, every second if streaming, or once otherwise
+///
+/// The table is redrawn on each refresh, the JSON output is a line with an array of stats per refresh.
+/// The cpu usage is measured over a refresh interval, so the first stats are printed after one
+pub fn watch(containers: &[Container], stream: bool, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    info!("watching container stats...");
+
+    let mut cpu_samples = HashMap::new();
+    for container in containers {
+        read_stats(container, &mut cpu_samples)?;
+    }
+
+    loop {
+        sleep(REFRESH_INTERVAL);
+        let stats = containers.iter()
+            .map(|container| read_stats(container, &mut cpu_samples))
+            .collect::<Result<Vec<Stats>, _>>()?;
+
+        let mut out = stdout();
+        if json {
+            writeln!(out, "{}", serde_json::to_string(&stats)?)?;
+        } else {
+            if stream {
+                // Clear the terminal and go back to its top left corner
+                write!(out, "\x1b[2J\x1b[H")?;
+            }
+            print_table(&mut out, &stats)?;
+        }
+        out.flush()?;
+
+        if !stream {
+            return Ok(())
+        }
+    }
+}
+
+/// Read the current usage of a container, the cpu one relative to its previous sample
+fn read_stats(container: &Container, cpu_samples: &mut HashMap<String, (u64, Instant)>) -> Result<Stats, Box<dyn std::error::Error>> {
+    let mut stats = Stats {
+        id: container.id.clone(),
+        cpu_percent: None,
+        memory_usage: None,
+        memory_limit: None,
+        memory_percent: None,
+        network_received: None,
+        network_sent: None,
+        block_read: None,
+        block_written: None,
+        pids: None,
+    };
+    let pid = match State::load(&container.path)? {
+        Some(state) if state.is_active() && state.pid != 0 => state.pid,
+        _                                                   => {
+            cpu_samples.remove(&container.id);
+            return Ok(stats)
+        }
+    };
+
+    let usage = cgroups::usage(container)?;
+    if let Some(cpu) = usage.cpu {
+        let now = Instant::now();
+        if let Some((previous_cpu, previous_time)) = cpu_samples.insert(container.id.clone(), (cpu, now)) {
+            let elapsed = now.duration_since(previous_time).as_nanos() as f64;
+            stats.cpu_percent = Some(cpu.saturating_sub(previous_cpu) as f64 / elapsed * 100.0);
+        }
+    }
+
+    // Unlimited memory is shown as the host memory
+    let host_memory = host_memory();
+    stats.memory_usage = usage.memory;
+    stats.memory_limit = match (usage.memory_limit, host_memory) {
+        (Some(limit), Some(host_memory)) => Some(limit.min(host_memory)),
+        (limit, host_memory)             => limit.or(host_memory)
+    };
+    if let (Some(memory), Some(limit)) = (stats.memory_usage, stats.memory_limit) {
+        stats.memory_percent = Some(memory as f64 / limit as f64 * 100.0);
+    }
+
+    if let Some((received, sent)) = network_usage(pid) {
+        stats.network_received = Some(received);
+        stats.network_sent = Some(sent);
+    }
+    stats.block_read = usage.block_read;
+    stats.block_written = usage.block_write;
+    stats.pids = usage.pids;
+
+    Ok(stats)
+}
+/// Bytes received and sent by the interfaces of the network namespace of a process, but the loopback
+///
+/// Lines of '/proc/<pid>/net/dev' are 'interface: received bytes, 7 other counters, sent bytes...'
+fn network_usage(pid: i32) -> Option<(u64, u64)> {
+    let devices = read_to_string(format!("/proc/{}/net/dev", pid)).ok()?;
+    let usage = devices.lines()
+        .skip(2)
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            let interface = parts.next()?.trim();
+            let counters: Vec<u64> = parts.next()?.split_whitespace()
+                .filter_map(|counter| counter.parse().ok())
+                .collect();
+            match (interface, counters.first(), counters.get(8)) {
+                ("lo", _, _)                    => None,
+                (_, Some(received), Some(sent)) => Some((*received, *sent)),
+                _                               => None
+            }
+        })
+        .fold((0, 0), |(received, sent), (interface_received, interface_sent)| (received + interface_received, sent + interface_sent));
+    Some(usage)
+}
+/// Total memory of the host, from '/proc/meminfo'
+fn host_memory() -> Option<u64> {
+    let meminfo = read_to_string("/proc/meminfo").ok()?;
+    let kilobytes: u64 = meminfo.lines()
+        .find(|line| line.starts_with("MemTotal:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Print stats as a table, like 'container list'
+fn print_table(out: &mut impl Write, stats: &[Stats]) -> Result<(), Box<dyn std::error::Error>> {
+    writeln!(
+        out, "{:30} {:8} {:24} {:8} {:24} {:24} pids",
+        "id", "cpu %", "mem usage / limit", "mem %", "net i/o", "block i/o")?;
+    for stats in stats {
+        writeln!(
+            out, "{:30} {:8} {:24} {:8} {:24} {:24} {}",
+            stats.id,
+            format_percent(stats.cpu_percent),
+            format!("{} / {}", format_size(stats.memory_usage), format_size(stats.memory_limit)),
+            format_percent(stats.memory_percent),
+            format!("{} / {}", format_size(stats.network_received), format_size(stats.network_sent)),
+            format!("{} / {}", format_size(stats.block_read), format_size(stats.block_written)),
+            stats.pids.map_or("--".to_string(), |pids| pids.to_string()))?;
+    }
+    Ok(())
+}
+/// Format a percentage with two decimals
+fn format_percent(percent: Option<f64>) -> String {
+    match percent {
+        Some(percent) => format!("{:.2}%", percent),
+        None          => "--".to_string()
+    }
+}
+/// Format a size in bytes with a binary unit, like '1.5MiB'
+fn format_size(bytes: Option<u64>) -> String {
+    let bytes = match bytes {
+        Some(bytes) => bytes,
+        None        => return "--".to_string()
+    };
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{}{}", bytes, units[0]),
+        _ => format!("{:.2}{}", size, units[unit])
+    }
+}
@@ -0,0 +1,614 @@
+use std::fmt;
+use std::fs::{self, create_dir_all, read_to_string, rename, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::RawFd;
+use nix::mount::{mount, umount, MsFlags};
+use nix::unistd::{chown, close, Gid, Uid};
+
+use log::{info, debug};
+
+use crate::paths;
+use crate::utils;
+use crate::image::Image;
+use crate::container::{Container, IdMappings};
+use crate::config::{Config, StorageDriverType};
+use crate::mounts;
+use crate::spec::Mapping;
+use crate::userns;
+
+
+/// A path of the container filesystem that differs from its image
+pub enum Change {
+    Added(PathBuf),
+    Changed(PathBuf),
+    Deleted(PathBuf),
+}
+impl Change {
+    fn path(&self) -> &Path {
+        match self {
+            Change::Added(path) | Change::Changed(path) | Change::Deleted(path) => path,
+        }
+    }
+}
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self {
+            Change::Added(_)   => "A",
+            Change::Changed(_) => "C",
+            Change::Deleted(_) => "D",
+        };
+        write!(f, "{} /{}", kind, self.path().display())
+    }
+}
+
+/// Builds the writable root filesystem of a container, at '<container>/merged'
+pub trait StorageDriver {
+    /// Driver type, stored with the container
+    fn driver_type(&self) -> StorageDriverType;
+    /// Create the container storage, once, when the container is created
+    fn prepare(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>>;
+    /// Make the container root filesystem available, before each run
+    fn mount(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>>;
+    /// Release the container root filesystem, after each run
+    fn unmount(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>>;
+    /// List the changes made by the container to its image
+    fn diff(&self, container: &Container) -> Result<Vec<Change>, Box<dyn std::error::Error>>;
+    /// Remove the container storage, before the container directory is deleted
+    fn remove(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Get the driver implementing a driver type
+pub fn get_driver(driver_type: StorageDriverType) -> Box<dyn StorageDriver> {
+    match driver_type {
+        StorageDriverType::overlay        => Box::new(OverlayDriver),
+        StorageDriverType::fuse_overlayfs => Box::new(FuseOverlayDriver),
+        StorageDriverType::vfs            => Box::new(VfsDriver),
+        StorageDriverType::btrfs          => Box::new(BtrfsDriver),
+    }
+}
+/// Get the driver selected in the host configuration, for new containers
+pub fn new_driver() -> Result<Box<dyn StorageDriver>, Box<dyn std::error::Error>> {
+    Ok(get_driver(Config::load()?.storage.driver))
+}
+/// Get the driver a container was created with
+///
+/// Containers created before drivers were stored use kernel overlayfs
+pub fn load_driver(container_path: &str) -> Result<Box<dyn StorageDriver>, Box<dyn std::error::Error>> {
+    let driver_path = format!("{}/driver", container_path);
+    if !Path::new(&driver_path).exists() {
+        return Ok(get_driver(StorageDriverType::overlay))
+    }
+
+    let driver_type = serde_json::from_str(&read_to_string(driver_path)?)?;
+    Ok(get_driver(driver_type))
+}
+/// Store the driver a container is created with, so it keeps it if the host configuration changes
+pub fn save_driver(container_path: &str, driver: &dyn StorageDriver) -> Result<(), Box<dyn std::error::Error>> {
+    let driver_path = format!("{}/driver", container_path);
+    let mut file = File::create(driver_path)?;
+    file.write_all(serde_json::to_string(&driver.driver_type())?.as_bytes())?;
+    Ok(())
+}
+
+
+/// Kernel overlayfs, with the image layers as lower directories
+pub struct OverlayDriver;
+impl StorageDriver for OverlayDriver {
+    fn driver_type(&self) -> StorageDriverType {
+        StorageDriverType::overlay
+    }
+    fn prepare(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        create_overlay_directories(&container.path)
+    }
+    fn mount(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        let image = get_image(container)?;
+        image.mount_layers()?;
+
+        // Files written by the container are stored with its host ids
+        let layers_path = match remapped_ids(container)? {
+            Some((uid_mappings, gid_mappings)) => {
+                let upper = format!("{}/upper", &container.path);
+                chown(upper.as_str(), root_id(uid_mappings).map(Uid::from_raw), root_id(gid_mappings).map(Gid::from_raw))?;
+                idmap_layers(container, image)?
+            },
+            None => Path::new(&container.path).join("lower")
+        };
+
+        let options = overlay_options(&container.path, &layers_path, image)?;
+        let merged = format!("{}/merged", &container.path);
+        debug!("mount arguments: \n{}\n{}", options, merged);
+
+        mount(
+            Some("overlay"),
+            merged.as_str(),
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(options.as_str())
+        )?;
+
+        Ok(())
+    }
+    fn unmount(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        let merged = format!("{}/merged", &container.path);
+        info!("unmounting '{}'...", merged);
+        umount(merged.as_str())?;
+        unmount_idmapped_layers(&container.path)
+    }
+    fn diff(&self, container: &Container) -> Result<Vec<Change>, Box<dyn std::error::Error>> {
+        overlay_diff(container)
+    }
+    fn remove(&self, _container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+/// Overlayfs in userspace, for hosts where the kernel one can't be mounted
+pub struct FuseOverlayDriver;
+impl StorageDriver for FuseOverlayDriver {
+    fn driver_type(&self) -> StorageDriverType {
+        StorageDriverType::fuse_overlayfs
+    }
+    fn prepare(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        create_overlay_directories(&container.path)
+    }
+    fn mount(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        let image = get_image(container)?;
+        image.mount_layers()?;
+
+        let mut options = overlay_options(&container.path, &Path::new(&container.path).join("lower"), image)?;
+        if let Some((uid_mappings, gid_mappings)) = remapped_ids(container)? {
+            options = format!(
+                "{},uidmapping={},gidmapping={}",
+                options, fuse_mapping(uid_mappings), fuse_mapping(gid_mappings)
+            );
+        }
+        let merged = format!("{}/merged", &container.path);
+        info!("fuse-overlayfs -o {} {}", options, merged);
+
+        let output = Command::new("fuse-overlayfs")
+            .arg("-o").arg(options)
+            .arg(merged)
+            .output()?;
+        debug!("output: {}", output.status);
+        if !output.status.success() {
+            return Err(format!(
+                "fuse-overlayfs failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ).into());
+        }
+
+        Ok(())
+    }
+    fn unmount(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        let merged = format!("{}/merged", &container.path);
+        info!("unmounting '{}'...", merged);
+        umount(merged.as_str())?;
+        Ok(())
+    }
+    fn diff(&self, container: &Container) -> Result<Vec<Change>, Box<dyn std::error::Error>> {
+        overlay_diff(container)
+    }
+    fn remove(&self, _container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+/// Plain copy of the image layers, works on any filesystem
+pub struct VfsDriver;
+impl StorageDriver for VfsDriver {
+    fn driver_type(&self) -> StorageDriverType {
+        StorageDriverType::vfs
+    }
+    fn prepare(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        let image = get_image(container)?;
+        image.mount_layers()?;
+
+        let merged = format!("{}/merged", &container.path);
+        create_dir_all(&merged)?;
+        copy_image(image, Path::new(&merged))?;
+
+        Ok(())
+    }
+    fn mount(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        idmap_merged(container)
+    }
+    fn unmount(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        unmount_idmapped_merged(container)
+    }
+    fn diff(&self, container: &Container) -> Result<Vec<Change>, Box<dyn std::error::Error>> {
+        copy_diff(container)
+    }
+    fn remove(&self, _container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+/// Snapshot of a btrfs subvolume holding the image, made once per image
+pub struct BtrfsDriver;
+impl BtrfsDriver {
+    /// Create the image subvolume, if it doesn't exist
+    fn prepare_image_subvolume(&self, image: &Image) -> Result<String, Box<dyn std::error::Error>> {
+        let subvolume_path = paths::get_image_subvolume_path(image)?;
+        if Path::new(&subvolume_path).exists() {
+            return Ok(subvolume_path)
+        }
+        info!("creating image subvolume...");
+
+        image.mount_layers()?;
+        create_dir_all(Path::new(&subvolume_path).parent().unwrap())?;
+
+        let temporary_path = format!("{}.{}", subvolume_path, std::process::id());
+        btrfs(&["subvolume", "create", temporary_path.as_str()])?;
+        if let Err(e) = copy_image(image, Path::new(&temporary_path)) {
+            btrfs(&["subvolume", "delete", temporary_path.as_str()])?;
+            return Err(e)
+        }
+        btrfs(&["property", "set", "-ts", temporary_path.as_str(), "ro", "true"])?;
+        rename(&temporary_path, &subvolume_path)?;
+
+        info!("created image subvolume.");
+        Ok(subvolume_path)
+    }
+}
+impl StorageDriver for BtrfsDriver {
+    fn driver_type(&self) -> StorageDriverType {
+        StorageDriverType::btrfs
+    }
+    fn prepare(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        let subvolume_path = self.prepare_image_subvolume(get_image(container)?)?;
+        let merged = format!("{}/merged", &container.path);
+        btrfs(&["subvolume", "snapshot", subvolume_path.as_str(), merged.as_str()])
+    }
+    fn mount(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        idmap_merged(container)
+    }
+    fn unmount(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        unmount_idmapped_merged(container)
+    }
+    fn diff(&self, container: &Container) -> Result<Vec<Change>, Box<dyn std::error::Error>> {
+        copy_diff(container)
+    }
+    fn remove(&self, container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+        let merged = format!("{}/merged", &container.path);
+        if Path::new(&merged).exists() {
+            btrfs(&["subvolume", "delete", merged.as_str()])?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Get the image of a loaded container
+fn get_image(container: &Container) -> Result<&Image, Box<dyn std::error::Error>> {
+    match &container.image {
+        Some(image) => Ok(image),
+        None        => Err("container image not found".into())
+    }
+}
+/// Create the upper, work and merged directories used by overlayfs
+fn create_overlay_directories(container_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    for subdirectory in &["upper", "work", "merged"] {
+        create_dir_all(format!("{}/{}", container_path, subdirectory))?;
+    }
+    Ok(())
+}
+/// Build the overlayfs mount options
+///
+/// Layers are reached through a container directory, like the 'lower' link, since image ids contain ':', the lowerdir separator
+fn overlay_options(container_path: &str, layers_path: &Path, image: &Image) -> Result<String, Box<dyn std::error::Error>> {
+    let layers = image.layer_paths()?
+        .iter()
+        .map(|layer| format!("{}", layers_path.join(Path::new(layer).file_name().unwrap_or_default()).display()))
+        .collect::<Vec<String>>();
+
+    Ok(format!(
+        "lowerdir={},upperdir={}/upper,workdir={}/work",
+        layers.join(":"), container_path, container_path
+    ))
+}
+/// Id maps to show the container files with, for containers with a user namespace
+///
+/// Rootless, the files are owned by the user, which is the container root, and they're mounted from the user namespace
+fn remapped_ids(container: &Container) -> Result<Option<IdMappings<'_>>, Box<dyn std::error::Error>> {
+    if utils::is_rootless() {
+        return Ok(None)
+    }
+    container.id_mappings()
+}
+/// Open a user namespace with the container id maps, to mount the container files with
+fn container_userns(uid_mappings: &[Mapping], gid_mappings: &[Mapping]) -> Result<RawFd, Box<dyn std::error::Error>> {
+    userns::create(uid_mappings, gid_mappings)
+        .map_err(|e| format!("cannot create user namespace for the container files: {}", e).into())
+}
+/// Host id of the container root
+fn root_id(mappings: &[Mapping]) -> Option<u32> {
+    userns::host_id(mappings, 0)
+}
+/// Mount the image layers with the container ids, in the container 'idmapped' directory
+///
+/// The overlay mount itself can't be idmapped, so its lower directories are
+fn idmap_layers(container: &Container, image: &Image) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let lower_path = Path::new(&container.path).join("lower");
+    let idmapped_path = Path::new(&container.path).join("idmapped");
+    let (uid_mappings, gid_mappings) = remapped_ids(container)?.ok_or("container files aren't remapped")?;
+
+    let mut layers = Vec::new();
+    for layer in image.layer_paths()? {
+        let name = Path::new(&layer).file_name().unwrap_or_default().to_owned();
+        let target = format!("{}", idmapped_path.join(&name).display());
+        if !utils::is_mounted(&target)? {
+            layers.push((format!("{}", lower_path.join(&name).display()), target));
+        }
+    }
+    if layers.is_empty() {
+        return Ok(idmapped_path)
+    }
+
+    let userns = container_userns(uid_mappings, gid_mappings)?;
+    let result = layers.iter()
+        .map(|(source, target)| {
+            info!("mounting '{}' idmapped to '{}'...", source, target);
+            create_dir_all(target)?;
+            mounts::mount_idmapped(source, target, userns)
+        })
+        .collect::<Result<Vec<()>, Box<dyn std::error::Error>>>();
+    close(userns)?;
+    result?;
+
+    Ok(idmapped_path)
+}
+/// Unmount the idmapped image layers of a container, if any
+fn unmount_idmapped_layers(container_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let idmapped_path = Path::new(container_path).join("idmapped");
+    if !idmapped_path.exists() {
+        return Ok(())
+    }
+
+    for entry in fs::read_dir(&idmapped_path)? {
+        let layer = format!("{}", entry?.path().display());
+        if utils::is_mounted(&layer)? {
+            info!("unmounting '{}'...", layer);
+            umount(layer.as_str())?;
+        }
+    }
+    Ok(())
+}
+/// Mount the container root over itself with the container ids, for drivers that copy the image
+fn idmap_merged(container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+    let (uid_mappings, gid_mappings) = match remapped_ids(container)? {
+        Some(mappings) => mappings,
+        None           => return Ok(())
+    };
+    let merged = format!("{}/merged", &container.path);
+    if utils::is_mounted(&merged)? {
+        return Ok(())
+    }
+
+    let userns = container_userns(uid_mappings, gid_mappings)?;
+    info!("mounting '{}' idmapped...", merged);
+    let result = mounts::mount_idmapped(&merged, &merged, userns);
+    close(userns)?;
+    result
+}
+/// Unmount the idmapped container root, if mounted
+fn unmount_idmapped_merged(container: &Container) -> Result<(), Box<dyn std::error::Error>> {
+    let merged = format!("{}/merged", &container.path);
+    if utils::is_mounted(&merged)? {
+        info!("unmounting '{}'...", merged);
+        umount(merged.as_str())?;
+    }
+    Ok(())
+}
+/// Id maps as fuse-overlayfs takes them, 'container:host:size' joined by ':'
+fn fuse_mapping(mappings: &[Mapping]) -> String {
+    mappings.iter()
+        .map(|mapping| format!("{}:{}:{}", mapping.container_id, mapping.host_id, mapping.size))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+/// Run a btrfs command
+fn btrfs(args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+    info!("btrfs {}", args.join(" "));
+    let output = Command::new("btrfs")
+        .args(args)
+        .output()?;
+    debug!("output: {}", output.status);
+    if !output.status.success() {
+        return Err(format!(
+            "btrfs failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ).into());
+    }
+    Ok(())
+}
+
+/// Get the relative paths of everything under a directory, parents before children
+fn walk(root: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut paths = Vec::new();
+    let mut directories = vec![PathBuf::new()];
+    while let Some(directory) = directories.pop() {
+        for entry in root.join(&directory).read_dir()? {
+            let entry = entry?;
+            let path = directory.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                directories.push(path.clone());
+            }
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+/// Get the path hidden by a whiteout file, if the path is one
+fn whiteout_target(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    if name.starts_with(".wh.") && name != ".wh..wh..opq" {
+        Some(path.with_file_name(&name[4..]))
+    } else {
+        None
+    }
+}
+/// Check if a path is an opaque directory marker
+fn is_opaque_marker(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == ".wh..wh..opq")
+}
+/// Remove a file or directory, if it exists
+fn remove_path(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let result = match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_)                             => fs::remove_file(path),
+        Err(_)                            => return Ok(())
+    };
+    match result {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(())
+    }
+}
+
+/// Copy all the image layers in a directory, from the base one to the top one
+fn copy_image(image: &Image, target: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    info!("copying image layers...");
+    for layer_path in image.layer_paths()?.iter().rev() {
+        copy_layer(Path::new(layer_path), target)?;
+    }
+    info!("copied image layers.");
+    Ok(())
+}
+/// Copy a layer on top of a directory, applying its whiteouts
+fn copy_layer(layer: &Path, target: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("copying layer {}...", layer.display());
+
+    let markers: Vec<PathBuf> = walk(layer)?
+        .into_iter()
+        .filter(|path| is_opaque_marker(path) || whiteout_target(path).is_some())
+        .collect();
+
+    for marker in &markers {
+        if let Some(hidden) = whiteout_target(marker) {
+            remove_path(&target.join(hidden))?;
+        } else if let Some(directory) = marker.parent() {
+            let directory = target.join(directory);
+            if directory.is_dir() {
+                for entry in directory.read_dir()? {
+                    remove_path(&entry?.path())?;
+                }
+            }
+        }
+    }
+
+    let output = Command::new("cp")
+        .arg("-a").arg("--reflink=auto")
+        .arg(layer.join("."))
+        .arg(target)
+        .output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "copying layer failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ).into());
+    }
+
+    for marker in &markers {
+        remove_path(&target.join(marker))?;
+    }
+
+    Ok(())
+}
+
+/// List the changes of an overlay upper directory
+///
+/// Deleted paths are character devices with device number 0
+fn overlay_diff(container: &Container) -> Result<Vec<Change>, Box<dyn std::error::Error>> {
+    let image = get_image(container)?;
+    image.mount_layers()?;
+    let layer_paths = image.layer_paths()?;
+    let upper = PathBuf::from(format!("{}/upper", &container.path));
+
+    let mut changes = Vec::new();
+    for path in walk(&upper)? {
+        let metadata = upper.join(&path).symlink_metadata()?;
+        if metadata.file_type().is_char_device() && metadata.rdev() == 0 {
+            changes.push(Change::Deleted(path));
+        } else if layer_paths.iter().any(|layer| Path::new(layer).join(&path).symlink_metadata().is_ok()) {
+            changes.push(Change::Changed(path));
+        } else {
+            changes.push(Change::Added(path));
+        }
+    }
+
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(changes)
+}
+/// List the changes of a copied root filesystem, by comparing it to the image layers
+fn copy_diff(container: &Container) -> Result<Vec<Change>, Box<dyn std::error::Error>> {
+    let image = get_image(container)?;
+    image.mount_layers()?;
+
+    // Files of the image, as seen through the layers from the top one
+    let mut image_files: HashMap<PathBuf, fs::Metadata> = HashMap::new();
+    let mut hidden: HashSet<PathBuf> = HashSet::new();
+    let mut opaque: HashSet<PathBuf> = HashSet::new();
+    for layer_path in image.layer_paths()? {
+        let layer = Path::new(&layer_path);
+        let mut layer_hidden = Vec::new();
+        let mut layer_opaque = Vec::new();
+
+        for path in walk(layer)? {
+            if is_opaque_marker(&path) {
+                layer_opaque.push(path.parent().unwrap().to_path_buf());
+            } else if let Some(target) = whiteout_target(&path) {
+                layer_hidden.push(target);
+            } else if !image_files.contains_key(&path)
+                && !path.ancestors().any(|p| hidden.contains(p))
+                && !path.ancestors().skip(1).any(|p| opaque.contains(p)) {
+                image_files.insert(path.clone(), layer.join(&path).symlink_metadata()?);
+            }
+        }
+
+        hidden.extend(layer_hidden);
+        opaque.extend(layer_opaque);
+    }
+
+    let merged = PathBuf::from(format!("{}/merged", &container.path));
+    let mut changes = Vec::new();
+    for path in walk(&merged)? {
+        let metadata = merged.join(&path).symlink_metadata()?;
+        match image_files.remove(&path) {
+            None => changes.push(Change::Added(path)),
+            Some(original) => {
+                if is_changed(&original, &metadata) {
+                    changes.push(Change::Changed(path));
+                }
+            }
+        }
+    }
+    let deleted: HashSet<PathBuf> = image_files.keys().cloned().collect();
+    for path in deleted.iter() {
+        if !path.parent().is_some_and(|parent| deleted.contains(parent)) {
+            changes.push(Change::Deleted(path.clone()));
+        }
+    }
+
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(changes)
+}
+/// Compare the metadata of a file of the image to the one of its copy
+///
+/// Directory timestamps are ignored, applying the layers changes them
+fn is_changed(original: &fs::Metadata, copy: &fs::Metadata) -> bool {
+    if original.file_type() != copy.file_type()
+        || original.mode() != copy.mode()
+        || original.uid() != copy.uid()
+        || original.gid() != copy.gid() {
+        return true
+    }
+    if original.is_dir() {
+        return false
+    }
+    original.len() != copy.len()
+        || original.mtime() != copy.mtime()
+        || original.mtime_nsec() != copy.mtime_nsec()
+}
@@ -0,0 +1,201 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use nix::errno::Errno;
+use nix::libc;
+use nix::poll::{poll, PollFd, EventFlags};
+use nix::pty::openpty;
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, InputFlags, SetArg, Termios};
+use nix::sys::uio::IoVec;
+use nix::unistd::{close, dup2, isatty, read, setsid, write};
+
+use log::info;
+
+
+/// Open a new pseudo-terminal, returning its master and slave ends
+pub fn open_pty() -> Result<(RawFd, RawFd), Box<dyn std::error::Error>> {
+    let pty = openpty(None, None)?;
+    Ok((pty.master, pty.slave))
+}
+/// Send a file descriptor to the process listening on a unix socket
+pub fn send_fd(socket_path: &str, fd: RawFd) -> Result<(), Box<dyn std::error::Error>> {
+    info!("sending terminal to {}...", socket_path);
+
+    let stream = UnixStream::connect(socket_path)?;
+    let iov = [IoVec::from_slice(b"\0")];
+    let fds = [fd];
+    sendmsg(
+        stream.as_raw_fd(),
+        &iov,
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None
+    )?;
+
+    Ok(())
+}
+/// Make a terminal the controlling terminal and the standard streams of the current process
+///
+/// The process becomes the leader of a new session
+pub fn set_controlling_terminal(fd: RawFd) -> Result<(), Box<dyn std::error::Error>> {
+    setsid()?;
+    if unsafe { libc::ioctl(fd, libc::TIOCSCTTY, 0) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    for stream in 0..3 {
+        dup2(fd, stream)?;
+    }
+    if fd > 2 {
+        close(fd)?;
+    }
+
+    Ok(())
+}
+/// Put a terminal in raw mode, so keys are passed through as they're typed
+///
+/// Returns the previous settings, or None if the file isn't a terminal
+pub fn set_raw_mode(fd: RawFd) -> Result<Option<Termios>, Box<dyn std::error::Error>> {
+    if !isatty(fd)? {
+        return Ok(None)
+    }
+
+    let original = tcgetattr(fd)?;
+    let mut raw = original.clone();
+    cfmakeraw(&mut raw);
+    tcsetattr(fd, SetArg::TCSANOW, &raw)?;
+
+    Ok(Some(original))
+}
+/// Let ctrl-s and ctrl-q through instead of using them for flow control
+///
+/// Returns the previous settings, or None if the file isn't a terminal
+pub fn disable_flow_control(fd: RawFd) -> Result<Option<Termios>, Box<dyn std::error::Error>> {
+    if !isatty(fd)? {
+        return Ok(None)
+    }
+
+    let original = tcgetattr(fd)?;
+    let mut termios = original.clone();
+    termios.input_flags.remove(InputFlags::IXON);
+    tcsetattr(fd, SetArg::TCSANOW, &termios)?;
+
+    Ok(Some(original))
+}
+/// Restore the settings of a terminal
+pub fn restore_mode(fd: RawFd, termios: &Termios) -> Result<(), Box<dyn std::error::Error>> {
+    tcsetattr(fd, SetArg::TCSANOW, termios)?;
+    Ok(())
+}
+/// Window size of a terminal, as rows and columns, or None if the file isn't a terminal
+pub fn window_size(fd: RawFd) -> Option<(u16, u16)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } < 0 {
+        return None
+    }
+    Some((size.ws_row, size.ws_col))
+}
+/// Set the window size of a terminal, which sends SIGWINCH to its foreground processes
+pub fn set_window_size(fd: RawFd, rows: u16, columns: u16) -> Result<(), Box<dyn std::error::Error>> {
+    let size = libc::winsize {
+        ws_row: rows,
+        ws_col: columns,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &size) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+/// Give a terminal the window size of another one, if the latter is a terminal
+pub fn copy_window_size(from: RawFd, to: RawFd) -> Result<(), Box<dyn std::error::Error>> {
+    match window_size(from) {
+        Some((rows, columns)) => set_window_size(to, rows, columns),
+        None                  => Ok(())
+    }
+}
+/// Receive the window size changes of the controlling terminal, SIGWINCH, through a file descriptor
+///
+/// The signal is blocked in the current thread, and in the threads it spawns afterwards
+pub fn watch_window_size(flags: SfdFlags) -> Result<SignalFd, Box<dyn std::error::Error>> {
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGWINCH);
+    mask.thread_block()?;
+    Ok(SignalFd::with_flags(&mask, flags | SfdFlags::SFD_CLOEXEC)?)
+}
+/// Copy the standard input to the master end of a terminal, and its output to the standard output
+///
+/// The window size of the standard input is kept on the terminal.
+/// Returns once the terminal is closed on the other side, i.e. its processes exited
+pub fn proxy(master: RawFd, forward_input: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut window_changes = watch_window_size(SfdFlags::SFD_NONBLOCK)?;
+    let result = copy_streams(master, forward_input, &mut window_changes);
+
+    // Processes forked afterwards would inherit the blocked signal
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGWINCH);
+    mask.thread_unblock()?;
+
+    result
+}
+
+/// Write a whole buffer to a file descriptor
+pub fn write_all(fd: RawFd, mut buffer: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    while !buffer.is_empty() {
+        match write(fd, buffer) {
+            Ok(size)                           => buffer = &buffer[size..],
+            Err(nix::Error::Sys(Errno::EINTR)) => {},
+            Err(e)                             => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+/// Copy between the standard streams and a terminal, see 'proxy'
+fn copy_streams(master: RawFd, forward_input: bool, window_changes: &mut SignalFd) -> Result<(), Box<dyn std::error::Error>> {
+    let mut buffer = [0u8; 4096];
+    let mut input_open = forward_input;
+
+    loop {
+        let mut fds = vec![
+            PollFd::new(master, EventFlags::POLLIN),
+            PollFd::new(window_changes.as_raw_fd(), EventFlags::POLLIN),
+        ];
+        if input_open {
+            fds.push(PollFd::new(libc::STDIN_FILENO, EventFlags::POLLIN));
+        }
+        match poll(&mut fds, -1) {
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(e)                             => return Err(e.into()),
+            Ok(_)                              => {}
+        }
+
+        if fds[1].revents().is_some_and(|events| !events.is_empty()) {
+            while window_changes.read_signal()?.is_some() {}
+            copy_window_size(libc::STDIN_FILENO, master)?;
+        }
+        if input_open && fds[2].revents().is_some_and(|events| !events.is_empty()) {
+            match read(libc::STDIN_FILENO, &mut buffer) {
+                Ok(0) | Err(_) => input_open = false,
+                Ok(size)       => write_all(master, &buffer[..size])?,
+            }
+        }
+
+        let events = fds[0].revents().unwrap_or_else(EventFlags::empty);
+        if events.contains(EventFlags::POLLIN) {
+            match read(master, &mut buffer) {
+                Ok(0)                              => return Ok(()),
+                Ok(size)                           => write_all(libc::STDOUT_FILENO, &buffer[..size])?,
+                // Reading the master fails with EIO once the slave is closed
+                Err(nix::Error::Sys(Errno::EIO))   => return Ok(()),
+                Err(nix::Error::Sys(Errno::EINTR)) => {},
+                Err(e)                             => return Err(e.into()),
+            }
+        } else if !events.is_empty() {
+            return Ok(())
+        }
+    }
+}
@@ -0,0 +1,230 @@
+#![allow(non_camel_case_types)]
+use std::fs::{read, File};
+use std::io::{BufReader, Read};
+use std::path::Path;
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Public};
+use openssl::sha::Sha256;
+use openssl::sign::Verifier;
+use serde_json::{self, Value};
+
+use log::{info, debug};
+
+use crate::paths;
+
+
+/// Layer of schema 1 manifests that only holds an empty archive, absent from signed manifests
+pub const EMPTY_LAYER: &str = "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4";
+/// Annotation holding the signature of a signature payload
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+/// Trust policy, saying which repositories must be signed and by which keys
+///
+/// Repositories are matched in order; a pattern ending in '*' matches by prefix
+#[derive(Deserialize, Debug, Default)]
+pub struct Policy {
+    #[serde(default)]
+    pub default: Requirement,
+    #[serde(default)]
+    pub repositories: Vec<RepositoryPolicy>,
+}
+impl Policy {
+    /// Load the trust policy, falling back to accepting everything if there is none
+    pub fn load() -> Result<Policy, Box<dyn std::error::Error>> {
+        let path = paths::get_policy_path()?;
+        if !Path::new(&path).exists() {
+            return Ok(Policy::default())
+        }
+
+        let file = File::open(path)?;
+        let buf_reader = BufReader::new(file);
+        let policy: Policy = serde_json::from_reader(buf_reader)?;
+        Ok(policy)
+    }
+    /// Get the keys a repository must be signed by
+    ///
+    /// Returns None if the repository doesn't need signatures, and an error if it is rejected
+    pub fn keys(&self, repository: &str) -> Result<Option<Vec<PKey<Public>>>, Box<dyn std::error::Error>> {
+        let repository_policy = self.repositories
+            .iter()
+            .find(|repository_policy| repository_policy.matches(repository));
+
+        let key_paths = match repository_policy {
+            Some(repository_policy) => &repository_policy.keys,
+            None => match self.default {
+                Requirement::accept => return Ok(None),
+                Requirement::reject => return Err(format!("repository {} is rejected by the trust policy", repository).into()),
+            }
+        };
+        if key_paths.is_empty() {
+            return Err(format!("repository {} is rejected by the trust policy", repository).into())
+        }
+
+        let mut keys = Vec::new();
+        for key_path in key_paths {
+            debug!("loading public key {}...", key_path);
+            keys.push(PKey::public_key_from_pem(&read(key_path)?)?);
+        }
+        Ok(Some(keys))
+    }
+}
+
+/// What to do with repositories not listed in the policy
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Requirement {
+    #[default]
+    accept,
+    reject,
+}
+
+/// Keys trusted for the repositories matching a pattern. No keys means the repositories are rejected
+#[derive(Deserialize, Debug)]
+pub struct RepositoryPolicy {
+    pub repository: String,
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+impl RepositoryPolicy {
+    fn matches(&self, repository: &str) -> bool {
+        if self.repository.ends_with('*') {
+            repository.starts_with(&self.repository[..self.repository.len() - 1])
+        } else {
+            repository == self.repository
+        }
+    }
+}
+
+
+/// Get the digest of some content, as 'sha256:<hex>'
+pub fn digest(content: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(content);
+    format!("sha256:{}", hex(&hasher.finish()))
+}
+/// Get the digest of a file, as 'sha256:<hex>'
+pub fn file_digest(path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("sha256:{}", hex(&hasher.finish())))
+}
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Get the tag the signatures of a manifest are stored under
+pub fn signature_tag(manifest_digest: &str) -> String {
+    format!("{}.sig", manifest_digest.replace(':', "-"))
+}
+/// Get the payloads of a signature manifest, with their digest and base64 signature
+pub fn signature_layers(signature_manifest: &Value) -> Vec<(String, String)> {
+    let layers = match &signature_manifest["layers"] {
+        Value::Array(layers) => layers.clone(),
+        _ => return Vec::new()
+    };
+
+    layers.iter()
+        .filter_map(|layer| match (&layer["digest"], &layer["annotations"][SIGNATURE_ANNOTATION]) {
+            (Value::String(digest), Value::String(signature)) => Some((digest.clone(), signature.clone())),
+            _ => None
+        })
+        .collect()
+}
+/// Check a signature payload against the trusted keys
+///
+/// Returns true if one of the keys signed it, and it names the manifest digest and the repository
+pub fn verify_payload(
+    keys: &[PKey<Public>],
+    payload: &[u8],
+    signature: &str,
+    manifest_digest: &str,
+    repository: &str
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let signature = base64::decode_block(signature)?;
+
+    let mut signed = false;
+    for key in keys {
+        let mut verifier = Verifier::new(MessageDigest::sha256(), key)?;
+        verifier.update(payload)?;
+        if verifier.verify(&signature).unwrap_or(false) {
+            signed = true;
+            break
+        }
+    }
+    if !signed {
+        debug!("signature doesn't match any trusted key");
+        return Ok(false)
+    }
+
+    let payload: Value = serde_json::from_slice(payload)?;
+    let critical = &payload["critical"];
+    if critical["image"]["docker-manifest-digest"] != Value::String(manifest_digest.to_string()) {
+        info!("signature is for another manifest");
+        return Ok(false)
+    }
+    match &critical["identity"]["docker-reference"] {
+        Value::String(reference) if normalize_reference(reference) == normalize_reference(repository) => {},
+        _ => {
+            info!("signature is for another repository");
+            return Ok(false)
+        }
+    }
+
+    Ok(true)
+}
+/// Get the full name of a repository, as 'registry/path', with the default registry and 'library/' prefix of docker hub
+///
+/// Tags and digests are dropped, 'alpine' and 'index.docker.io/library/alpine:3' both give 'docker.io/library/alpine'
+fn normalize_reference(reference: &str) -> String {
+    let reference = reference.split('@').next().unwrap_or_default();
+    // A tag follows the last ':' after the last '/', other ones are a registry port
+    let reference = match (reference.rfind(':'), reference.rfind('/')) {
+        (Some(colon), Some(slash)) if colon > slash => &reference[..colon],
+        (Some(colon), None)                         => &reference[..colon],
+        _                                           => reference
+    };
+
+    let (registry, path) = match reference.split_once('/') {
+        Some((registry, path)) if registry.contains('.') || registry.contains(':') || registry == "localhost" => (registry, path),
+        _ => ("docker.io", reference)
+    };
+    let registry = match registry {
+        "index.docker.io" | "registry-1.docker.io" | "registry.hub.docker.com" => "docker.io",
+        registry                                                                => registry
+    };
+    match registry == "docker.io" && !path.contains('/') {
+        true  => format!("{}/library/{}", registry, path),
+        false => format!("{}/{}", registry, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_reference_names() {
+        let references = [
+            ("alpine", "docker.io/library/alpine"),
+            ("alpine:3", "docker.io/library/alpine"),
+            ("index.docker.io/library/alpine:3", "docker.io/library/alpine"),
+            ("registry-1.docker.io/alpine", "docker.io/library/alpine"),
+            ("user/repo:tag", "docker.io/user/repo"),
+            ("quay.io/coreos/etcd@sha256:0123", "quay.io/coreos/etcd"),
+            ("localhost/foo", "localhost/foo"),
+            ("localhost:5000/foo/bar:tag", "localhost:5000/foo/bar"),
+            ("registry:5000/foo", "registry:5000/foo"),
+        ];
+        for (reference, name) in references.iter() {
+            assert_eq!(normalize_reference(reference), *name, "{}", reference);
+        }
+    }
+}
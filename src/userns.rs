@@ -0,0 +1,351 @@
+use std::fs::{self, create_dir_all, read_to_string, File, OpenOptions};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::process::{exit, Command};
+use nix::fcntl::{flock, open, FlockArg, OFlag};
+use nix::libc;
+use nix::sched::{setns, unshare, CloneFlags};
+use nix::sys::stat::Mode;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{close, fork, pipe, read, setgid, setuid, write, ForkResult, Gid, Pid, Uid};
+
+use log::{info, error, debug};
+
+use crate::spec::{Mapping, Spec};
+use crate::paths;
+use crate::utils;
+
+
+/// Range of host ids, as start and count
+type IdRange = (u32, u32);
+
+/// Ids given to each container, enough for the 16 bit ids of image files
+const RANGE_SIZE: u32 = 65536;
+/// Host ids root gives to containers when it has no subordinate ids
+const ROOT_SUBORDINATE_IDS: IdRange = (100_000, RANGE_SIZE * 16384);
+
+/// Create a user namespace with the given id maps, and open it
+///
+/// A helper process unshares the namespace, and its maps are written from outside,
+/// directly as root, or with newuidmap and newgidmap otherwise
+pub fn create(uid_mappings: &[Mapping], gid_mappings: &[Mapping]) -> Result<RawFd, Box<dyn std::error::Error>> {
+    info!("creating user namespace...");
+
+    let (unshared_read, unshared_write) = pipe()?;
+    let (mapped_read, mapped_write) = pipe()?;
+    match fork()? {
+        ForkResult::Child => {
+            close(unshared_read)?;
+            close(mapped_write)?;
+            let unshared = unshare(CloneFlags::CLONE_NEWUSER).is_ok();
+            write(unshared_write, &[unshared as u8])?;
+            read(mapped_read, &mut [0u8; 1])?;
+            exit(0);
+        }
+        ForkResult::Parent { child } => {
+            close(unshared_write)?;
+            close(mapped_read)?;
+
+            let mut unshared = [0u8; 1];
+            read(unshared_read, &mut unshared)?;
+            close(unshared_read)?;
+            let result = match unshared[0] {
+                1 => write_mappings(child, uid_mappings, gid_mappings)
+                    .and_then(|_| {
+                        let path = format!("/proc/{}/ns/user", child);
+                        Ok(open(path.as_str(), OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())?)
+                    }),
+                _ => Err("cannot create user namespace".into())
+            };
+
+            // The namespace outlives the helper, while it's open
+            close(mapped_write)?;
+            waitpid(child, None)?;
+
+            info!("created user namespace.");
+            result
+        }
+    }
+}
+
+/// Join a new user namespace with the given id maps, as its root
+pub fn join(uid_mappings: &[Mapping], gid_mappings: &[Mapping]) -> Result<(), Box<dyn std::error::Error>> {
+    let userns = create(uid_mappings, gid_mappings)?;
+    let result = setns(userns, CloneFlags::CLONE_NEWUSER);
+    close(userns)?;
+    result?;
+
+    // The host ids are kept, and aren't mapped in the namespace
+    setgid(Gid::from_raw(0))
+        .and_then(|_| setuid(Uid::from_raw(0)))
+        .map_err(|e| format!("cannot become root of the user namespace, is 0 mapped? {}", e))?;
+    // Changing ids clears the dumpable flag, which leaves the /proc/self files to the unmapped host root
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 1, 0, 0, 0) } != 0 {
+        return Err(format!("cannot make the process dumpable: {}", std::io::Error::last_os_error()).into())
+    }
+    debug!("uid: {} - euid: {}", Uid::current(), Uid::effective());
+    debug!("gid: {} - egid: {}", Gid::current(), Gid::effective());
+
+    Ok(())
+}
+/// Run a function as root of a new user namespace with the given id maps, in a child process
+pub fn run_as_root<F>(uid_mappings: &[Mapping], gid_mappings: &[Mapping], function: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce() -> Result<(), Box<dyn std::error::Error>>
+{
+    match fork()? {
+        ForkResult::Child => {
+            if let Err(e) = join(uid_mappings, gid_mappings).and_then(|_| function()) {
+                error!("{}", e);
+                exit(1);
+            }
+            exit(0);
+        }
+        ForkResult::Parent { child } => {
+            match waitpid(child, None)? {
+                WaitStatus::Exited(_, 0) => Ok(()),
+                _                        => Err("failed in the user namespace, see the log above".into())
+            }
+        }
+    }
+}
+
+/// Give a container uid and gid ranges that no other container uses
+///
+/// Ranges come from the subordinate ids of the current user, in '/etc/subuid' and '/etc/subgid'.
+/// Root uses host ids from 100000 if it has none, but the subordinate ids of the other users.
+///
+/// Rootless, the container root is the user, and the other ids are its subordinate ones, shared by its containers
+pub fn allocate(container_id: &str) -> Result<(Vec<Mapping>, Vec<Mapping>), Box<dyn std::error::Error>> {
+    info!("allocating container id ranges...");
+
+    if utils::is_rootless() {
+        return Ok((
+            rootless_mappings(Uid::effective().as_raw(), &subordinate_ids("/etc/subuid")?),
+            rootless_mappings(Gid::effective().as_raw(), &subordinate_ids("/etc/subgid")?),
+        ))
+    }
+
+    let (mut used_uids, mut used_gids) = used_ids(container_id)?;
+    let uid = allocate_range(&root_subordinate_ids("/etc/subuid", &mut used_uids)?, &used_uids)
+        .ok_or("no free subordinate uids left for the container, see /etc/subuid")?;
+    let gid = allocate_range(&root_subordinate_ids("/etc/subgid", &mut used_gids)?, &used_gids)
+        .ok_or("no free subordinate gids left for the container, see /etc/subgid")?;
+    debug!("uids: {}-{}, gids: {}-{}", uid, uid + RANGE_SIZE - 1, gid, gid + RANGE_SIZE - 1);
+
+    let mapping = |host_id| vec![Mapping {
+        container_id: 0,
+        host_id,
+        size: RANGE_SIZE,
+    }];
+    Ok((mapping(uid), mapping(gid)))
+}
+
+/// Lock the container id ranges, until the returned file is closed
+///
+/// Held from the allocation of a container ranges until its spec is written, so concurrent containers don't get the same ones
+pub fn lock() -> Result<File, Box<dyn std::error::Error>> {
+    let lock_path = paths::get_id_lock_path()?;
+    if let Some(parent) = Path::new(&lock_path).parent() {
+        create_dir_all(parent)?;
+    }
+    let lock_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)?;
+
+    info!("waiting for id lock...");
+    flock(lock_file.as_raw_fd(), FlockArg::LockExclusive)?;
+
+    Ok(lock_file)
+}
+/// Host id of a container id, if it's mapped
+pub fn host_id(mappings: &[Mapping], id: u32) -> Option<u32> {
+    mappings.iter()
+        .find(|mapping| id >= mapping.container_id && id - mapping.container_id < mapping.size)
+        .map(|mapping| mapping.host_id + (id - mapping.container_id))
+}
+
+/// Write the id maps of a process in a new user namespace
+///
+/// Without root, the user's own ids can still be mapped, with setgroups denied
+fn write_mappings(pid: Pid, uid_mappings: &[Mapping], gid_mappings: &[Mapping]) -> Result<(), Box<dyn std::error::Error>> {
+    let own_ids = is_own_id(uid_mappings, Uid::effective().as_raw()) && is_own_id(gid_mappings, Gid::effective().as_raw());
+    if Uid::effective().is_root() || own_ids {
+        let lines = |mappings: &[Mapping]| mappings.iter()
+            .map(|mapping| format!("{} {} {}\n", mapping.container_id, mapping.host_id, mapping.size))
+            .collect::<String>();
+        fs::write(format!("/proc/{}/uid_map", pid), lines(uid_mappings))
+            .map_err(|e| format!("cannot write uid map: {}", e))?;
+        if !Uid::effective().is_root() {
+            fs::write(format!("/proc/{}/setgroups", pid), "deny")?;
+        }
+        fs::write(format!("/proc/{}/gid_map", pid), lines(gid_mappings))
+            .map_err(|e| format!("cannot write gid map: {}", e))?;
+        return Ok(())
+    }
+
+    id_map_helper("newuidmap", pid, uid_mappings)?;
+    id_map_helper("newgidmap", pid, gid_mappings)
+}
+/// Run newuidmap or newgidmap, which check the maps against the subordinate ids of the user
+fn id_map_helper(helper: &str, pid: Pid, mappings: &[Mapping]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut command = Command::new(helper);
+    command.arg(pid.to_string());
+    for mapping in mappings {
+        command.arg(mapping.container_id.to_string())
+            .arg(mapping.host_id.to_string())
+            .arg(mapping.size.to_string());
+    }
+    info!("{:?}", command);
+
+    let output = command.output()
+        .map_err(|e| format!("cannot run {}: {}", helper, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            helper, String::from_utf8_lossy(&output.stderr).trim()
+        ).into());
+    }
+    Ok(())
+}
+
+/// Check if id maps map only one id, to itself on the host
+fn is_own_id(mappings: &[Mapping], id: u32) -> bool {
+    match mappings {
+        [mapping] => mapping.host_id == id && mapping.size == 1,
+        _         => false
+    }
+}
+/// Id maps of rootless containers: root is the user, the other ids its first subordinate range
+fn rootless_mappings(id: u32, subordinate_ids: &[IdRange]) -> Vec<Mapping> {
+    let mut mappings = vec![Mapping {
+        container_id: 0,
+        host_id: id,
+        size: 1,
+    }];
+    if let Some((start, count)) = subordinate_ids.first() {
+        mappings.push(Mapping {
+            container_id: 1,
+            host_id: *start,
+            size: (*count).min(RANGE_SIZE - 1),
+        });
+    }
+    mappings
+}
+/// Subordinate id ranges of the current user, as start and count
+fn subordinate_ids(path: &str) -> Result<Vec<IdRange>, Box<dyn std::error::Error>> {
+    let uid = Uid::effective();
+    let name = user_name(uid);
+
+    let ranges = all_subordinate_ids(path)?.into_iter()
+        .filter(|(user, _)| Some(user.as_str()) == name.as_deref() || *user == uid.to_string())
+        .map(|(_, range)| range)
+        .collect();
+    Ok(ranges)
+}
+/// Subordinate id ranges root gives to containers, as start and count
+///
+/// Without any, root uses host ids from 100000, and the subordinate ids of every user are added to the used ones,
+/// as the usual ranges of '/etc/subuid' and '/etc/subgid' are in there
+fn root_subordinate_ids(path: &str, used: &mut Vec<IdRange>) -> Result<Vec<IdRange>, Box<dyn std::error::Error>> {
+    let ranges = subordinate_ids(path)?;
+    if !ranges.is_empty() {
+        return Ok(ranges)
+    }
+
+    used.extend(all_subordinate_ids(path)?.into_iter().map(|(_, range)| range));
+    Ok(vec![ROOT_SUBORDINATE_IDS])
+}
+/// Subordinate id ranges of all users, as user and range
+///
+/// Lines are 'user:start:count', the user being a name or a uid
+fn all_subordinate_ids(path: &str) -> Result<Vec<(String, IdRange)>, Box<dyn std::error::Error>> {
+    let content = if Path::new(path).exists() { read_to_string(path)? } else { String::new() };
+
+    let ranges = content.lines()
+        .map(|line| line.split(':').collect::<Vec<&str>>())
+        .filter(|fields| fields.len() == 3)
+        .filter_map(|fields| Some((fields[0].to_string(), (fields[1].parse().ok()?, fields[2].parse().ok()?))))
+        .collect();
+    Ok(ranges)
+}
+/// Name of a user, from '/etc/passwd'
+fn user_name(uid: Uid) -> Option<String> {
+    let passwd = read_to_string("/etc/passwd").ok()?;
+    passwd.lines()
+        .map(|line| line.split(':').collect::<Vec<&str>>())
+        .find(|fields| fields.len() > 2 && fields[2] == uid.to_string())
+        .map(|fields| fields[0].to_string())
+}
+/// Host id ranges used by the other containers, as start and count
+fn used_ids(container_id: &str) -> Result<(Vec<IdRange>, Vec<IdRange>), Box<dyn std::error::Error>> {
+    let mut uids = Vec::new();
+    let mut gids = Vec::new();
+
+    let containers_path = paths::get_containers_path()?;
+    if !Path::new(&containers_path).exists() {
+        return Ok((uids, gids))
+    }
+    for entry in fs::read_dir(&containers_path)? {
+        let entry = entry?;
+        let spec_path = entry.path().join("config.json");
+        if entry.file_name() == container_id || !spec_path.exists() {
+            continue
+        }
+        let spec = match Spec::load(&format!("{}", spec_path.display())) {
+            Ok(spec) => spec,
+            Err(e)   => {
+                debug!("skipping {}: {}", spec_path.display(), e);
+                continue
+            }
+        };
+        if let Some(linux) = spec.linux {
+            uids.extend(linux.uid_mappings.iter().map(|mapping| (mapping.host_id, mapping.size)));
+            gids.extend(linux.gid_mappings.iter().map(|mapping| (mapping.host_id, mapping.size)));
+        }
+    }
+
+    Ok((uids, gids))
+}
+/// First range of RANGE_SIZE ids in the available ranges that doesn't overlap the used ones
+fn allocate_range(available: &[IdRange], used: &[IdRange]) -> Option<u32> {
+    let overlaps = |start: u64| used.iter()
+        .any(|(used_start, used_size)| start < *used_start as u64 + *used_size as u64 && (*used_start as u64) < start + RANGE_SIZE as u64);
+
+    for (available_start, available_size) in available {
+        let end = *available_start as u64 + *available_size as u64;
+        let mut start = *available_start as u64;
+        while start + RANGE_SIZE as u64 <= end {
+            if !overlaps(start) {
+                return Some(start as u32)
+            }
+            start += RANGE_SIZE as u64;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_range_skips_used_ids() {
+        let available = [(100_000, 3 * RANGE_SIZE)];
+        assert_eq!(allocate_range(&available, &[]), Some(100_000));
+        assert_eq!(allocate_range(&available, &[(100_000, RANGE_SIZE)]), Some(100_000 + RANGE_SIZE));
+        assert_eq!(allocate_range(&available, &[(100_010, 1)]), Some(100_000 + RANGE_SIZE));
+        assert_eq!(allocate_range(&available, &[(99_000, 1001)]), Some(100_000 + RANGE_SIZE));
+        assert_eq!(allocate_range(&available, &[(99_000, 1000)]), Some(100_000));
+        assert_eq!(allocate_range(&available, &[(100_000, 3 * RANGE_SIZE)]), None);
+    }
+
+    #[test]
+    fn allocate_range_needs_a_whole_range() {
+        assert_eq!(allocate_range(&[(100_000, RANGE_SIZE - 1)], &[]), None);
+        assert_eq!(allocate_range(&[(100_000, 1000), (300_000, RANGE_SIZE)], &[]), Some(300_000));
+        assert_eq!(allocate_range(&[(u32::MAX - RANGE_SIZE, RANGE_SIZE)], &[]), Some(u32::MAX - RANGE_SIZE));
+    }
+}
@@ -1,18 +1,20 @@
 use std::path::Path;
 use std::fs::read_to_string;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use nix::sys::stat::{Mode};
-use nix::unistd::{mkdir};
+use nix::unistd::{mkdir, Uid};
+use nix::sys::signal::Signal;
 
 use structopt::StructOpt;
-// use dirs;
 use log::debug;
 
 use crate::*;
-use crate::image::Image;
 use crate::image_manager::ImageManager;
 use crate::container::Container;
 use crate::container_manager::ContainerManager;
+use crate::state::State;
 
 #[allow(dead_code)]
 /// Run a command for the managers, passed as a string
@@ -22,27 +24,46 @@ pub fn run_command_from_string(opt_str: &str, image_manager: &ImageManager, cont
     Ok(())
 }
 /// Run a command for the managers
-pub fn run_command(opt: Opt, image_manager: &ImageManager, container_manager: &ContainerManager) -> Result<(), Box<dyn std::error::Error>> {
+///
+/// Returns the exit code for minato, which is the one of the command or the container for exec and attach
+pub fn run_command(opt: Opt, image_manager: &ImageManager, container_manager: &ContainerManager) -> Result<i32, Box<dyn std::error::Error>> {
+    let mut code = 0;
     match opt.subcommand {
         Some(Subcommand::Image  { action }) => match action {
-            ImageAction::Pull   { image_id } => image_manager.pull(&image_id),
+            ImageAction::Pull   { image_id, lazy } => image_manager.pull(&image_id, lazy),
             ImageAction::List                => image_manager.list(),
             ImageAction::Delete { image_id } => image_manager.delete(&image_id),
         },
         Some(Subcommand::Container  { action }) => match action {
-            ContainerAction::Create { container_name, image_id } => container_manager.create(&container_name, &image_id),
-            ContainerAction::Run    { container_name, volume, host_ip, container_ip }   => container_manager.run(&container_name, opt.daemon, volume, host_ip, container_ip),
-            ContainerAction::Open   { container_name }           => container_manager.open(&container_name),
+            ContainerAction::Create { container_name, image_id, overrides } => container_manager.create(&container_name, &image_id, &overrides),
+            ContainerAction::Run    { container_name, volume, host_ip, container_ip, detach, overrides } => container_manager.run(&container_name, opt.daemon, volume, host_ip, container_ip, detach, &overrides),
+            ContainerAction::Open   { container_name }           => container_manager.open(&container_name).map(|exit_code| code = exit_code),
+            ContainerAction::Exec   { interactive, tty, user, env, workdir, container_name, command } => {
+                let overrides = spec::Overrides { env, workdir, user, command, ..spec::Overrides::default() };
+                container_manager.exec(&container_name, &overrides, tty, interactive).map(|exit_code| code = exit_code)
+            },
             ContainerAction::Stop   { container_name }           => container_manager.stop(&container_name),
+            ContainerAction::Pause   { container_name }          => container_manager.pause(&container_name),
+            ContainerAction::Unpause { container_name }          => container_manager.unpause(&container_name),
             ContainerAction::List                                => container_manager.list(),
-            ContainerAction::Delete { container_name }           => container_manager.delete(&container_name),
+            ContainerAction::Delete { container_name }           => container_manager.delete(&container_name, false),
+            ContainerAction::Attach { container_name, detach_keys } => container_manager.attach(&container_name, &detach_keys).map(|exit_code| code = exit_code),
+            ContainerAction::Logs   { container_name, follow, since, tail } => container_manager.logs(&container_name, follow, since, tail),
+            ContainerAction::Diff   { container_name }           => container_manager.diff(&container_name),
+            ContainerAction::Stats  { no_stream, json, container_names } => container_manager.stats(&container_names, !no_stream, json),
         }
+        Some(Subcommand::Create { bundle, pid_file, console_socket, container_id }) => container_manager.create_from_bundle(&container_id, &bundle, pid_file, console_socket),
+        Some(Subcommand::Start  { container_id })         => container_manager.start(&container_id),
+        Some(Subcommand::State  { container_id })         => container_manager.state(&container_id),
+        Some(Subcommand::Kill   { container_id, signal }) => container_manager.kill(&container_id, &signal),
+        Some(Subcommand::Delete { force, container_id })  => container_manager.delete(&container_id, force),
         None => {
             info!("unexpected arguments");
             Opt::clap().print_help().unwrap();
             exit(1);
         }
-    }
+    }?;
+    Ok(code)
 }
 
 
@@ -74,65 +95,134 @@ pub fn split_image_id(image_id: String) -> Result<(String, String), Box<dyn std:
 
     Ok((ids[0].clone(), ids[1].clone()))
 }
-/// Get path to image, from the image id
-pub fn get_image_path_with_str(image_id: &str) -> Result<String, Box<dyn std::error::Error>> {
-    // let home = match dirs::home_dir() {
-    //     Some(path) => path,
-    //     None       => return Err("error getting home directory".into())
-    // };
-
-    // Ok(format!(
-    //     "{}/.minato/images/{}",
-    //     home.display(), image_id
-    // ))
-    Ok(format!(
-        "/var/lib/minato/images/{}",
-        image_id
-    ))
-}
-/// Get path to image, from image object
-pub fn get_image_path(image: &Image) -> Result<String, Box<dyn std::error::Error>> {
-    get_image_path_with_str(image.id.as_str())
-}
-
-
-/// Get path to container, from the container id
-pub fn get_container_path_with_str(container_id: &str) -> Result<String, Box<dyn std::error::Error>> {
-    // let home = match dirs::home_dir() {
-    //     Some(path) => path,
-    //     None       => return Err("error getting home directory".into())
-    // };
-
-    // Ok(format!(
-    //     "{}/.minato/containers/{}",
-    //     home.display(), container_id
-    // ))
-    Ok(format!(
-        "/var/lib/minato/containers/{}",
-        container_id
-    ))
-}
-/// Get path to container, from imagcontainere object
-pub fn get_container_path(container: &Container) -> Result<String, Box<dyn std::error::Error>> {
-    get_container_path_with_str(container.id.as_str())
+/// Rootless mode, 0 until checked
+static ROOTLESS: AtomicU8 = AtomicU8::new(0);
+
+/// Check if minato runs without root, keeping its state in the user directories
+///
+/// Checked once, so it doesn't change after joining a container user namespace
+pub fn is_rootless() -> bool {
+    match ROOTLESS.load(Ordering::SeqCst) {
+        0 => {
+            let rootless = !Uid::effective().is_root();
+            ROOTLESS.store(if rootless { 2 } else { 1 }, Ordering::SeqCst);
+            rootless
+        },
+        mode => mode == 2
+    }
 }
 /// Get container pid, from the container id
+///
+/// Only containers with a running or paused process have one
 pub fn get_container_pid_with_str(container_id: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
-    let container_path = utils::get_container_path_with_str(container_id)?;
+    let container_path = paths::get_container_path_with_str(container_id)?;
 
-    let pid_path = format!("{}/pid", container_path);
-    if !Path::new(&pid_path).exists() {
-        return Ok(None);
+    match State::load(&container_path)? {
+        Some(state) if state.is_active() => Ok(Some(state.pid.to_string())),
+        _                                => Ok(None)
     }
-
-    let pid = read_to_string(pid_path)?;
-
-    Ok(Some(pid.replace('\n', "")))
 }
 /// Get container pid, from the container object
 pub fn get_container_pid(container: &Container) -> Result<Option<String>, Box<dyn std::error::Error>> {
     get_container_pid_with_str(container.id.as_str())
 }
+/// Get the current time as an RFC 3339 timestamp, in UTC
+pub fn get_timestamp() -> String {
+    format_timestamp(SystemTime::now())
+}
+/// Format a time as an RFC 3339 timestamp, in UTC
+pub fn format_timestamp(time: SystemTime) -> String {
+    let duration = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = duration.as_secs() as i64;
+    let (time, days) = (seconds % 86400, seconds / 86400);
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        year, month, day,
+        time / 3600, time % 3600 / 60, time % 60,
+        duration.subsec_nanos()
+    )
+}
+/// Parse an RFC 3339 timestamp in UTC, like 'YYYY-MM-DDTHH:MM:SS[.fraction]Z'
+pub fn parse_timestamp(timestamp: &str) -> Result<SystemTime, Box<dyn std::error::Error>> {
+    let invalid = || format!("invalid timestamp '{}', expected YYYY-MM-DDTHH:MM:SSZ", timestamp);
+
+    let fields: Vec<&str> = timestamp
+        .trim_end_matches('Z')
+        .split(['-', 'T', ':', '.'])
+        .collect();
+    if !timestamp.ends_with('Z') || fields.len() < 6 || fields.len() > 7 {
+        return Err(invalid().into())
+    }
+    let numbers: Vec<i64> = fields[..6].iter()
+        .map(|field| field.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    let fraction = fields.get(6).cloned().unwrap_or_default();
+    if fraction.len() > 9 {
+        return Err(invalid().into())
+    }
+    let nanos = format!("{:0<9}", fraction).parse::<u32>().map_err(|_| invalid())?;
+
+    // Days since the epoch from the civil date (Howard Hinnant's algorithm)
+    let (year, month, day) = (numbers[0], numbers[1], numbers[2]);
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let seconds = days * 86400 + numbers[3] * 3600 + numbers[4] * 60 + numbers[5];
+    if seconds < 0 {
+        return Err(invalid().into())
+    }
+    Ok(UNIX_EPOCH + Duration::new(seconds as u64, nanos))
+}
+/// Parse a signal, from its name with or without the 'SIG' prefix, or its number
+pub fn parse_signal(signal: &str) -> Result<Signal, Box<dyn std::error::Error>> {
+    if let Ok(number) = signal.parse::<i32>() {
+        return Ok(Signal::from_c_int(number)?)
+    }
+
+    let name = signal.to_uppercase();
+    let name = if name.starts_with("SIG") { name } else { format!("SIG{}", name) };
+    Ok(Signal::from_str(&name)?)
+}
+/// Parse a size in bytes, with an optional 'b', 'k', 'm' or 'g' unit in powers of 1024
+pub fn parse_size(size: &str) -> Result<i64, Box<dyn std::error::Error>> {
+    let invalid = || format!("invalid size '{}', expected a number with an optional b, k, m or g unit", size);
+
+    let lowercase = size.to_lowercase();
+    let (number, unit) = match lowercase.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => lowercase.split_at(index),
+        None        => (lowercase.as_str(), "")
+    };
+    let multiplier: i64 = match unit {
+        "" | "b" => 1,
+        "k"      => 1 << 10,
+        "m"      => 1 << 20,
+        "g"      => 1 << 30,
+        _        => return Err(invalid().into())
+    };
+    number.parse::<i64>().ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .filter(|bytes| *bytes > 0)
+        .ok_or_else(|| invalid().into())
+}
 /// Prepare container directory by removing it, if it exists, and recreating it with specified permissions
 pub fn prepare_directory(rootfs: &str, dir_name: &str, perms: Mode) -> Result<(), Box<dyn std::error::Error>> {
     let dir_path = Path::new(rootfs).join(dir_name);
@@ -151,6 +241,37 @@ pub fn prepare_directory(rootfs: &str, dir_name: &str, perms: Mode) -> Result<()
     Ok(())
 }
 
+/// Check if a path is a mountpoint in the current mount namespace
+pub fn is_mounted(path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let path = match Path::new(path).canonicalize() {
+        Ok(path) => path,
+        Err(_)   => return Ok(false)
+    };
+
+    let mountinfo = read_to_string("/proc/self/mountinfo")?;
+    let mounted = mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .any(|mountpoint| Path::new(&mountpoint.replace("\\040", " ")) == path);
+
+    Ok(mounted)
+}
+/// Check if something is mounted on a directory or under it
+pub fn has_mounts_under(path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let path = match Path::new(path).canonicalize() {
+        Ok(path) => path,
+        Err(_)   => return Ok(false)
+    };
+
+    let mountinfo = read_to_string("/proc/self/mountinfo")?;
+    let mounted = mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .any(|mountpoint| Path::new(&mountpoint.replace("\\040", " ")).starts_with(&path));
+
+    Ok(mounted)
+}
+
 #[allow(dead_code)]
 /// Print capabilities
 pub fn print_caps() {
@@ -165,19 +286,30 @@ pub fn print_caps() {
     }
 }
 
-/// Get path to socket
-pub fn get_socket_path(socket_name: &str) -> Result<String, Box<dyn std::error::Error>> {
-    // let home = match dirs::home_dir() {
-    //     Some(path) => path,
-    //     None       => return Err("error getting home directory".into())
-    // };
-
-    // Ok(format!(
-    //     "{}/.minato/{}",
-    //     home.display(), socket_name
-    // ))
-    Ok(format!(
-        "/var/lib/minato/{}",
-        socket_name
-    ))
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("10b").unwrap(), 10);
+        assert_eq!(parse_size("1k").unwrap(), 1024);
+        assert_eq!(parse_size("2M").unwrap(), 2 << 20);
+        assert_eq!(parse_size("3g").unwrap(), 3 << 30);
+        for size in ["", "0", "-1", "k", "1kb", "1.5m", "1t", "9999999999g"].iter() {
+            assert!(parse_size(size).is_err(), "{}", size);
+        }
+    }
+
+    #[test]
+    fn parse_timestamp_from_the_epoch() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z").unwrap(), UNIX_EPOCH);
+        assert_eq!(parse_timestamp("2000-03-01T00:00:00Z").unwrap(), UNIX_EPOCH + Duration::from_secs(951_868_800));
+        assert_eq!(parse_timestamp("2024-02-29T12:34:56.5Z").unwrap(), UNIX_EPOCH + Duration::new(1_709_210_096, 500_000_000));
+        for timestamp in ["", "2024-02-29", "2024-02-29T12:34:56", "2024-02-29 12:34:56Z", "1969-12-31T23:59:59Z",
+                          "2024-02-29T12:34:56.1234567890Z", "2024-02-29T12:34:xxZ"].iter() {
+            assert!(parse_timestamp(timestamp).is_err(), "{}", timestamp);
+        }
+    }
+}